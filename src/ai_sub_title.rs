@@ -1,4 +1,11 @@
-use std::{fs::File, ptr::null_mut, sync::Arc};
+use std::{
+    fs::File,
+    ptr::null_mut,
+    sync::{
+        Arc,
+        mpsc::{Receiver, SyncSender, TrySendError, sync_channel},
+    },
+};
 
 use candle_core::IndexOp;
 use candle_nn::ops::softmax;
@@ -12,8 +19,8 @@ use candle_transformers::{
 use ffmpeg_the_third::{
     ChannelLayout,
     ffi::{
-        AV_CHANNEL_LAYOUT_MONO, AV_CHANNEL_LAYOUT_STEREO, swr_alloc_set_opts2, swr_convert_frame,
-        swr_free, swr_init,
+        AV_CHANNEL_LAYOUT_MONO, AV_CHANNEL_LAYOUT_STEREO, SwrContext, swr_alloc_set_opts2,
+        swr_convert_frame, swr_free, swr_init,
     },
     format::sample::Type,
     frame::Audio,
@@ -21,12 +28,132 @@ use ffmpeg_the_third::{
 
 use rustfft::{Fft, FftPlanner, num_complex::Complex};
 use tokenizers::{Tokenizer, tokenizer};
-use tokio::sync::mpsc::Sender;
-use tracing::{info, warn};
-
-use crate::{CURRENT_EXE_PATH, PlayerError, PlayerResult, decode::ManualProtectedResampler};
+use tokio::sync::{broadcast, mpsc::Sender};
+use tracing::warn;
+
+use crate::{CURRENT_EXE_PATH, PlayerError, PlayerResult, player::PlayerEvent};
+/// downmix the played audio to the 16000hz mono format of whisper
+/// it is created and freed in the subtitle thread
+struct SubtitleSourceResampler(*mut SwrContext);
+impl SubtitleSourceResampler {
+    fn new() -> Option<Self> {
+        unsafe {
+            let mut swr_ctx = null_mut();
+            let r = swr_alloc_set_opts2(
+                &mut swr_ctx,
+                &AV_CHANNEL_LAYOUT_MONO,
+                ffmpeg_the_third::ffi::AVSampleFormat::AV_SAMPLE_FMT_FLT,
+                16000,
+                &AV_CHANNEL_LAYOUT_STEREO,
+                ffmpeg_the_third::ffi::AVSampleFormat::AV_SAMPLE_FMT_FLT,
+                48000,
+                0,
+                null_mut(),
+            );
+            if r < 0 {
+                warn!("subtitle swr ctx create err");
+                return None;
+            }
+            let resampler = Self(swr_ctx);
+            if swr_init(resampler.0) < 0 {
+                warn!("subtitle swr init err");
+                return None;
+            }
+            Some(resampler)
+        }
+    }
+    /// the samples of the played frame to recognize
+    fn convert(&mut self, audio_frame: &Audio) -> Option<Vec<f32>> {
+        let mut to_recognize_frame = Audio::empty();
+        to_recognize_frame.set_format(ffmpeg_the_third::format::Sample::F32(Type::Packed));
+        to_recognize_frame.set_ch_layout(ChannelLayout::MONO);
+        to_recognize_frame.set_rate(16000);
+        unsafe {
+            if 0 > swr_convert_frame(
+                self.0,
+                to_recognize_frame.as_mut_ptr(),
+                audio_frame.as_ptr(),
+            ) {
+                warn!("subtitle frame convert err!");
+                return None;
+            }
+        }
+        Some(
+            bytemuck::cast_slice::<_, f32>(to_recognize_frame.data(0))
+                [0..to_recognize_frame.samples()]
+                .to_vec(),
+        )
+    }
+}
+impl Drop for SubtitleSourceResampler {
+    fn drop(&mut self) {
+        unsafe {
+            swr_free(&mut self.0);
+        }
+    }
+}
+/// the played audio frames waiting for the subtitle thread
+const SUBTITLE_FRAME_QUEUE_LEN: usize = 64;
+/// the thread recognizing the subtitle, it owns the model and the resampler
+/// so the recognition never holds the locks of the present task
+#[derive(Clone)]
+pub struct SubtitleWorker {
+    frame_sender: SyncSender<(Audio, UsedModel)>,
+}
+impl SubtitleWorker {
+    pub fn new(
+        subtitle_sender: Sender<String>,
+        event_sender: broadcast::Sender<PlayerEvent>,
+    ) -> PlayerResult<Self> {
+        let (frame_sender, frame_receiver) = sync_channel(SUBTITLE_FRAME_QUEUE_LEN);
+        std::thread::Builder::new()
+            .name("subtitle".to_string())
+            .spawn(move || SubtitleWorker::run(subtitle_sender, event_sender, frame_receiver))
+            .map_err(|e| PlayerError::Internal(format!("spawn subtitle thread err: {}", e)))?;
+        Ok(Self { frame_sender })
+    }
+    /// the frame is dropped when the recognition is behind the playback
+    pub fn push_frame(&self, audio_frame: Audio, used_model: UsedModel) {
+        if let Err(TrySendError::Disconnected(_)) =
+            self.frame_sender.try_send((audio_frame, used_model))
+        {
+            warn!("the subtitle thread exited");
+        }
+    }
+    /// the model is loaded for the first frame to recognize, so the player works without it
+    /// the thread exits once every sender is dropped
+    fn run(
+        subtitle_sender: Sender<String>,
+        event_sender: broadcast::Sender<PlayerEvent>,
+        frame_receiver: Receiver<(Audio, UsedModel)>,
+    ) {
+        let mut subtitle_sender = Some(subtitle_sender);
+        let mut recognizer = None;
+        while let Ok((audio_frame, used_model)) = frame_receiver.recv() {
+            if let Some(subtitle_sender) = subtitle_sender.take() {
+                match AISubTitle::new(subtitle_sender) {
+                    Ok(ai_subtitle) => {
+                        recognizer = SubtitleSourceResampler::new()
+                            .map(|resampler| (ai_subtitle, resampler));
+                    }
+                    Err(e) => {
+                        warn!("the subtitle model is not loaded: {}", e);
+                        if event_sender.send(PlayerEvent::Error(e)).is_err() {
+                            warn!("no front-end gets the subtitle model error");
+                        }
+                    }
+                }
+            }
+            if let Some((ai_subtitle, resampler)) = recognizer.as_mut() {
+                if let Some(pcm) = resampler.convert(&audio_frame) {
+                    ai_subtitle.push_pcm(pcm, used_model);
+                }
+            }
+        }
+    }
+}
 const MEL_FILTERS: &[u8] = include_bytes!("../resources/melfilters.bytes");
-#[derive(PartialEq, Clone)]
+#[derive(Debug, PartialEq, Clone)]
 pub enum UsedModel {
     Empty,
     Chinese,
@@ -46,7 +173,6 @@ pub struct AISubTitle {
     device: Device,
     model: Whisper,
     tokenizer: Tokenizer,
-    subtitle_source_resampler: ManualProtectedResampler,
     subtitle_sender: Sender<String>,
     candle_streaming_mel_processor: CandleStreamingMelProcessor,
     token_ids: TokenIds,
@@ -59,133 +185,80 @@ impl AISubTitle {
             let config_path = folder_path.join("model/config.json");
             let tokenizer_path = folder_path.join("model/tokenizer.json");
             if let Some(model_path_str) = model_path.to_str() {
-                let device = Device::cuda_if_available(0)
-                    .map_err(|e| PlayerError::Internal(e.to_string()))?;
+                let device = Device::cuda_if_available(0).map_err(PlayerError::subtitle_model)?;
                 let var_builder = VarBuilder::from_gguf(model_path_str, &device)
-                    .map_err(|e| PlayerError::Internal(e.to_string()))?;
+                    .map_err(PlayerError::subtitle_model)?;
 
                 let config = serde_json::from_reader::<_, Config>(
-                    File::open(config_path).map_err(|e| PlayerError::Internal(e.to_string()))?,
+                    File::open(config_path).map_err(PlayerError::subtitle_model)?,
                 )
-                .map_err(|e| PlayerError::Internal(e.to_string()))?;
+                .map_err(PlayerError::subtitle_model)?;
                 let whisper_recognizer =
                     candle_transformers::models::whisper::quantized_model::Whisper::load(
                         &var_builder,
                         config.clone(),
                     )
-                    .map_err(|e| PlayerError::Internal(e.to_string()))?;
+                    .map_err(PlayerError::subtitle_model)?;
                 let tokenizer = tokenizer::Tokenizer::from_file(tokenizer_path)
-                    .map_err(|e| PlayerError::Internal(e.to_string()))?;
-
-                unsafe {
-                    let mut swr_ctx = null_mut();
-                    let r = swr_alloc_set_opts2(
-                        &mut swr_ctx,
-                        &AV_CHANNEL_LAYOUT_MONO,
-                        ffmpeg_the_third::ffi::AVSampleFormat::AV_SAMPLE_FMT_FLT,
-                        16000,
-                        &AV_CHANNEL_LAYOUT_STEREO,
-                        ffmpeg_the_third::ffi::AVSampleFormat::AV_SAMPLE_FMT_FLT,
-                        48000,
-                        0,
-                        null_mut(),
-                    );
-                    if r < 0 {
-                        info!("swr ctx create err");
-                    }
-                    let r = swr_init(swr_ctx);
-                    if r < 0 {
-                        info!("swr init err");
+                    .map_err(PlayerError::subtitle_model)?;
+
+                let mel_filters = bytemuck::cast_slice::<_, f32>(MEL_FILTERS).to_vec();
+                let sot_id = tokenizer
+                    .token_to_id(candle_transformers::models::whisper::SOT_TOKEN)
+                    .ok_or(PlayerError::subtitle_model("sot token to id err"))?;
+
+                let transcribe_id = tokenizer
+                    .token_to_id(candle_transformers::models::whisper::TRANSCRIBE_TOKEN)
+                    .ok_or(PlayerError::subtitle_model("TRANSCRIBE_TOKEN to id err"))?;
+
+                let no_timestamp_id = tokenizer
+                    .token_to_id(candle_transformers::models::whisper::NO_TIMESTAMPS_TOKEN)
+                    .ok_or(PlayerError::subtitle_model("NO_TIMESTAMPS_TOKEN to id err"))?;
+                let eot_id = tokenizer
+                    .token_to_id(candle_transformers::models::whisper::EOT_TOKEN)
+                    .ok_or(PlayerError::subtitle_model("EOT_TOKEN to id err"))?;
+                let no_speech_token = whisper::NO_SPEECH_TOKENS
+                    .iter()
+                    .find_map(|token| tokenizer.token_to_id(token));
+                let no_speech_id = match no_speech_token {
+                    None => {
+                        return Err(PlayerError::subtitle_model(
+                            "unable to find any non-speech token",
+                        ));
                     }
-                    let mel_filters = bytemuck::cast_slice::<_, f32>(MEL_FILTERS).to_vec();
-                    let sot_id = tokenizer
-                        .token_to_id(candle_transformers::models::whisper::SOT_TOKEN)
-                        .ok_or(PlayerError::Internal("sot token to id err".to_string()))?;
-
-                    let transcribe_id = tokenizer
-                        .token_to_id(candle_transformers::models::whisper::TRANSCRIBE_TOKEN)
-                        .ok_or(PlayerError::Internal(
-                            "TRANSCRIBE_TOKEN to id err".to_string(),
-                        ))?;
-
-                    let no_timestamp_id = tokenizer
-                        .token_to_id(candle_transformers::models::whisper::NO_TIMESTAMPS_TOKEN)
-                        .ok_or(PlayerError::Internal(
-                            "NO_TIMESTAMPS_TOKEN to id err".to_string(),
-                        ))?;
-                    let eot_id = tokenizer
-                        .token_to_id(candle_transformers::models::whisper::EOT_TOKEN)
-                        .ok_or(PlayerError::Internal("EOT_TOKEN to id err".to_string()))?;
-                    let no_speech_token = whisper::NO_SPEECH_TOKENS
-                        .iter()
-                        .find_map(|token| tokenizer.token_to_id(token));
-                    let no_speech_id = match no_speech_token {
-                        None => {
-                            return Err(PlayerError::Internal(
-                                "unable to find any non-speech token".to_string(),
-                            ));
-                        }
-                        Some(n) => n,
-                    };
-                    let candle_streaming_mel_processor = CandleStreamingMelProcessor::new(
-                        Tensor::new(mel_filters, &device)
-                            .map_err(|e| PlayerError::Internal(e.to_string()))?,
-                        &device,
-                    )?;
-                    return Ok(Self {
-                        candle_streaming_mel_processor,
-                        subtitle_sender,
-
-                        _config: config,
-                        device,
-                        tokenizer,
-                        model: whisper_recognizer,
-                        subtitle_source_resampler: ManualProtectedResampler(swr_ctx),
-                        token_ids: TokenIds {
-                            sot_id,
-                            zh_language_id: 50260,
-                            en_language_id: 50259,
-                            transcribe_id,
-                            no_timestamp_id,
-                            eot_id,
-                            no_speech_id,
-                        },
-                    });
-                }
+                    Some(n) => n,
+                };
+                let candle_streaming_mel_processor = CandleStreamingMelProcessor::new(
+                    Tensor::new(mel_filters, &device).map_err(PlayerError::subtitle_model)?,
+                    &device,
+                )?;
+                return Ok(Self {
+                    candle_streaming_mel_processor,
+                    subtitle_sender,
+
+                    _config: config,
+                    device,
+                    tokenizer,
+                    model: whisper_recognizer,
+                    token_ids: TokenIds {
+                        sot_id,
+                        zh_language_id: 50260,
+                        en_language_id: 50259,
+                        transcribe_id,
+                        no_timestamp_id,
+                        eot_id,
+                        no_speech_id,
+                    },
+                });
             }
         }
 
-        Err(PlayerError::Internal(
-            "AISubTitle construct err".to_string(),
-        ))
+        Err(PlayerError::subtitle_model("AISubTitle construct err"))
     }
 
-    pub async fn push_frame_data(
-        &mut self,
-        audio_frame: ffmpeg_the_third::frame::Audio,
-        used_model: UsedModel,
-    ) {
-        let mut to_recognize_frame = Audio::empty();
-        to_recognize_frame.set_format(ffmpeg_the_third::format::Sample::F32(Type::Packed));
-        to_recognize_frame.set_ch_layout(ChannelLayout::MONO);
-        to_recognize_frame.set_rate(16000);
-        unsafe {
-            let resampler = &mut self.subtitle_source_resampler;
-
-            if 0 > swr_convert_frame(
-                resampler.0,
-                to_recognize_frame.as_mut_ptr(),
-                audio_frame.as_ptr(),
-            ) {
-                warn!("subtitle frame convert err!");
-            }
-        }
-        let data = &bytemuck::cast_slice::<_, f32>(to_recognize_frame.data(0))
-            [0..to_recognize_frame.samples()];
-        if let Ok(Some(t)) = self
-            .candle_streaming_mel_processor
-            .process_chunk(data.to_vec())
-        {
+    /// recognize the 16000hz mono samples, the text is sent once a chunk is decoded
+    fn push_pcm(&mut self, pcm: Vec<f32>, used_model: UsedModel) {
+        if let Ok(Some(t)) = self.candle_streaming_mel_processor.process_chunk(pcm) {
             if let Ok(audio_features) = self.model.encoder.forward(&t, true) {
                 if let UsedModel::Chinese = &used_model {
                     let mut tokens = vec![
@@ -353,7 +426,7 @@ impl AISubTitle {
                     }
                     if let Ok(text) = self.tokenizer.decode(&tokens, true) {
                         warn!("after decode text==={}", text);
-                        if let Err(e) = self.subtitle_sender.send(text).await {
+                        if let Err(e) = self.subtitle_sender.blocking_send(text) {
                             warn!("send subtitle err {}", e.to_string());
                         }
                     }
@@ -362,13 +435,6 @@ impl AISubTitle {
         }
     }
 }
-impl Drop for AISubTitle {
-    fn drop(&mut self) {
-        unsafe {
-            swr_free(&mut self.subtitle_source_resampler.0);
-        }
-    }
-}
 
 pub struct CandleStreamingMelProcessor {
     device: Device,
@@ -395,9 +461,9 @@ impl CandleStreamingMelProcessor {
         // 确保 mel_filters 形状正确并搬运到目标设备
         let mel_filters = mel_filters
             .reshape((80, 201))
-            .map_err(|e| PlayerError::Internal(e.to_string()))?
+            .map_err(PlayerError::subtitle_model)?
             .to_device(device)
-            .map_err(|e| PlayerError::Internal(e.to_string()))?;
+            .map_err(PlayerError::subtitle_model)?;
 
         Ok(Self {
             device: device.clone(),
@@ -455,17 +521,12 @@ impl CandleStreamingMelProcessor {
         // 2. 批量计算功率谱
         let power_spec = self
             .frames_to_power_spec(&frames)
-            .map_err(|e| PlayerError::Internal(e.to_string()))?;
+            .map_err(PlayerError::subtitle_model)?;
 
         // 3. 矩阵乘法转 Mel: [frames, 201] * [201, 80] -> [frames, 80]
         let mel = power_spec
-            .matmul(
-                &self
-                    .mel_filters
-                    .t()
-                    .map_err(|e| PlayerError::Internal(e.to_string()))?,
-            )
-            .map_err(|e| PlayerError::Internal(e.to_string()))?;
+            .matmul(&self.mel_filters.t().map_err(PlayerError::subtitle_model)?)
+            .map_err(PlayerError::subtitle_model)?;
 
         // 4. Log-Mel 变换与归一化 (核心修正点)
         // Whisper 标准: log10(mel) -> 映射到 [-1, 1]
@@ -474,9 +535,9 @@ impl CandleStreamingMelProcessor {
         // 1. 将 Tensor 转回 Rust 的 Vec<f32> 进行高精度 log10 处理
         let mel_vec = mel
             .flatten_all()
-            .map_err(|e| PlayerError::Internal(e.to_string()))?
+            .map_err(PlayerError::subtitle_model)?
             .to_vec1::<f32>()
-            .map_err(|e| PlayerError::Internal(e.to_string()))?;
+            .map_err(PlayerError::subtitle_model)?;
 
         // 2. 使用 Rust 标准库 f32::log10 处理
         // 我们同时在这里处理 clamp，防止 log10(-inf)
@@ -484,36 +545,36 @@ impl CandleStreamingMelProcessor {
 
         // 3. 转回 Tensor
         let log10_mel = Tensor::from_vec(log_mel_vec, shape, &self.device)
-            .map_err(|e| PlayerError::Internal(e.to_string()))?;
+            .map_err(PlayerError::subtitle_model)?;
 
         // 1. 获取当前批次的最大值
         let max_val = log10_mel
             .max_all()
-            .map_err(|e| PlayerError::Internal(e.to_string()))?
+            .map_err(PlayerError::subtitle_model)?
             .to_scalar::<f32>()
-            .map_err(|e| PlayerError::Internal(e.to_string()))?;
+            .map_err(PlayerError::subtitle_model)?;
 
         // 2. 创建一个标量 Tensor 作为阈值 (注意必须在同一个 device 上)
         let threshold = Tensor::from_vec(vec![max_val - 8.0], (1, 1), &self.device)
-            .map_err(|e| PlayerError::Internal(e.to_string()))?
+            .map_err(PlayerError::subtitle_model)?
             .broadcast_as(log10_mel.shape())
-            .map_err(|e| PlayerError::Internal(e.to_string()))?;
+            .map_err(PlayerError::subtitle_model)?;
 
         // 3. 使用 broadcast_maximum (或简写 maximum)
         let log_mel = log10_mel
             .maximum(&threshold)
-            .map_err(|e| PlayerError::Internal(e.to_string()))?
+            .map_err(PlayerError::subtitle_model)?
             .affine(1.0 / 4.0, 1.0)
-            .map_err(|e| PlayerError::Internal(e.to_string()))?;
+            .map_err(PlayerError::subtitle_model)?;
 
         // 5. 逐帧推入缓冲区
         // 修正：从 log_mel 中提取每一帧时，保持 Tensor 引用
         for i in 0..num_frames {
             let frame = log_mel
                 .narrow(0, i, 1)
-                .map_err(|e| PlayerError::Internal(e.to_string()))?
+                .map_err(PlayerError::subtitle_model)?
                 .squeeze(0)
-                .map_err(|e| PlayerError::Internal(e.to_string()))?;
+                .map_err(PlayerError::subtitle_model)?;
             self.mel_buffer.push(frame);
         }
 
@@ -533,27 +594,25 @@ impl CandleStreamingMelProcessor {
 
         // 重点：Whisper Encoder 期望的形状是 [Batch, 80, Time]
         let final_mel = Tensor::stack(&self.mel_buffer, 0)
-            .map_err(|e| PlayerError::Internal(e.to_string()))?
+            .map_err(PlayerError::subtitle_model)?
             .t() // [80, Time]
-            .map_err(|e| PlayerError::Internal(e.to_string()))?
+            .map_err(PlayerError::subtitle_model)?
             .unsqueeze(0) // [1, 80, Time]
-            .map_err(|e| PlayerError::Internal(e.to_string()))?;
+            .map_err(PlayerError::subtitle_model)?;
         self.mel_buffer.clear();
-        let (_, _, current_frames) = final_mel
-            .dims3()
-            .map_err(|e| PlayerError::Internal(e.to_string()))?;
+        let (_, _, current_frames) = final_mel.dims3().map_err(PlayerError::subtitle_model)?;
         let final_mel = if current_frames < 3000 {
             let pad_size = 3000 - current_frames;
             let pad = Tensor::zeros((1, 80, pad_size), final_mel.dtype(), final_mel.device())
-                .map_err(|e| PlayerError::Internal(e.to_string()))?
+                .map_err(PlayerError::subtitle_model)?
                 .affine(1.0, -1.0) // 0.0 * 1.0 + (-1.0) = -1.0
-                .map_err(|e| PlayerError::Internal(e.to_string()))?;
+                .map_err(PlayerError::subtitle_model)?;
 
-            Tensor::cat(&[final_mel, pad], 2).map_err(|e| PlayerError::Internal(e.to_string()))?
+            Tensor::cat(&[final_mel, pad], 2).map_err(PlayerError::subtitle_model)?
         } else {
             final_mel
                 .narrow(2, 0, 3000)
-                .map_err(|e| PlayerError::Internal(e.to_string()))?
+                .map_err(PlayerError::subtitle_model)?
         };
         Ok(Some(final_mel))
     }
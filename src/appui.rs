@@ -1,11 +1,13 @@
 use std::{
+    ops::RangeInclusive,
     path::{Path, PathBuf},
     sync::{Arc, LazyLock},
     time::{Duration, Instant},
 };
 
 use eframe::{
-    Frame,
+    CreationContext, Frame,
+    egui_wgpu::Callback,
     wgpu::{Extent3d, Origin3d, TexelCopyBufferLayout, TexelCopyTextureInfo, TextureAspect},
 };
 use egui::{
@@ -14,23 +16,33 @@ use egui::{
     WidgetText, include_image,
 };
 
-use ffmpeg_the_third::{format::stream::Disposition, frame::Video, media::Type};
+use ffmpeg_the_third::{
+    format::{Pixel, stream::Disposition},
+    frame::Video,
+    media::Type,
+};
 use image::{DynamicImage, EncodableLayout, RgbaImage};
 
 use tokio::{
-    runtime::Runtime,
-    sync::{
-        Notify, RwLock, mpsc,
-        watch::{self, Receiver, Sender},
-    },
+    runtime::Handle,
+    sync::{RwLock, broadcast},
 };
 use tracing::{info, warn};
 
 use crate::{
     PlayerError, PlayerResult,
-    ai_sub_title::{AISubTitle, UsedModel},
-    decode::{MainStream, TinyDecoder},
-    present_data_manage::{DataManageContextBuilder, PresentDataManager},
+    ai_sub_title::UsedModel,
+    buffer::BufferLimits,
+    clock::ClockSource,
+    decode::{
+        ColorMatrixOverride, ColorRangeOverride, DecoderThreading, HardwarePolicy, SeekMode,
+        TinyDecoder, ToneMapOperator, VideoFilterChain, VideoRenderer,
+    },
+    graphics::{GraphicsBackend, GraphicsSettings},
+    player::{Player, PlayerEvent, PlayerSnapshot},
+    player_state::PlayerState,
+    subtitle::{SubtitleContent, SubtitleCues},
+    yuv_render::{YuvPaintCallback, YuvRenderer},
 };
 
 const VIDEO_FILE_IMG: ImageSource = include_image!("../resources/file-play.png");
@@ -41,6 +53,7 @@ const FULLSCREEN_IMG: ImageSource = include_image!("../resources/fullscreen.png"
 const DEFAULT_BG_IMG: ImageSource = include_image!("../resources/background.png");
 const PLAY_LIST_IMG: ImageSource = include_image!("../resources/list-video.png");
 const SUBTITLE_IMG: ImageSource = include_image!("../resources/captions.png");
+const PLAYBACK_SPEEDS: [f64; 9] = [0.25, 0.5, 0.75, 1.0, 1.25, 1.5, 2.0, 3.0, 4.0];
 pub const MAPLE_FONT: &[u8] = include_bytes!("../resources/fonts/MapleMono-CN-Regular.ttf");
 const EMOJI_FONT: &[u8] = include_bytes!("../resources/fonts/seguiemj.ttf");
 static THEME_COLOR: LazyLock<Color32> = LazyLock::new(|| {
@@ -78,22 +91,39 @@ impl Widget for PlayerTextButton {
 }
 /// the main struct stores all the vars which are related to ui
 struct UiFlags {
-    pause_flag: (Sender<bool>, Receiver<bool>),
     fullscreen_flag: bool,
     control_ui_flag: bool,
     tip_window_flag: bool,
     playlist_window_flag: bool,
     show_subtitle_options_flag: bool,
+    show_audio_track_options_flag: bool,
+    show_speed_options_flag: bool,
+    show_video_filter_options_flag: bool,
+    show_settings_flag: bool,
+    show_stats_flag: bool,
     show_volumn_slider_flag: bool,
 }
 
 pub struct AppUi {
     video_texture_handle: Option<TextureHandle>,
-    tiny_decoder: Arc<RwLock<crate::decode::TinyDecoder>>,
-    audio_player: crate::audio_play::AudioPlayer,
-    _present_data_manager: PresentDataManager,
-    main_stream_current_timestamp: Arc<RwLock<i64>>,
+    player: Player,
+    player_events: broadcast::Receiver<PlayerEvent>,
+    /// what the player published last, refreshed once every redraw
+    snapshot: PlayerSnapshot,
+    /// the cover picture the main color image is made from
+    shown_cover_pic: Option<Arc<Vec<u8>>>,
     main_color_image: ColorImage,
+    /// what the video texture holds now, it is written again only when this changes
+    uploaded_video_frame: Option<Arc<Video>>,
+    main_color_image_uploaded: bool,
+    /// the yuv shader can draw the frames, the wgpu renderer of egui is running
+    yuv_renderer_installed: bool,
+    /// the uploaded frame is drawn by the yuv shader instead of the video texture
+    yuv_frame_shown: bool,
+    /// the saved graphics settings, used from the next start
+    graphics_settings: GraphicsSettings,
+    /// the adapter and backend the window is drawn with
+    graphics_adapter_text: String,
     bg_dyn_img: DynamicImage,
     frame_show_instant: Instant,
     ui_flags: UiFlags,
@@ -101,19 +131,20 @@ pub struct AppUi {
     time_text: String,
     tip_window_msg: String,
     last_show_control_ui_instant: Instant,
-    app_start_instant: Instant,
-    current_video_frame: Arc<RwLock<Video>>,
-    async_rt: Runtime,
-    opened_file: Option<std::path::PathBuf>,
+    async_rt: Handle,
     open_file_dialog: Option<egui_file::FileDialog>,
+    open_subtitle_dialog: Option<egui_file::FileDialog>,
     scan_folder_dialog: Option<egui_file::FileDialog>,
-    _subtitle: Arc<RwLock<AISubTitle>>,
     subtitle_text: String,
-    subtitle_text_receiver: mpsc::Receiver<String>,
+    subtitle_bitmap_textures: Vec<SubtitleBitmapTexture>,
+    external_subtitle_cues: Option<SubtitleCues>,
+    video_filter_chain: VideoFilterChain,
+    video_filter_text: String,
+    video_threading: DecoderThreading,
+    audio_threading: DecoderThreading,
+    buffer_limits: BufferLimits,
     video_des: Arc<RwLock<Vec<VideoDes>>>,
-    used_model: Arc<RwLock<UsedModel>>,
     audio_volumn: f32,
-    data_thread_notify: Arc<Notify>,
 }
 impl eframe::App for AppUi {
     /// this function will automaticly be called every ui redraw
@@ -130,40 +161,15 @@ impl eframe::App for AppUi {
                     self.load_video_texture(ctx);
                     self.frame_show_instant = now;
                 }
-                {
-                    if let Ok(tiny_decoder) = self.tiny_decoder.try_read() {
-                        if self.async_rt.block_on(tiny_decoder.is_input_exist()) {
-                            if !*self.ui_flags.pause_flag.1.borrow() {
-                                /*
-                                if now is next_frame_time or a little beyond get and show a new frame
-                                 */
-                                if keepawake::Builder::default()
-                                    .display(true)
-                                    .idle(true)
-                                    .app_name("tiny_player")
-                                    .reason("video play")
-                                    .create()
-                                    .is_err()
-                                {
-                                    warn!("keep awake err");
-                                }
-                                self.notify_data_thread(&tiny_decoder);
-                                if self.check_play_is_at_endtail(&tiny_decoder) {
-                                    if self.ui_flags.pause_flag.0.send(true).is_err() {
-                                        warn!("change pause flag err");
-                                    }
-                                }
-                            }
-                        }
-                    }
-                }
+                self.step_video_frame(ctx);
+                self.handle_player_events(&now);
                 self.copy_video_data_to_texture(frame);
                 /*
                 down part is ui painting and control
 
                  */
                 self.paint_video_image(ctx, ui);
-                self.paint_frame_info_text(ui, ctx, &now);
+                self.paint_frame_info_text(ui, ctx);
                 if self.ui_flags.control_ui_flag {
                     ui.set_opacity(1.0);
                 } else {
@@ -171,6 +177,7 @@ impl eframe::App for AppUi {
                 }
                 ui.horizontal(|ui| {
                     self.paint_tip_window(ctx);
+                    self.paint_open_subtitle_dialog(ctx);
                     self.paint_file_btn(ui, ctx, &now);
                     ui.with_layout(egui::Layout::right_to_left(egui::Align::Min), |ui| {
                         self.paint_playlist_button(ui, ctx, &now);
@@ -183,7 +190,6 @@ impl eframe::App for AppUi {
                 });
 
                 ui.with_layout(Layout::bottom_up(egui::Align::Min), |ui| {
-                    self.update_time_and_time_text();
                     self.paint_control_area(ui, ctx, &now);
                     self.paint_subtitle(ui, ctx);
                 });
@@ -196,6 +202,22 @@ impl eframe::App for AppUi {
     }
 }
 impl AppUi {
+    /// let the shader draw the yuv frames when egui renders with wgpu,
+    /// the frames stay converted by swscale otherwise
+    pub fn install_video_renderer(&mut self, cc: &CreationContext) {
+        if let Some(render_state) = &cc.wgpu_render_state {
+            let adapter_info = render_state.adapter.get_info();
+            self.graphics_adapter_text = format!(
+                "{} {:?} {:?}",
+                adapter_info.name, adapter_info.backend, adapter_info.device_type
+            );
+            YuvRenderer::install(render_state);
+            self.yuv_renderer_installed = true;
+            self.player.set_video_renderer(VideoRenderer::GpuYuv);
+        } else {
+            warn!("no wgpu render state, the video frames are converted by swscale");
+        }
+    }
     pub fn replace_fonts(&self, ctx: &egui::Context) {
         // Start with the default fonts (we will be adding to them rather than replacing them).
         let mut fonts = egui::FontDefinitions::default();
@@ -238,11 +260,6 @@ impl AppUi {
         let play_time =
             time::Time::from_hms(0, 0, 0).map_err(|e| PlayerError::Internal(e.to_string()))?;
 
-        let async_rt = tokio::runtime::Builder::new_multi_thread()
-            .enable_all()
-            .build()
-            .map_err(|e| PlayerError::Internal(e.to_string()))?;
-        let rt = async_rt.handle().clone();
         let f_dialog = egui_file::FileDialog::open_file();
         let (color_image, dyn_img) = {
             if let ImageSource::Bytes { bytes, .. } = DEFAULT_BG_IMG {
@@ -260,69 +277,60 @@ impl AppUi {
             }
         }?;
 
-        let tiny_decoder = crate::decode::TinyDecoder::new(rt.clone())?;
-        let tiny_decoder = Arc::new(RwLock::new(tiny_decoder));
-        let used_model = Arc::new(RwLock::new(UsedModel::Empty));
-        let subtitle_channel = mpsc::channel(10);
-        let subtitle = Arc::new(RwLock::new(AISubTitle::new(subtitle_channel.0)?));
-        let audio_player = crate::audio_play::AudioPlayer::new()?;
-        let empty_frame = Video::empty();
-        let current_video_frame = Arc::new(RwLock::new(empty_frame));
-        let main_stream_current_timestamp = Arc::new(RwLock::new(0));
-        let pause_flag = watch::channel(true);
-
-        let data_thread_notify = Arc::new(Notify::new());
-        let data_manage_context = DataManageContextBuilder::default()
-            .data_thread_notify(data_thread_notify.clone())
-            .tiny_decoder(tiny_decoder.clone())
-            .used_model(used_model.clone())
-            .ai_subtitle(subtitle.clone())
-            .current_video_frame(current_video_frame.clone())
-            .audio_sink(audio_player.sink())
-            .main_stream_current_timestamp(main_stream_current_timestamp.clone())
-            .runtime_handle(rt)
-            .build()
-            .map_err(|e| PlayerError::Internal(e.to_string()))?;
-        let present_data_manager = PresentDataManager::new(data_manage_context);
+        let player = Player::new()?;
+        let player_events = player.subscribe();
+        let snapshot = player.snapshot();
+        let async_rt = player.runtime_handle();
 
         Ok(Self {
-            subtitle_text_receiver: subtitle_channel.1,
             video_texture_handle: None,
-            tiny_decoder,
-            audio_player,
-            _present_data_manager: present_data_manager,
-            main_stream_current_timestamp,
+            player,
+            player_events,
+            snapshot,
+            shown_cover_pic: None,
             play_time,
             main_color_image: color_image,
+            uploaded_video_frame: None,
+            main_color_image_uploaded: false,
+            yuv_renderer_installed: false,
+            yuv_frame_shown: false,
+            graphics_settings: GraphicsSettings::load(),
+            graphics_adapter_text: String::new(),
             frame_show_instant: Instant::now(),
             ui_flags: UiFlags {
-                pause_flag,
                 fullscreen_flag: false,
                 control_ui_flag: true,
                 tip_window_flag: false,
                 playlist_window_flag: false,
                 show_subtitle_options_flag: false,
+                show_audio_track_options_flag: false,
+                show_speed_options_flag: false,
+                show_video_filter_options_flag: false,
+                show_settings_flag: false,
+                show_stats_flag: false,
                 show_volumn_slider_flag: false,
             },
-            used_model,
 
             time_text: String::new(),
 
             tip_window_msg: String::new(),
 
             last_show_control_ui_instant: Instant::now(),
-            app_start_instant: Instant::now(),
-            current_video_frame,
             async_rt,
-            opened_file: None,
             open_file_dialog: Some(f_dialog),
+            open_subtitle_dialog: Some(egui_file::FileDialog::open_file()),
             scan_folder_dialog: Some(egui_file::FileDialog::select_folder()),
             bg_dyn_img: dyn_img,
-            _subtitle: subtitle,
             subtitle_text: String::new(),
+            subtitle_bitmap_textures: vec![],
+            external_subtitle_cues: None,
+            video_filter_chain: VideoFilterChain::default(),
+            video_filter_text: String::new(),
+            video_threading: DecoderThreading::default_video(),
+            audio_threading: DecoderThreading::default_audio(),
+            buffer_limits: BufferLimits::default(),
             video_des: Arc::new(RwLock::new(vec![])),
             audio_volumn: 1.0,
-            data_thread_notify,
         })
     }
     fn paint_video_image(&mut self, ctx: &egui::Context, ui: &mut Ui) {
@@ -330,72 +338,60 @@ impl AppUi {
         show image that contains the video texture
          */
         let layer_painter = ctx.layer_painter(ui.layer_id());
-        if let Some(video_texture_handle) = &self.video_texture_handle {
+        let video_rect = Rect::from_min_max(
+            Pos2::new(0.0, 0.0),
+            Pos2::new(ctx.content_rect().width(), ctx.content_rect().height()),
+        );
+        if self.yuv_frame_shown {
+            layer_painter.add(Callback::new_paint_callback(video_rect, YuvPaintCallback));
+        } else if let Some(video_texture_handle) = &self.video_texture_handle {
             layer_painter.image(
                 video_texture_handle.id(),
-                Rect::from_min_max(
-                    Pos2::new(0.0, 0.0),
-                    Pos2::new(ctx.content_rect().width(), ctx.content_rect().height()),
-                ),
+                video_rect,
                 Rect::from_min_max(Pos2::new(0.0, 0.0), Pos2::new(1.0, 1.0)),
                 Color32::WHITE,
             );
         }
     }
-    fn update_time_and_time_text(&mut self) {
-        if let Ok(tiny_decoder) = self.tiny_decoder.try_read() {
-            if self.async_rt.block_on(tiny_decoder.is_input_exist()) {
-                if let Ok(play_ts) = self.main_stream_current_timestamp.try_read() {
-                    let sec_num = {
-                        if let MainStream::Audio = tiny_decoder.main_stream() {
-                            let audio_time_base = tiny_decoder.audio_time_base();
-                            *play_ts * audio_time_base.numerator() as i64
-                                / audio_time_base.denominator() as i64
-                        } else {
-                            let v_time_base = tiny_decoder.video_time_base();
-
-                            *play_ts * v_time_base.numerator() as i64
-                                / v_time_base.denominator() as i64
-                        }
-                    };
-                    let sec = (sec_num % 60) as u8;
-                    let min_num = sec_num / 60;
-                    let min = (min_num % 60) as u8;
-                    let hour_num = min_num / 60;
-                    let hour = hour_num as u8;
-                    if let Ok(cur_time) = time::Time::from_hms(hour, min, sec) {
-                        if !cur_time.eq(&self.play_time) {
-                            if let Ok(formatter) =
-                                time::format_description::parse("[hour]:[minute]:[second]")
-                            {
-                                if let Ok(mut now_str) = cur_time.format(&formatter) {
-                                    now_str.push('|');
-                                    now_str.push_str(tiny_decoder.end_time_formatted_string());
-                                    self.time_text = now_str;
-                                    self.play_time = cur_time;
-                                }
-                            }
-                        }
-                    } else {
-                        warn!("update time str err!");
+    /// `position_ms` is the play position from the media start
+    fn update_time_text(&mut self, position_ms: i64) {
+        let sec_num = position_ms.max(0) / 1000;
+        let sec = (sec_num % 60) as u8;
+        let min_num = sec_num / 60;
+        let min = (min_num % 60) as u8;
+        let hour_num = min_num / 60;
+        let hour = hour_num as u8;
+        if let Ok(cur_time) = time::Time::from_hms(hour, min, sec) {
+            if !cur_time.eq(&self.play_time) || self.time_text.is_empty() {
+                if let Ok(formatter) = time::format_description::parse("[hour]:[minute]:[second]") {
+                    if let Ok(mut now_str) = cur_time.format(&formatter) {
+                        now_str.push('|');
+                        now_str.push_str(&self.snapshot.end_time_text);
+                        self.time_text = now_str;
+                        self.play_time = cur_time;
                     }
                 }
             }
+        } else {
+            warn!("update time str err!");
         }
     }
     fn update_color_image(&mut self) {
-        if let Ok(tiny_decoder) = self.tiny_decoder.try_read() {
-            let frame_rect = tiny_decoder.video_frame_rect();
-            if frame_rect[0] != 0 {
-                let color_image = ColorImage::filled(
-                    [frame_rect[0] as usize, frame_rect[1] as usize],
-                    Color32::from_rgba_unmultiplied(0, 0, 0, 255),
-                );
+        let frame_rect = self.snapshot.video_frame_rect;
+        if frame_rect[0] != 0 {
+            let color_image = ColorImage::filled(
+                [frame_rect[0] as usize, frame_rect[1] as usize],
+                Color32::from_rgba_unmultiplied(0, 0, 0, 255),
+            );
 
-                self.main_color_image = color_image;
-            }
+            self.set_main_color_image(color_image);
         }
     }
+    /// the texture shows the new image at the next redraw when no video frame is shown
+    fn set_main_color_image(&mut self, color_image: ColorImage) {
+        self.main_color_image = color_image;
+        self.main_color_image_uploaded = false;
+    }
     fn load_video_texture(&mut self, ctx: &egui::Context) {
         /*
         从image背景图加载视频显示用texture
@@ -407,6 +403,7 @@ impl AppUi {
             TextureOptions::LINEAR,
         );
         self.video_texture_handle = Some(t);
+        self.main_color_image_uploaded = true;
     }
 
     fn paint_file_btn(&mut self, ui: &mut Ui, ctx: &Context, now: &Instant) {
@@ -428,33 +425,27 @@ impl AppUi {
             }
         }
 
+        let mut selected_path = None;
         if let Some(d) = &mut self.open_file_dialog {
             d.show(ctx);
             if d.selected() {
                 if let Some(p) = d.path() {
                     warn!("path selected{:#?}", p);
-                    self.opened_file = Some(p.to_path_buf());
+                    selected_path = Some(p.to_path_buf());
                 }
             }
         }
 
-        if let Some(path) = self.opened_file.take() {
-            if self.change_format_input(path.as_path(), now).is_ok() {
-                if let Some(p_str) = path.to_str() {
-                    warn!("accept file path{}", p_str);
-                }
-            } else {
-                self.tip_window_msg = "please choose a valid video or audio file !!!".to_string();
-                self.ui_flags.tip_window_flag = true;
-            }
+        if let Some(path) = selected_path {
+            // a failed open is shown by the error event of the player
+            warn!("accept file path{:?}", path);
+            self.player.open(&path);
         }
     }
 
     fn paint_playpause_btn(&mut self, ui: &mut Ui, ctx: &Context, now: &Instant) {
-        let decoder = self.tiny_decoder.clone();
-        let tiny_decoder = self.async_rt.block_on(decoder.read());
-        if self.async_rt.block_on(tiny_decoder.is_input_exist()) {
-            let play_or_pause_image_source = if *self.ui_flags.pause_flag.1.borrow() {
+        if self.snapshot.state.has_input() {
+            let play_or_pause_image_source = if self.snapshot.paused {
                 PLAY_IMG
             } else {
                 PAUSE_IMG
@@ -473,32 +464,60 @@ impl AppUi {
                 self.last_show_control_ui_instant = *now;
             }
             if btn_response.clicked() || ctx.input(|s| s.key_released(egui::Key::Space)) {
-                let pause_flag = &self.ui_flags.pause_flag;
-                let previous_v = *pause_flag.1.borrow();
-                if pause_flag.0.send(!previous_v).is_err() {
-                    warn!("change pause flag err");
-                }
-                let audio_player = &self.audio_player;
-                if *pause_flag.1.borrow() {
-                    audio_player.pause();
-                } else {
-                    audio_player.play();
-                }
+                self.player.toggle_pause();
             }
         }
     }
 
+    /// step the video one frame forward with `.` or one frame backward with `,` while paused
+    fn step_video_frame(&mut self, ctx: &Context) {
+        let (step_forward, step_backward) = ctx.input(|input| {
+            (
+                input.key_pressed(egui::Key::Period),
+                input.key_pressed(egui::Key::Comma),
+            )
+        });
+        if step_forward {
+            self.player.step_frame_forward();
+        } else if step_backward {
+            self.player.step_frame_backward();
+        }
+    }
+    /// edit a byte budget of the buffers in MB, return true if it changed
+    fn byte_budget_drag_value(
+        ui: &mut Ui,
+        bytes: &mut usize,
+        range_mb: RangeInclusive<usize>,
+    ) -> bool {
+        let mut mb = *bytes / 1024 / 1024;
+        let changed = ui
+            .add(egui::DragValue::new(&mut mb).range(range_mb))
+            .changed();
+        if changed {
+            *bytes = mb * 1024 * 1024;
+        }
+        changed
+    }
     fn paint_control_area(&mut self, ui: &mut Ui, ctx: &Context, now: &Instant) {
+        let mut selected_audio_track = None;
+        let mut selected_subtitle_track = None;
+        let mut selected_speed = None;
+        let mut video_filter_applied = false;
+        let mut selected_color_override = None;
+        let mut selected_hardware_policy = None;
+        let mut selected_video_renderer = None;
+        let mut decoder_threading_applied = false;
+        let mut buffer_limits_changed = false;
+        let mut selected_clock_source = None;
+        let mut selected_seek = None;
+        let mut selected_used_model = None;
         ui.horizontal(|ui| {
-            let decoder = self.tiny_decoder.clone();
-            let tiny_decoder = self.async_rt.block_on(decoder.read());
-            if self.async_rt.block_on(tiny_decoder.is_input_exist()) {
-                let mut timestamp = self
-                    .async_rt
-                    .block_on(self.main_stream_current_timestamp.write());
+            let snapshot = &self.snapshot;
+            if snapshot.state.has_input() {
+                let mut timestamp = snapshot.timestamp;
                 let mut slider_color = THEME_COLOR.to_srgba_unmultiplied();
                 slider_color[3] = 100;
-                let progress_slider = egui::Slider::new(&mut *timestamp, 0..=tiny_decoder.end_ts())
+                let progress_slider = egui::Slider::new(&mut timestamp, 0..=snapshot.end_timestamp)
                     .show_value(false)
                     .text(WidgetText::RichText(Arc::new(
                         RichText::new(self.time_text.clone()).size(20.0).color(
@@ -528,20 +547,21 @@ impl AppUi {
                     self.ui_flags.control_ui_flag = true;
                     self.last_show_control_ui_instant = Instant::now();
                 }
-                if slider_response.changed() {
-                    warn!("slider dragged!");
-                    let audio_player = &mut self.audio_player;
-
-                    tiny_decoder.seek_timestamp_to_decode(*timestamp);
-                    audio_player.source_queue_skip_to_end();
-                    if !*self.ui_flags.pause_flag.1.borrow() {
-                        audio_player.play();
+                // keyframe seek while scrubbing, exact seek when the slider is released or clicked
+                let seek_mode = if slider_response.drag_stopped() {
+                    Some(SeekMode::Accurate)
+                } else if slider_response.changed() {
+                    if slider_response.dragged() {
+                        Some(SeekMode::Fast)
+                    } else {
+                        Some(SeekMode::Accurate)
                     }
-                    self.frame_show_instant = *now;
-                    let cur_v_frame = self.current_video_frame.clone();
-                    let mut current_video_frame = self.async_rt.block_on(cur_v_frame.write());
-                    let empty_frame = Video::empty();
-                    *current_video_frame = empty_frame;
+                } else {
+                    None
+                };
+                if let Some(seek_mode) = seek_mode {
+                    warn!("slider dragged!");
+                    selected_seek = Some((timestamp, seek_mode));
                 }
                 ui.with_layout(Layout::bottom_up(egui::Align::Min), |ui| {
                     let subtitle_btn =
@@ -555,12 +575,343 @@ impl AppUi {
                         self.ui_flags.show_subtitle_options_flag =
                             !self.ui_flags.show_subtitle_options_flag;
                     }
-                    let used_model = self.used_model.clone();
-                    let mut used_model = self.async_rt.block_on(used_model.write());
                     if self.ui_flags.show_subtitle_options_flag {
-                        ui.radio_value(&mut *used_model, UsedModel::Empty, "closed");
-                        ui.radio_value(&mut *used_model, UsedModel::Chinese, "中文");
-                        ui.radio_value(&mut *used_model, UsedModel::English, "English");
+                        let mut used_model = snapshot.used_model.clone();
+                        for (model, text) in [
+                            (UsedModel::Empty, "closed"),
+                            (UsedModel::Chinese, "中文"),
+                            (UsedModel::English, "English"),
+                        ] {
+                            if ui.radio_value(&mut used_model, model, text).changed() {
+                                selected_used_model = Some(used_model.clone());
+                            }
+                        }
+                        if ui.button("open subtitle file").clicked() {
+                            if let Some(dialog) = &mut self.open_subtitle_dialog {
+                                dialog.open();
+                            }
+                        }
+                        if !snapshot.subtitle_tracks.is_empty() {
+                            ui.separator();
+                            let mut current_track = snapshot.subtitle_stream_index;
+                            if ui.radio_value(&mut current_track, None, "off").changed() {
+                                selected_subtitle_track = Some(None);
+                            }
+                            for track in &snapshot.subtitle_tracks {
+                                if ui
+                                    .radio_value(
+                                        &mut current_track,
+                                        Some(track.stream_index),
+                                        track.label(),
+                                    )
+                                    .changed()
+                                {
+                                    selected_subtitle_track = Some(Some(track.stream_index));
+                                }
+                            }
+                        }
+                    }
+                });
+                if snapshot.audio_tracks.len() > 1 {
+                    ui.with_layout(Layout::bottom_up(egui::Align::Min), |ui| {
+                        let btn_response = ui.add(PlayerTextButton::new("🎵", 36.0, false));
+                        if btn_response.hovered() {
+                            self.ui_flags.control_ui_flag = true;
+                            self.last_show_control_ui_instant = *now;
+                        }
+                        if btn_response.clicked() {
+                            self.ui_flags.show_audio_track_options_flag =
+                                !self.ui_flags.show_audio_track_options_flag;
+                        }
+                        if self.ui_flags.show_audio_track_options_flag {
+                            let mut current_track = snapshot.audio_stream_index;
+                            for track in &snapshot.audio_tracks {
+                                if ui
+                                    .radio_value(
+                                        &mut current_track,
+                                        track.stream_index,
+                                        track.label(),
+                                    )
+                                    .changed()
+                                {
+                                    selected_audio_track = Some(track.stream_index);
+                                }
+                            }
+                        }
+                    });
+                }
+                ui.with_layout(Layout::bottom_up(egui::Align::Min), |ui| {
+                    let speed_text = format!("{}x", snapshot.speed);
+                    let btn_response = ui.add(PlayerTextButton::new(speed_text, 30.0, false));
+                    if btn_response.hovered() {
+                        self.ui_flags.control_ui_flag = true;
+                        self.last_show_control_ui_instant = *now;
+                    }
+                    if btn_response.clicked() {
+                        self.ui_flags.show_speed_options_flag =
+                            !self.ui_flags.show_speed_options_flag;
+                    }
+                    if self.ui_flags.show_speed_options_flag {
+                        let mut current_speed = snapshot.speed;
+                        for speed in PLAYBACK_SPEEDS {
+                            if ui
+                                .radio_value(&mut current_speed, speed, format!("{speed}x"))
+                                .changed()
+                            {
+                                selected_speed = Some(speed);
+                            }
+                        }
+                    }
+                });
+                ui.with_layout(Layout::bottom_up(egui::Align::Min), |ui| {
+                    let btn_response = ui.add(PlayerTextButton::new("🎨", 36.0, false));
+                    if btn_response.hovered() {
+                        self.ui_flags.control_ui_flag = true;
+                        self.last_show_control_ui_instant = *now;
+                    }
+                    if btn_response.clicked() {
+                        self.ui_flags.show_video_filter_options_flag =
+                            !self.ui_flags.show_video_filter_options_flag;
+                    }
+                    if self.ui_flags.show_video_filter_options_flag {
+                        if ui.button("apply").clicked() {
+                            video_filter_applied = true;
+                        }
+                        let filter_text_response = ui.add(
+                            egui::TextEdit::singleline(&mut self.video_filter_text)
+                                .hint_text("hflip,eq=contrast=1.2")
+                                .desired_width(200.0),
+                        );
+                        if filter_text_response.hovered() || filter_text_response.has_focus() {
+                            self.ui_flags.control_ui_flag = true;
+                            self.last_show_control_ui_instant = *now;
+                        }
+                        if filter_text_response.lost_focus()
+                            && ui.input(|i| i.key_pressed(egui::Key::Enter))
+                        {
+                            video_filter_applied = true;
+                        }
+                        if ui
+                            .checkbox(&mut self.video_filter_chain.watermark, "watermark")
+                            .changed()
+                        {
+                            video_filter_applied = true;
+                        }
+                        if ui
+                            .checkbox(
+                                &mut self.video_filter_chain.tone_mapping,
+                                "HDR tone mapping",
+                            )
+                            .changed()
+                        {
+                            video_filter_applied = true;
+                        }
+                        if self.video_filter_chain.tone_mapping {
+                            for (operator, text) in [
+                                (ToneMapOperator::Hable, "hable"),
+                                (ToneMapOperator::Reinhard, "reinhard"),
+                                (ToneMapOperator::Mobius, "mobius"),
+                            ] {
+                                if ui
+                                    .radio_value(
+                                        &mut self.video_filter_chain.tone_map_operator,
+                                        operator,
+                                        text,
+                                    )
+                                    .changed()
+                                {
+                                    video_filter_applied = true;
+                                }
+                            }
+                        }
+                        let mut color_matrix = snapshot.color_matrix;
+                        let mut color_range = snapshot.color_range;
+                        let mut color_changed = false;
+                        ui.horizontal(|ui| {
+                            for (matrix, text) in [
+                                (ColorMatrixOverride::Auto, "auto"),
+                                (ColorMatrixOverride::Bt601, "BT.601"),
+                                (ColorMatrixOverride::Bt709, "BT.709"),
+                                (ColorMatrixOverride::Bt2020, "BT.2020"),
+                            ] {
+                                color_changed |=
+                                    ui.radio_value(&mut color_matrix, matrix, text).changed();
+                            }
+                        });
+                        ui.horizontal(|ui| {
+                            for (range, text) in [
+                                (ColorRangeOverride::Auto, "auto"),
+                                (ColorRangeOverride::Limited, "limited"),
+                                (ColorRangeOverride::Full, "full"),
+                            ] {
+                                color_changed |=
+                                    ui.radio_value(&mut color_range, range, text).changed();
+                            }
+                        });
+                        if color_changed {
+                            selected_color_override = Some((color_matrix, color_range));
+                        }
+                    }
+                });
+                ui.with_layout(Layout::bottom_up(egui::Align::Min), |ui| {
+                    let btn_response = ui.add(PlayerTextButton::new("⚙", 36.0, false));
+                    if btn_response.hovered() {
+                        self.ui_flags.control_ui_flag = true;
+                        self.last_show_control_ui_instant = *now;
+                    }
+                    if btn_response.clicked() {
+                        self.ui_flags.show_settings_flag = !self.ui_flags.show_settings_flag;
+                    }
+                    if self.ui_flags.show_settings_flag {
+                        ui.label(format!("decode: {}", snapshot.video_decode_path));
+                        let mut current_policy = snapshot.hardware_policy;
+                        let mut policies = vec![
+                            (HardwarePolicy::Auto, "auto".to_string()),
+                            (HardwarePolicy::Software, "software".to_string()),
+                        ];
+                        for device_type in TinyDecoder::supported_hardware_devices() {
+                            policies.push((
+                                HardwarePolicy::Device(device_type),
+                                TinyDecoder::hardware_device_name(device_type),
+                            ));
+                        }
+                        for (policy, text) in policies {
+                            if ui.radio_value(&mut current_policy, policy, text).changed() {
+                                selected_hardware_policy = Some(policy);
+                            }
+                        }
+                        ui.separator();
+                        if self.yuv_renderer_installed {
+                            let mut current_renderer = snapshot.video_renderer;
+                            for (renderer, text) in [
+                                (VideoRenderer::GpuYuv, "render: gpu yuv"),
+                                (VideoRenderer::Swscale, "render: swscale"),
+                            ] {
+                                if ui
+                                    .radio_value(&mut current_renderer, renderer, text)
+                                    .changed()
+                                {
+                                    selected_video_renderer = Some(renderer);
+                                }
+                            }
+                        } else {
+                            ui.label("render: swscale");
+                        }
+                        ui.separator();
+                        ui.label(format!("adapter: {}", self.graphics_adapter_text));
+                        let mut graphics_settings = self.graphics_settings;
+                        ui.label("graphics backend (next start)");
+                        for backend in GraphicsBackend::ALL {
+                            ui.radio_value(&mut graphics_settings.backend, backend, backend.name());
+                        }
+                        ui.checkbox(&mut graphics_settings.software_adapter, "software adapter");
+                        if graphics_settings != self.graphics_settings {
+                            self.graphics_settings = graphics_settings;
+                            self.graphics_settings.save();
+                        }
+                        ui.separator();
+                        if ui.button("apply decoder threads").clicked() {
+                            decoder_threading_applied = true;
+                        }
+                        ui.horizontal(|ui| {
+                            ui.label("audio threads(0 auto)");
+                            ui.add(
+                                egui::DragValue::new(&mut self.audio_threading.count).range(0..=64),
+                            );
+                        });
+                        ui.horizontal(|ui| {
+                            ui.label("video threads(0 auto)");
+                            ui.add(
+                                egui::DragValue::new(&mut self.video_threading.count).range(0..=64),
+                            );
+                            ui.checkbox(&mut self.video_threading.frame, "frame");
+                            ui.checkbox(&mut self.video_threading.slice, "slice");
+                        });
+                        ui.separator();
+                        let mut current_source = snapshot.clock_source;
+                        ui.horizontal(|ui| {
+                            ui.label("master clock");
+                            for (source, text) in [
+                                (ClockSource::Audio, "audio"),
+                                (ClockSource::Video, "video"),
+                                (ClockSource::External, "external"),
+                            ] {
+                                if ui.radio_value(&mut current_source, source, text).changed() {
+                                    selected_clock_source = Some(source);
+                                }
+                            }
+                        });
+                        let av_offset_text = if let Some(offset) = snapshot.av_offset_ms {
+                            format!("{offset:+.0}ms")
+                        } else {
+                            "-".to_string()
+                        };
+                        ui.label(format!(
+                            "a/v offset:{} audio latency:{:.0}ms",
+                            av_offset_text, snapshot.audio_latency_ms
+                        ));
+                        ui.separator();
+                        let buffer_health = &snapshot.buffer_health;
+                        ui.label(format!(
+                            "buffered packets audio:{:.1}s video:{:.1}s({:.1}MB)",
+                            buffer_health.audio_packets.seconds,
+                            buffer_health.video_packets.seconds,
+                            buffer_health.video_packets.bytes as f64 / 1024.0 / 1024.0
+                        ));
+                        ui.label(format!(
+                            "buffered frames audio:{:.1}s video:{:.1}s({} frames)",
+                            buffer_health.audio_frames.seconds,
+                            buffer_health.video_frames.seconds,
+                            buffer_health.video_frames.count
+                        ));
+                        ui.horizontal(|ui| {
+                            ui.label("read ahead ms audio");
+                            buffer_limits_changed |= ui
+                                .add(
+                                    egui::DragValue::new(
+                                        &mut self.buffer_limits.audio_packets.duration_ms,
+                                    )
+                                    .range(500..=60_000),
+                                )
+                                .changed();
+                            ui.label("video");
+                            buffer_limits_changed |= ui
+                                .add(
+                                    egui::DragValue::new(
+                                        &mut self.buffer_limits.video_packets.duration_ms,
+                                    )
+                                    .range(500..=60_000),
+                                )
+                                .changed();
+                        });
+                        ui.horizontal(|ui| {
+                            ui.label("read ahead MB audio");
+                            buffer_limits_changed |= Self::byte_budget_drag_value(
+                                ui,
+                                &mut self.buffer_limits.audio_packets.bytes,
+                                1..=256,
+                            );
+                            ui.label("video");
+                            buffer_limits_changed |= Self::byte_budget_drag_value(
+                                ui,
+                                &mut self.buffer_limits.video_packets.bytes,
+                                8..=2048,
+                            );
+                        });
+                        ui.horizontal(|ui| {
+                            ui.label("decoded frames MB audio");
+                            buffer_limits_changed |= Self::byte_budget_drag_value(
+                                ui,
+                                &mut self.buffer_limits.audio_frames.bytes,
+                                1..=256,
+                            );
+                            ui.label("video");
+                            buffer_limits_changed |= Self::byte_budget_drag_value(
+                                ui,
+                                &mut self.buffer_limits.video_frames.bytes,
+                                32..=4096,
+                            );
+                        });
                     }
                 });
                 ui.with_layout(Layout::bottom_up(egui::Align::Min), |ui| {
@@ -578,7 +929,6 @@ impl AppUi {
                     if self.ui_flags.show_volumn_slider_flag {
                         ui.with_layout(Layout::bottom_up(egui::Align::Min), |ui| {
                             ui.add_space(150.0);
-                            let audio_player = &mut self.audio_player;
                             let volumn_slider =
                                 egui::Slider::new(&mut self.audio_volumn, 0.0..=2.0)
                                     .vertical()
@@ -598,14 +948,14 @@ impl AppUi {
                             ui.set_style(slider_style);
                             let mut slider_response = ui.add(volumn_slider);
                             slider_response = slider_response
-                                .on_hover_text((audio_player.current_volumn() * 100.0).to_string());
+                                .on_hover_text((snapshot.volume * 100.0).to_string());
                             if slider_response.hovered() {
                                 self.ui_flags.control_ui_flag = true;
                                 self.last_show_control_ui_instant = *now;
                             }
                             if slider_response.changed() {
                                 warn!("volumn slider dragged!");
-                                audio_player.change_volumn(self.audio_volumn);
+                                self.player.set_volume(self.audio_volumn);
                             }
                         });
                     }
@@ -628,54 +978,205 @@ impl AppUi {
                 });
             }
         });
+        if let Some((timestamp, seek_mode)) = selected_seek {
+            self.player.seek(timestamp, seek_mode);
+        }
+        // the failures of these commands are shown by the error event of the player
+        if let Some(stream_index) = selected_audio_track {
+            self.player.set_audio_track(stream_index);
+        }
+        if let Some(speed) = selected_speed {
+            self.player.set_speed(speed);
+        }
+        if video_filter_applied {
+            self.video_filter_chain
+                .set_filters_from_str(&self.video_filter_text);
+            self.player
+                .set_video_filter_chain(self.video_filter_chain.clone());
+        }
+        if let Some(policy) = selected_hardware_policy {
+            self.player.set_hardware_policy(policy);
+        }
+        if let Some(renderer) = selected_video_renderer {
+            self.player.set_video_renderer(renderer);
+        }
+        if buffer_limits_changed {
+            self.player.set_buffer_limits(self.buffer_limits);
+        }
+        if let Some(source) = selected_clock_source {
+            self.player.set_clock_source(source);
+        }
+        if decoder_threading_applied {
+            self.player
+                .set_decoder_threading(self.video_threading, self.audio_threading);
+        }
+        if let Some((matrix, range)) = selected_color_override {
+            self.player.set_color_override(matrix, range);
+        }
+        if let Some(stream_index) = selected_subtitle_track {
+            self.player.set_subtitle_track(stream_index);
+        }
+        if let Some(used_model) = selected_used_model {
+            self.player.set_used_model(used_model);
+        }
     }
     fn paint_subtitle(&mut self, ui: &mut Ui, ctx: &Context) {
         ui.horizontal(|ui| {
-            if let Ok(tiny_decoder) = self.tiny_decoder.try_read() {
-                if self.async_rt.block_on(tiny_decoder.is_input_exist()) {
-                    ui.with_layout(Layout::bottom_up(egui::Align::Min), |ui| {
-                        if let Ok(generated_str) = self.subtitle_text_receiver.try_recv() {
-                            self.subtitle_text.push_str(&generated_str);
-                        }
-                        if self.subtitle_text.len() > 50 {
-                            self.subtitle_text.remove(0);
-                        }
-                        let subtitle_text_button = egui::Button::new(
-                            RichText::new(self.subtitle_text.clone())
-                                .size(50.0)
-                                .color(*THEME_COLOR)
+            if self.snapshot.state.has_input() {
+                let cue_text = self.update_subtitle_cues(ctx);
+                self.paint_subtitle_bitmaps(ui, ctx);
+                ui.with_layout(Layout::bottom_up(egui::Align::Min), |ui| {
+                    if let Some(generated_str) = self.player.try_recv_generated_subtitle() {
+                        self.subtitle_text.push_str(&generated_str);
+                    }
+                    if self.subtitle_text.len() > 50 {
+                        self.subtitle_text.remove(0);
+                    }
+                    let be_opacity = ui.opacity();
+                    ui.set_opacity(1.0);
+                    if !cue_text.is_empty() {
+                        let cue_text_button = egui::Button::new(
+                            RichText::new(cue_text)
+                                .size(40.0)
+                                .color(Color32::WHITE)
                                 .atom_size(Vec2::new(ctx.content_rect().width(), 10.0)),
                         )
                         .frame(false);
-                        let be_opacity = ui.opacity();
-                        ui.set_opacity(1.0);
-                        ui.add(subtitle_text_button);
-                        ui.set_opacity(be_opacity);
-                    });
-                }
+                        ui.add(cue_text_button);
+                    }
+                    let subtitle_text_button = egui::Button::new(
+                        RichText::new(self.subtitle_text.clone())
+                            .size(50.0)
+                            .color(*THEME_COLOR)
+                            .atom_size(Vec2::new(ctx.content_rect().width(), 10.0)),
+                    )
+                    .frame(false);
+                    ui.add(subtitle_text_button);
+                    ui.set_opacity(be_opacity);
+                });
             }
         });
     }
+    /// find the subtitle cues of the current play time
+    /// return the text to show and reload the bitmap textures if the shown pictures changed
+    fn update_subtitle_cues(&mut self, ctx: &Context) -> String {
+        let mut cue_text = String::new();
+        {
+            let play_ms = self.snapshot.position_ms + self.snapshot.start_time_ms;
+            let subtitle_cues = self.snapshot.subtitle_cues.clone();
+            if let Ok(subtitle_cues) = subtitle_cues.try_read() {
+                let mut bitmap_cues = vec![];
+                for cue in subtitle_cues.active_cues(play_ms) {
+                    match &cue.content {
+                        SubtitleContent::Text(text) => {
+                            if !cue_text.is_empty() {
+                                cue_text.push('\n');
+                            }
+                            cue_text.push_str(text);
+                        }
+                        SubtitleContent::Bitmap(bitmap) => {
+                            bitmap_cues.push((cue.start_ms, bitmap));
+                        }
+                    }
+                }
+                let changed = bitmap_cues.len() != self.subtitle_bitmap_textures.len()
+                    || bitmap_cues
+                        .iter()
+                        .zip(self.subtitle_bitmap_textures.iter())
+                        .any(|((start_ms, bitmap), texture)| {
+                            *start_ms != texture.start_ms
+                                || bitmap.x != texture.x
+                                || bitmap.y != texture.y
+                        });
+                if changed {
+                    self.subtitle_bitmap_textures = bitmap_cues
+                        .into_iter()
+                        .map(|(start_ms, bitmap)| {
+                            let color_image = ColorImage::from_rgba_unmultiplied(
+                                [bitmap.width as usize, bitmap.height as usize],
+                                &bitmap.rgba,
+                            );
+                            let canvas_w = bitmap.canvas[0].max(1) as f32;
+                            let canvas_h = bitmap.canvas[1].max(1) as f32;
+                            SubtitleBitmapTexture {
+                                start_ms,
+                                x: bitmap.x,
+                                y: bitmap.y,
+                                uv_rect: Rect::from_min_max(
+                                    Pos2::new(
+                                        bitmap.x as f32 / canvas_w,
+                                        bitmap.y as f32 / canvas_h,
+                                    ),
+                                    Pos2::new(
+                                        (bitmap.x + bitmap.width) as f32 / canvas_w,
+                                        (bitmap.y + bitmap.height) as f32 / canvas_h,
+                                    ),
+                                ),
+                                texture_handle: ctx.load_texture(
+                                    "subtitle_bitmap",
+                                    ImageData::Color(Arc::new(color_image)),
+                                    TextureOptions::LINEAR,
+                                ),
+                            }
+                        })
+                        .collect();
+                }
+            }
+            // the file cues don't wait for the lock of the embedded ones, it is held
+            // by the subtitle decode thread and by every seek
+            if let Some(external_subtitle_cues) = &self.external_subtitle_cues {
+                // subtitle files count the time from the media start
+                let relative_ms = self.snapshot.position_ms;
+                for cue in external_subtitle_cues.active_cues(relative_ms) {
+                    if let SubtitleContent::Text(text) = &cue.content {
+                        if !cue_text.is_empty() {
+                            cue_text.push('\n');
+                        }
+                        cue_text.push_str(text);
+                    }
+                }
+            }
+        }
+        cue_text
+    }
+    /// paint the picture subtitles over the video, the position is relative to the video frame
+    fn paint_subtitle_bitmaps(&self, ui: &mut Ui, ctx: &Context) {
+        let layer_painter = ctx.layer_painter(ui.layer_id());
+        let width = ctx.content_rect().width();
+        let height = ctx.content_rect().height();
+        for bitmap_texture in &self.subtitle_bitmap_textures {
+            let uv_rect = &bitmap_texture.uv_rect;
+            layer_painter.image(
+                bitmap_texture.texture_handle.id(),
+                Rect::from_min_max(
+                    Pos2::new(uv_rect.min.x * width, uv_rect.min.y * height),
+                    Pos2::new(uv_rect.max.x * width, uv_rect.max.y * height),
+                ),
+                Rect::from_min_max(Pos2::new(0.0, 0.0), Pos2::new(1.0, 1.0)),
+                Color32::WHITE,
+            );
+        }
+    }
 
-    fn paint_frame_info_text(&self, ui: &mut Ui, ctx: &Context, now: &Instant) {
+    /// clicking the fps text or pressing `I` toggles the playback stats overlay
+    fn paint_frame_info_text(&mut self, ui: &mut Ui, ctx: &Context) {
+        let mut orange_color = Color32::ORANGE.to_srgba_unmultiplied();
+        orange_color[3] = 100;
+        let text_color = Color32::from_rgba_unmultiplied(
+            orange_color[0],
+            orange_color[1],
+            orange_color[2],
+            orange_color[3],
+        );
         ui.horizontal(|ui| {
-            let app_sec = (*now - self.app_start_instant).as_secs();
-            let mut orange_color = Color32::ORANGE.to_srgba_unmultiplied();
-            orange_color[3] = 100;
-            if app_sec > 0 {
-                let mut text_str = "fps：".to_string();
-                text_str.push_str((ctx.cumulative_frame_nr() / app_sec).to_string().as_str());
-
-                let rich_text = egui::RichText::new(text_str)
-                    .color(Color32::from_rgba_unmultiplied(
-                        orange_color[0],
-                        orange_color[1],
-                        orange_color[2],
-                        orange_color[3],
-                    ))
-                    .size(30.0);
-                let fps_button = egui::Button::new(rich_text).frame(false);
-                ui.add(fps_button);
+            // stable_dt is the smoothed time between two redraws
+            let ui_fps = 1.0 / ctx.input(|input| input.stable_dt).max(0.001);
+            let rich_text = egui::RichText::new(format!("fps：{ui_fps:.0}"))
+                .color(text_color)
+                .size(30.0);
+            let fps_button = egui::Button::new(rich_text).frame(false);
+            if ui.add(fps_button).clicked() {
+                self.ui_flags.show_stats_flag = !self.ui_flags.show_stats_flag;
             }
             let mut date_time_str = "date-time：".to_string();
             if let Ok(formatter) =
@@ -688,52 +1189,96 @@ impl AppUi {
                 }
             }
             let rich_text = egui::RichText::new(date_time_str)
-                .color(Color32::from_rgba_unmultiplied(
-                    orange_color[0],
-                    orange_color[1],
-                    orange_color[2],
-                    orange_color[3],
-                ))
+                .color(text_color)
                 .size(30.0);
             let date_time_button = egui::Button::new(rich_text).frame(false);
 
             ui.add(date_time_button);
         });
-    }
-    fn check_play_is_at_endtail(&self, tiny_decoder: &TinyDecoder) -> bool {
-        if let Ok(pts) = self.main_stream_current_timestamp.try_read() {
-            let main_stream_time_base = {
-                if let MainStream::Audio = tiny_decoder.main_stream() {
-                    tiny_decoder.audio_time_base()
-                } else {
-                    tiny_decoder.video_time_base()
-                }
-            };
-            if *pts
-                + main_stream_time_base.denominator() as i64
-                    / main_stream_time_base.numerator() as i64
-                    / 2
-                >= tiny_decoder.end_ts()
-            // tiny_decoder.end_audio_ts() * audio_time_base.numerator() as i64
-            //     / audio_time_base.denominator() as i64
-            {
-                let end = tiny_decoder.end_ts();
-                warn!("play end! end_ts:{end},current_ts:{pts} ");
-                return true;
-            }
+        if ctx.input(|input| input.key_released(egui::Key::I)) {
+            self.ui_flags.show_stats_flag = !self.ui_flags.show_stats_flag;
+        }
+        if self.ui_flags.show_stats_flag {
+            let lines = self.playback_stats_lines();
+            egui::Area::new(egui::Id::new("playback_stats"))
+                .anchor(egui::Align2::LEFT_TOP, Vec2::new(10.0, 60.0))
+                .show(ctx, |ui| {
+                    egui::Frame::new()
+                        .fill(Color32::from_black_alpha(160))
+                        .inner_margin(egui::Margin::same(8))
+                        .show(ui, |ui| {
+                            for line in lines {
+                                ui.label(
+                                    RichText::new(line)
+                                        .color(Color32::WHITE)
+                                        .size(18.0)
+                                        .monospace(),
+                                );
+                            }
+                        });
+                });
         }
-        false
     }
-
-    fn _set_current_play_pts(&self, ts: i64) {
-        {
-            let mut cur_pts = self
-                .async_rt
-                .block_on(self.main_stream_current_timestamp.write());
-            {
-                *cur_pts = ts;
-            }
+    /// the lines of the stats overlay, read from the decoder caches and the clock
+    fn playback_stats_lines(&self) -> Vec<String> {
+        let snapshot = &self.snapshot;
+        if !snapshot.state.has_input() {
+            return vec![format!("{:?}", snapshot.state)];
         }
+        let decode_stats = &snapshot.decode_stats;
+        let buffer_health = &snapshot.buffer_health;
+        let frame_stats = &snapshot.frame_stats;
+        let [width, height] = snapshot.video_frame_rect;
+        let pixel_format = if let Some(pixel_format) = decode_stats.video_pixel_format {
+            format!("{pixel_format:?}")
+        } else {
+            "-".to_string()
+        };
+        let av_offset = if let Some(offset) = snapshot.av_offset_ms {
+            format!("{offset:+.0}ms")
+        } else {
+            "-".to_string()
+        };
+        vec![
+            format!(
+                "video: {} {}x{} {} {}",
+                snapshot.video_codec_name, width, height, pixel_format, snapshot.video_decode_path
+            ),
+            format!("audio: {}", snapshot.audio_codec_name),
+            format!(
+                "fps video:{:.2} display:{:.1}",
+                snapshot.video_frame_rate, frame_stats.display_fps
+            ),
+            format!("a/v offset:{} clock:{:?}", av_offset, snapshot.clock_source),
+            format!(
+                "dropped:{} duplicated:{}{}",
+                frame_stats.dropped_frames,
+                frame_stats.duplicated_frames,
+                if frame_stats.skipping_non_ref {
+                    " (skipping non-ref)"
+                } else {
+                    ""
+                }
+            ),
+            format!(
+                "packets audio:{}({:.1}s) video:{}({:.1}s)",
+                buffer_health.audio_packets.count,
+                buffer_health.audio_packets.seconds,
+                buffer_health.video_packets.count,
+                buffer_health.video_packets.seconds
+            ),
+            format!(
+                "frames audio:{}({:.1}s) video:{}({:.1}s)",
+                buffer_health.audio_frames.count,
+                buffer_health.audio_frames.seconds,
+                buffer_health.video_frames.count,
+                buffer_health.video_frames.seconds
+            ),
+            format!(
+                "decode:{:.1}ms/frame bitrate:{:.0}kbps",
+                decode_stats.video_decode_ms, decode_stats.video_bitrate_kbps
+            ),
+        ]
     }
     /// return true when the current video time > audio time,else return false
     fn paint_playlist_window(&mut self, ctx: &Context, now: &Instant) {
@@ -763,12 +1308,8 @@ impl AppUi {
                                         let player_text_button =
                                             PlayerTextButton::new(i.name.clone(), 20.0, true);
                                         if ui.add(player_text_button).clicked() {
-                                            if self
-                                                .change_format_input(&PathBuf::from(&i.path), now)
-                                                .is_ok()
-                                            {
-                                                info!("change_format_input success");
-                                            }
+                                            info!("open {:?} from the playlist", i.path);
+                                            self.player.open(&i.path);
                                         }
                                     });
                                 }
@@ -807,14 +1348,10 @@ impl AppUi {
             ],
             self.bg_dyn_img.as_bytes(),
         );
-        self.main_color_image = bg_color_img.clone();
+        self.set_main_color_image(bg_color_img);
     }
     fn reset_main_tex_to_cover_pic(&mut self) {
-        let decoder = self.tiny_decoder.clone();
-        let tiny_decoder = self.async_rt.block_on(decoder.read());
-        let pic_data = tiny_decoder.cover_pic_data();
-        let cover_data = self.async_rt.block_on(pic_data.read());
-        if let Some(data_vec) = &*cover_data {
+        if let Some(data_vec) = &self.shown_cover_pic {
             if let Ok(img) = image::load_from_memory(data_vec) {
                 let rgba8_img = img.to_rgba8();
                 let cover_color_img = ColorImage::from_rgba_unmultiplied(
@@ -822,90 +1359,160 @@ impl AppUi {
                     &rgba8_img,
                 );
                 info!("set cover img!");
-                self.main_color_image = cover_color_img.clone();
+                self.set_main_color_image(cover_color_img);
             }
         }
     }
-    fn change_format_input(&mut self, path: &Path, now: &Instant) -> PlayerResult<()> {
-        {
-            let decoder = self.tiny_decoder.clone();
-            let mut tiny_decoder = self.async_rt.block_on(decoder.write());
-            if self.ui_flags.pause_flag.0.send(true).is_err() {
-                warn!("change pause flag err");
-                return Err(PlayerError::Internal("change pause flag err".to_string()));
+    /// follow the changes made by the player, whoever asked for them
+    fn handle_player_events(&mut self, now: &Instant) {
+        let mut events = vec![];
+        while let Ok(event) = self.player_events.try_recv() {
+            events.push(event);
+        }
+        // taken after the events, so it already contains their changes
+        self.snapshot = self.player.snapshot();
+        for event in events {
+            match event {
+                PlayerEvent::StateChanged(state) => match state {
+                    PlayerState::Seeking => {
+                        self.frame_show_instant = *now;
+                    }
+                    PlayerState::Ended => {
+                        // show the controls so another file can be chosen
+                        self.ui_flags.control_ui_flag = true;
+                        self.last_show_control_ui_instant = *now;
+                    }
+                    _ => {}
+                },
+                PlayerEvent::Opened(path) => {
+                    info!("ui follows opened input {:?}", path);
+                    self.time_text.clear();
+                    self.external_subtitle_cues = None;
+                    self.shown_cover_pic = None;
+                    self.reset_main_tex_to_bg();
+                    self.update_color_image();
+                    self.frame_show_instant = *now;
+                }
+                PlayerEvent::Position(position_ms) => {
+                    self.update_time_text(position_ms);
+                }
+                PlayerEvent::TracksChanged {
+                    audio_tracks,
+                    subtitle_tracks,
+                } => {
+                    info!(
+                        "tracks audio:{} subtitle:{}",
+                        audio_tracks.len(),
+                        subtitle_tracks.len()
+                    );
+                    // the picker is only shown when there is a choice
+                    if audio_tracks.len() <= 1 {
+                        self.ui_flags.show_audio_track_options_flag = false;
+                    }
+                }
+                PlayerEvent::AudioTrackChanged(_) => {
+                    self.frame_show_instant = *now;
+                }
+                PlayerEvent::SubtitleTrackChanged(stream_index) => {
+                    info!("subtitle track changed {:?}", stream_index);
+                }
+                PlayerEvent::VolumeChanged(volume) => {
+                    self.audio_volumn = volume;
+                }
+                PlayerEvent::Error(e) => {
+                    warn!("player error {}", e);
+                    self.show_error_tip(&e);
+                }
             }
+        }
+        // the cover is found by the demux task a while after the input is opened
+        if let Some(cover_pic) = &self.snapshot.cover_pic {
             if self
-                .async_rt
-                .block_on(tiny_decoder.set_file_path_and_init_par(path))
-                .is_err()
+                .shown_cover_pic
+                .as_ref()
+                .is_none_or(|shown| !Arc::ptr_eq(shown, cover_pic))
             {
-                warn!("reset file path error!");
-                return Err(PlayerError::Internal("change pause flag err".to_string()));
+                self.shown_cover_pic = Some(cover_pic.clone());
+                self.reset_main_tex_to_cover_pic();
+                self.update_color_image();
             }
         }
-        let au_pl = &mut self.audio_player;
-        au_pl.source_queue_skip_to_end();
-
-        self.reset_main_tex_to_bg();
-        self.reset_main_tex_to_cover_pic();
-        self.update_color_image();
-        self.async_rt.block_on(async {
-            let mut mutex_guard = self.main_stream_current_timestamp.write().await;
-            {
-                *mutex_guard = 0;
-            }
-        });
-        let current_video_frame = self.current_video_frame.clone();
-        let mut current_video_frame = self.async_rt.block_on(current_video_frame.write());
-        let empty_frame = Video::empty();
-        *current_video_frame = empty_frame;
-        self.frame_show_instant = *now;
-
-        Ok(())
     }
 
     fn copy_video_data_to_texture(&mut self, frame: &mut Frame) {
-        let c_img = &mut self.main_color_image;
-        if let Ok(current_video_frame) = self.current_video_frame.try_read() {
-            if let Some(v_tex) = &mut self.video_texture_handle {
-                if current_video_frame.pts().is_some() {
+        let current_video_frame = self.player.current_video_frame();
+        if let Some(v_tex) = &mut self.video_texture_handle {
+            if current_video_frame.pts().is_some() {
+                // the redraws between two video frames show the uploaded one again
+                if self
+                    .uploaded_video_frame
+                    .as_ref()
+                    .is_some_and(|uploaded| Arc::ptr_eq(uploaded, &current_video_frame))
+                {
+                    return;
+                }
+                if current_video_frame.format() != Pixel::RGBA {
+                    // the decoder only skips the rgba conversion for the gpu renderer
                     if let Some(wgpu_render_state) = frame.wgpu_render_state() {
-                        let renderer = wgpu_render_state.renderer.read();
-                        if let Some(wgpu_texture) = renderer.texture(&v_tex.id()) {
-                            if let Some(texture) = &wgpu_texture.texture {
-                                let texel_copy_info = TexelCopyTextureInfo {
-                                    texture,
-                                    mip_level: 0,
-                                    origin: Origin3d::ZERO,
-                                    aspect: TextureAspect::All,
-                                };
-                                unsafe {
-                                    wgpu_render_state.queue.write_texture(
-                                        texel_copy_info,
-                                        current_video_frame.data(0),
-                                        TexelCopyBufferLayout {
-                                            offset: 0,
-                                            bytes_per_row: Some(
-                                                (*current_video_frame.as_ptr()).linesize[0] as u32,
-                                            ),
-                                            rows_per_image: None,
-                                        },
-                                        Extent3d {
-                                            width: current_video_frame.width(),
-                                            height: current_video_frame.height(),
-                                            depth_or_array_layers: 1,
-                                        },
-                                    );
-                                }
+                        let mut renderer = wgpu_render_state.renderer.write();
+                        if let Some(yuv_renderer) =
+                            renderer.callback_resources.get_mut::<YuvRenderer>()
+                        {
+                            if yuv_renderer.upload(
+                                &wgpu_render_state.device,
+                                &wgpu_render_state.queue,
+                                &current_video_frame,
+                            ) {
+                                self.yuv_frame_shown = true;
+                                self.main_color_image_uploaded = false;
+                                self.uploaded_video_frame = Some(current_video_frame);
                             }
                         }
                     }
-                } else {
-                    v_tex.set(
-                        ImageData::Color(Arc::new(c_img.clone())),
-                        TextureOptions::LINEAR,
-                    );
+                    return;
                 }
+                if let Some(wgpu_render_state) = frame.wgpu_render_state() {
+                    let renderer = wgpu_render_state.renderer.read();
+                    if let Some(wgpu_texture) = renderer.texture(&v_tex.id()) {
+                        if let Some(texture) = &wgpu_texture.texture {
+                            let texel_copy_info = TexelCopyTextureInfo {
+                                texture,
+                                mip_level: 0,
+                                origin: Origin3d::ZERO,
+                                aspect: TextureAspect::All,
+                            };
+                            unsafe {
+                                wgpu_render_state.queue.write_texture(
+                                    texel_copy_info,
+                                    current_video_frame.data(0),
+                                    TexelCopyBufferLayout {
+                                        offset: 0,
+                                        bytes_per_row: Some(
+                                            (*current_video_frame.as_ptr()).linesize[0] as u32,
+                                        ),
+                                        rows_per_image: None,
+                                    },
+                                    Extent3d {
+                                        width: current_video_frame.width(),
+                                        height: current_video_frame.height(),
+                                        depth_or_array_layers: 1,
+                                    },
+                                );
+                            }
+                            self.yuv_frame_shown = false;
+                            self.main_color_image_uploaded = false;
+                            self.uploaded_video_frame = Some(current_video_frame);
+                        }
+                    }
+                }
+            } else if !self.main_color_image_uploaded {
+                v_tex.set(
+                    ImageData::Color(Arc::new(self.main_color_image.clone())),
+                    TextureOptions::LINEAR,
+                );
+                self.main_color_image_uploaded = true;
+                self.yuv_frame_shown = false;
+                self.uploaded_video_frame = None;
             }
         }
     }
@@ -914,14 +1521,11 @@ impl AppUi {
             let dropped_files = &input.raw.dropped_files;
             if !dropped_files.is_empty() {
                 if let Some(path) = &dropped_files[0].path {
-                    if self.change_format_input(path.as_path(), now).is_ok() {
-                        if let Some(p_str) = path.to_str() {
-                            warn!("filepath{}", p_str);
-                        }
+                    if crate::subtitle::is_subtitle_file(path) {
+                        self.load_external_subtitle(path);
                     } else {
-                        self.tip_window_msg =
-                            "please choose a valid video or audio file !!!".to_string();
-                        self.ui_flags.tip_window_flag = true;
+                        warn!("filepath{:?}", path);
+                        self.player.open(path);
                     }
                 }
             }
@@ -1025,14 +1629,75 @@ impl AppUi {
         )
     }
 
-    fn notify_data_thread(&self, tiny_decoder: &TinyDecoder) {
-        if let MainStream::Video = tiny_decoder.main_stream() {
-            self.data_thread_notify.notify_one();
-        } else {
-            if self.audio_player.len() < 10 {
-                self.data_thread_notify.notify_one();
+    fn paint_open_subtitle_dialog(&mut self, ctx: &Context) {
+        let mut selected_path = None;
+        if let Some(dialog) = &mut self.open_subtitle_dialog {
+            dialog.show(ctx);
+            if dialog.selected() {
+                if let Some(p) = dialog.path() {
+                    selected_path = Some(p.to_path_buf());
+                }
             }
         }
+        if let Some(path) = selected_path {
+            self.load_external_subtitle(&path);
+        }
+    }
+    /// parse a subtitle file and show it with the playing media
+    fn load_external_subtitle(&mut self, path: &Path) {
+        match crate::subtitle::load_subtitle_file(path) {
+            Ok(cues) => {
+                info!("subtitle file loaded {:?}", path);
+                self.external_subtitle_cues = Some(cues);
+            }
+            Err(e) => {
+                warn!("load subtitle file err {}", e);
+                self.show_error_tip(&e);
+            }
+        }
+    }
+    /// tell the user what went wrong and what can be done about it
+    fn show_error_tip(&mut self, e: &PlayerError) {
+        self.tip_window_msg = match e {
+            PlayerError::Io { path, .. } => {
+                format!("can not read {}, check the file exists", path.display())
+            }
+            PlayerError::UnsupportedFormat { path, .. } => format!(
+                "{} is not a media file ffmpeg can open, choose a video or audio file",
+                path.display()
+            ),
+            PlayerError::NoPlayableStream { path } => format!(
+                "{} has no audio or video to play, choose another file",
+                path.display()
+            ),
+            PlayerError::Codec { codec, .. } => {
+                format!("the {} codec is not supported by this ffmpeg build", codec)
+            }
+            PlayerError::Hardware(_) => {
+                "hardware decoding is not available, choose software decoding in ⚙".to_string()
+            }
+            PlayerError::Filter(reason) => {
+                format!(
+                    "the filters can not be applied, check the filter text: {}",
+                    reason
+                )
+            }
+            PlayerError::AudioOutput(_) => {
+                "no audio output device can be opened, check the sound settings".to_string()
+            }
+            PlayerError::SubtitleModel(_) => {
+                "the subtitle model in the model folder is missing or broken".to_string()
+            }
+            PlayerError::SubtitleFile { reason, .. } => {
+                format!("the subtitle file can not be used: {}", reason)
+            }
+            PlayerError::TrackNotFound(_) => {
+                "the track is not in the opened file any more, open it again".to_string()
+            }
+            PlayerError::NoInput => "open a video or audio file first".to_string(),
+            PlayerError::Internal(_) => format!("{} !!!", e),
+        };
+        self.ui_flags.tip_window_flag = true;
     }
     fn paint_tip_window(&mut self, ctx: &Context) {
         if self.ui_flags.tip_window_flag {
@@ -1049,6 +1714,16 @@ impl AppUi {
     }
 }
 
+/// texture of a picture subtitle being shown
+/// `uv_rect` is the position in the video frame, normalized to 0..1
+struct SubtitleBitmapTexture {
+    start_ms: i64,
+    x: u32,
+    y: u32,
+    uv_rect: Rect,
+    texture_handle: TextureHandle,
+}
+
 struct VideoDes {
     pub name: String,
     pub path: PathBuf,
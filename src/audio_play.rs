@@ -11,8 +11,8 @@ pub struct AudioPlayer {
 }
 impl AudioPlayer {
     pub fn new() -> PlayerResult<Self> {
-        let stream = rodio::OutputStreamBuilder::open_default_stream()
-            .map_err(|e| PlayerError::Internal(e.to_string()))?;
+        let stream =
+            rodio::OutputStreamBuilder::open_default_stream().map_err(PlayerError::audio_output)?;
         let sink = Arc::new(rodio::Sink::connect_new(stream.mixer()));
 
         Ok(Self {
@@ -57,8 +57,4 @@ impl AudioPlayer {
     pub fn sink(&self) -> Arc<Sink> {
         self.sink.clone()
     }
-
-    pub fn len(&self) -> usize {
-        self.sink.len()
-    }
 }
@@ -0,0 +1,606 @@
+use std::{
+    collections::VecDeque,
+    ffi::c_void,
+    ptr::null_mut,
+    sync::{
+        Mutex, MutexGuard, PoisonError,
+        atomic::{AtomicUsize, Ordering},
+    },
+};
+
+use ffmpeg_the_third::{
+    Packet, Rational,
+    ffi::{
+        AVBufferPool, AVBufferRef, AVPixelFormat, av_buffer_alloc, av_buffer_pool_get,
+        av_buffer_pool_init2, av_buffer_pool_uninit, av_image_fill_arrays,
+        av_image_get_buffer_size,
+    },
+    frame::{Audio, Video},
+};
+use tracing::{info, warn};
+
+/// a demuxed packet or a decoded frame kept in a `MediaQueue`
+pub trait QueueItem {
+    fn item_pts(&self) -> Option<i64>;
+    /// duration in the stream time base, 0 if unknown
+    fn item_duration(&self) -> i64;
+    /// the memory used by the data of the item
+    fn byte_size(&self) -> usize;
+}
+impl QueueItem for Packet {
+    fn item_pts(&self) -> Option<i64> {
+        self.pts()
+    }
+    fn item_duration(&self) -> i64 {
+        self.duration()
+    }
+    fn byte_size(&self) -> usize {
+        self.size()
+    }
+}
+impl QueueItem for Video {
+    fn item_pts(&self) -> Option<i64> {
+        self.pts()
+    }
+    fn item_duration(&self) -> i64 {
+        unsafe { (*self.as_ptr()).duration }
+    }
+    fn byte_size(&self) -> usize {
+        let size = unsafe {
+            av_image_get_buffer_size(
+                AVPixelFormat::from(self.format()),
+                self.width() as i32,
+                self.height() as i32,
+                1,
+            )
+        };
+        size.max(0) as usize
+    }
+}
+impl QueueItem for Audio {
+    fn item_pts(&self) -> Option<i64> {
+        self.pts()
+    }
+    fn item_duration(&self) -> i64 {
+        unsafe { (*self.as_ptr()).duration }
+    }
+    fn byte_size(&self) -> usize {
+        self.samples() * self.ch_layout().channels() as usize * self.format().bytes()
+    }
+}
+
+/// the budget of one queue, the queue is full once it reaches any of them
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct QueueLimit {
+    pub duration_ms: i64,
+    pub bytes: usize,
+}
+/// the budgets of the packet and frame queues of the played streams
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BufferLimits {
+    pub audio_packets: QueueLimit,
+    pub video_packets: QueueLimit,
+    pub audio_frames: QueueLimit,
+    pub video_frames: QueueLimit,
+}
+impl Default for BufferLimits {
+    fn default() -> Self {
+        Self {
+            audio_packets: QueueLimit {
+                duration_ms: 5000,
+                bytes: 8 * 1024 * 1024,
+            },
+            video_packets: QueueLimit {
+                duration_ms: 5000,
+                bytes: 64 * 1024 * 1024,
+            },
+            audio_frames: QueueLimit {
+                duration_ms: 500,
+                bytes: 16 * 1024 * 1024,
+            },
+            // a 4k 10bit frame is about 24MB, the bytes budget is reached first for it
+            video_frames: QueueLimit {
+                duration_ms: 500,
+                bytes: 256 * 1024 * 1024,
+            },
+        }
+    }
+}
+
+/// how much one queue holds now
+#[derive(Debug, Clone, Copy, Default)]
+pub struct QueueHealth {
+    pub seconds: f64,
+    pub bytes: usize,
+    pub count: usize,
+}
+/// how much every queue holds now, for diagnostics and the ui
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BufferHealth {
+    pub audio_packets: QueueHealth,
+    pub video_packets: QueueHealth,
+    pub audio_frames: QueueHealth,
+    pub video_frames: QueueHealth,
+}
+
+/// fifo of packets or frames of one stream which tracks the bytes and duration it holds
+pub struct MediaQueue<T: QueueItem> {
+    items: VecDeque<T>,
+    bytes: usize,
+    duration_sum: i64,
+    time_base: Rational,
+}
+impl<T: QueueItem> Default for MediaQueue<T> {
+    fn default() -> Self {
+        Self {
+            items: VecDeque::new(),
+            bytes: 0,
+            duration_sum: 0,
+            time_base: Rational::new(1, 1),
+        }
+    }
+}
+impl<T: QueueItem> MediaQueue<T> {
+    /// the time base of the stream, used to convert the buffered duration to ms
+    pub fn set_time_base(&mut self, time_base: Rational) {
+        self.time_base = time_base;
+    }
+    pub fn push_back(&mut self, item: T) {
+        self.bytes += item.byte_size();
+        self.duration_sum += item.item_duration().max(0);
+        self.items.push_back(item);
+    }
+    pub fn pop_front(&mut self) -> Option<T> {
+        let item = self.items.pop_front()?;
+        self.bytes = self.bytes.saturating_sub(item.byte_size());
+        self.duration_sum = (self.duration_sum - item.item_duration().max(0)).max(0);
+        Some(item)
+    }
+    pub fn clear(&mut self) {
+        self.items.clear();
+        self.bytes = 0;
+        self.duration_sum = 0;
+    }
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+    pub fn get(&self, index: usize) -> Option<&T> {
+        self.items.get(index)
+    }
+    /// the time span from the first item to the end of the last one
+    /// the sum of the item durations is used when the pts are missing
+    pub fn buffered_ms(&self) -> i64 {
+        let mut duration = self.duration_sum;
+        if let (Some(front), Some(back)) = (self.items.front(), self.items.back()) {
+            if let (Some(front_pts), Some(back_pts)) = (front.item_pts(), back.item_pts()) {
+                duration = duration.max(back_pts + back.item_duration().max(0) - front_pts);
+            }
+        }
+        duration * 1000 * self.time_base.numerator().max(1) as i64
+            / self.time_base.denominator().max(1) as i64
+    }
+    pub fn is_full(&self, limit: &QueueLimit) -> bool {
+        self.bytes >= limit.bytes || self.buffered_ms() >= limit.duration_ms
+    }
+    pub fn health(&self) -> QueueHealth {
+        QueueHealth {
+            seconds: self.buffered_ms() as f64 / 1000.0,
+            bytes: self.bytes,
+            count: self.items.len(),
+        }
+    }
+}
+
+/// a bounded fifo between two threads of the pipeline, full once it reaches its `QueueLimit`
+/// every item is sent with the serial of the timeline it belongs to,
+/// the items of an older timeline than the one of the channel are dropped
+pub struct MediaChannel<T: QueueItem> {
+    state: Mutex<MediaChannelState<T>>,
+}
+struct MediaChannelState<T: QueueItem> {
+    queue: MediaQueue<T>,
+    limit: QueueLimit,
+    serial: usize,
+    /// the sender has nothing more to send in this timeline
+    finished: bool,
+}
+impl<T: QueueItem> MediaChannel<T> {
+    pub fn new(limit: QueueLimit) -> Self {
+        Self {
+            state: Mutex::new(MediaChannelState {
+                queue: MediaQueue::default(),
+                limit,
+                serial: 0,
+                finished: false,
+            }),
+        }
+    }
+    /// the queue is left consistent by every method, a panicked thread does not break it
+    fn state(&self) -> MutexGuard<'_, MediaChannelState<T>> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+    pub fn set_limit(&self, limit: QueueLimit) {
+        self.state().limit = limit;
+    }
+    pub fn limit(&self) -> QueueLimit {
+        self.state().limit
+    }
+    pub fn set_time_base(&self, time_base: Rational) {
+        self.state().queue.set_time_base(time_base);
+    }
+    /// give the item back when the channel is full, the sender tries again later
+    pub fn try_send(&self, item: T, serial: usize) -> Result<(), T> {
+        let mut state = self.state();
+        if serial != state.serial {
+            // made before the last flush, nobody wants it any more
+            return Ok(());
+        }
+        if state.queue.is_full(&state.limit) {
+            return Err(item);
+        }
+        state.queue.push_back(item);
+        Ok(())
+    }
+    /// take the front item with the serial of its timeline
+    pub fn try_recv(&self) -> Option<(T, usize)> {
+        let mut state = self.state();
+        let serial = state.serial;
+        state.queue.pop_front().map(|item| (item, serial))
+    }
+    /// look at a queued item without taking it, index 0 is the front one
+    pub fn peek<R>(&self, index: usize, f: impl FnOnce(&T) -> R) -> Option<R> {
+        self.state().queue.get(index).map(f)
+    }
+    /// the sender reached the end of the stream in the timeline of `serial`
+    pub fn finish(&self, serial: usize) {
+        let mut state = self.state();
+        if serial == state.serial {
+            state.finished = true;
+        }
+    }
+    pub fn is_finished(&self) -> bool {
+        self.state().finished
+    }
+    /// finished and every item is taken
+    pub fn is_drained(&self) -> bool {
+        let state = self.state();
+        state.finished && state.queue.is_empty()
+    }
+    /// drop every item and start the timeline of `serial`
+    pub fn flush(&self, serial: usize) {
+        let mut state = self.state();
+        state.queue.clear();
+        state.serial = serial;
+        state.finished = false;
+    }
+    /// drop every item and stay in the same timeline
+    pub fn clear(&self) {
+        self.state().queue.clear();
+    }
+    pub fn serial(&self) -> usize {
+        self.state().serial
+    }
+    pub fn is_full(&self) -> bool {
+        let state = self.state();
+        state.queue.is_full(&state.limit)
+    }
+    pub fn health(&self) -> QueueHealth {
+        self.state().queue.health()
+    }
+}
+
+/// how many buffers one `FramePool` may keep, shared with the alloc callback of the ffmpeg pool
+/// it lives until the ffmpeg pool is freed, which is after the last pooled buffer came back
+struct PoolBudget {
+    allocated: AtomicUsize,
+    max_buffers: AtomicUsize,
+}
+/// the alloc callback of the ffmpeg pool, no buffer is made once the pool holds its most
+unsafe extern "C" fn alloc_pool_buffer(opaque: *mut c_void, size: usize) -> *mut AVBufferRef {
+    let budget = unsafe { &*(opaque as *const PoolBudget) };
+    if budget.allocated.load(Ordering::Relaxed) >= budget.max_buffers.load(Ordering::Relaxed) {
+        return null_mut();
+    }
+    let buf = unsafe { av_buffer_alloc(size) };
+    if !buf.is_null() {
+        budget.allocated.fetch_add(1, Ordering::Relaxed);
+    }
+    buf
+}
+/// the free callback of the ffmpeg pool
+unsafe extern "C" fn free_pool_budget(opaque: *mut c_void) {
+    drop(unsafe { Box::from_raw(opaque as *mut PoolBudget) });
+}
+
+/// reusable buffers for the video frames of one format and size
+/// a frame gives its buffer back to the pool when it is dropped, the pool keeps at most the
+/// buffers the byte budget of the frames holds and a few spare ones for the frames in the
+/// decoder, the filters and the ui, the frames past it get a buffer freed with them
+/// the pool is made again when the format or the size changes
+pub struct FramePool {
+    pool: *mut AVBufferPool,
+    /// owned by the ffmpeg pool, valid while `pool` is not null
+    budget: *const PoolBudget,
+    format: AVPixelFormat,
+    width: u32,
+    height: u32,
+    buffer_size: usize,
+    max_bytes: usize,
+}
+impl Default for FramePool {
+    fn default() -> Self {
+        Self {
+            pool: null_mut(),
+            budget: null_mut(),
+            format: AVPixelFormat::AV_PIX_FMT_NONE,
+            width: 0,
+            height: 0,
+            buffer_size: 0,
+            max_bytes: 0,
+        }
+    }
+}
+impl FramePool {
+    /// the rows are aligned to 256 bytes to meet the wgpu requirement
+    const ALIGN: i32 = 256;
+    /// the buffers kept beyond the byte budget, for the frames out of the queue
+    const SPARE_BUFFERS: usize = 4;
+    /// the bytes of the frames queued at once, usually the video frame budget of `BufferLimits`
+    pub fn set_max_bytes(&mut self, max_bytes: usize) {
+        if self.max_bytes != max_bytes {
+            self.max_bytes = max_bytes;
+            self.update_max_buffers();
+        }
+    }
+    fn update_max_buffers(&self) {
+        if !self.pool.is_null() {
+            let max_buffers = self.max_bytes / self.buffer_size.max(1) + Self::SPARE_BUFFERS;
+            unsafe {
+                (*self.budget)
+                    .max_buffers
+                    .store(max_buffers, Ordering::Relaxed);
+            }
+        }
+    }
+    /// the buffers the pool made for the current format and size
+    #[cfg(test)]
+    fn pooled_buffers(&self) -> usize {
+        if self.pool.is_null() {
+            0
+        } else {
+            unsafe { (*self.budget).allocated.load(Ordering::Relaxed) }
+        }
+    }
+    /// an empty frame of the format and size backed by a pooled buffer
+    pub fn get(&mut self, format: AVPixelFormat, width: u32, height: u32) -> Option<Video> {
+        unsafe {
+            if self.pool.is_null()
+                || self.format != format
+                || self.width != width
+                || self.height != height
+            {
+                // the buffers still used by frames are freed when they come back
+                av_buffer_pool_uninit(&mut self.pool);
+                let size =
+                    av_image_get_buffer_size(format, width as i32, height as i32, Self::ALIGN);
+                if size <= 0 {
+                    warn!("frame pool buffer size err {}", size);
+                    return None;
+                }
+                info!("create frame pool {:?} {}x{}", format, width, height);
+                let budget = Box::into_raw(Box::new(PoolBudget {
+                    allocated: AtomicUsize::new(0),
+                    max_buffers: AtomicUsize::new(0),
+                }));
+                self.pool = av_buffer_pool_init2(
+                    size as usize,
+                    budget as *mut c_void,
+                    Some(alloc_pool_buffer),
+                    Some(free_pool_budget),
+                );
+                if self.pool.is_null() {
+                    drop(Box::from_raw(budget));
+                    warn!("frame pool init err");
+                    return None;
+                }
+                self.budget = budget;
+                self.format = format;
+                self.width = width;
+                self.height = height;
+                self.buffer_size = size as usize;
+                self.update_max_buffers();
+            }
+            let mut buf = av_buffer_pool_get(self.pool);
+            if buf.is_null() {
+                // the pool holds its most buffers, this one is freed with its frame
+                buf = av_buffer_alloc(self.buffer_size);
+            }
+            if buf.is_null() {
+                warn!("frame pool get buffer err");
+                return None;
+            }
+            let mut res = Video::empty();
+            let frame = res.as_mut_ptr();
+            (*frame).format = format as i32;
+            (*frame).width = width as i32;
+            (*frame).height = height as i32;
+            // owned by the frame from here, unref gives it back to the pool
+            (*frame).buf[0] = buf;
+            if 0 > av_image_fill_arrays(
+                (*frame).data.as_mut_ptr(),
+                (*frame).linesize.as_mut_ptr(),
+                (*buf).data,
+                format,
+                width as i32,
+                height as i32,
+                Self::ALIGN,
+            ) {
+                warn!("frame pool fill arrays err");
+                return None;
+            }
+            (*frame).extended_data = (*frame).data.as_mut_ptr();
+            Some(res)
+        }
+    }
+}
+impl Drop for FramePool {
+    fn drop(&mut self) {
+        unsafe {
+            av_buffer_pool_uninit(&mut self.pool);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct TestItem {
+        pts: Option<i64>,
+        duration: i64,
+    }
+    impl QueueItem for TestItem {
+        fn item_pts(&self) -> Option<i64> {
+            self.pts
+        }
+        fn item_duration(&self) -> i64 {
+            self.duration
+        }
+        fn byte_size(&self) -> usize {
+            100
+        }
+    }
+    fn item(pts: Option<i64>, duration: i64) -> TestItem {
+        TestItem { pts, duration }
+    }
+    fn queue_of(time_base: Rational, items: Vec<TestItem>) -> MediaQueue<TestItem> {
+        let mut queue = MediaQueue::default();
+        queue.set_time_base(time_base);
+        for item in items {
+            queue.push_back(item);
+        }
+        queue
+    }
+
+    #[test]
+    fn buffered_ms_is_the_longer_of_the_durations_and_the_pts_span() {
+        let ms = Rational::new(1, 1000);
+        // a gap between the pts
+        let queue = queue_of(ms, vec![item(Some(0), 10), item(Some(100), 10)]);
+        assert_eq!(queue.buffered_ms(), 110);
+        // no pts, only the durations are known
+        let queue = queue_of(ms, vec![item(None, 40), item(None, 40), item(None, 40)]);
+        assert_eq!(queue.buffered_ms(), 120);
+        // reordered pts make the span shorter than the durations
+        let queue = queue_of(ms, vec![item(Some(0), 50), item(Some(10), 50)]);
+        assert_eq!(queue.buffered_ms(), 100);
+        let queue = queue_of(
+            Rational::new(1, 90000),
+            vec![item(Some(0), 9000), item(Some(9000), 9000)],
+        );
+        assert_eq!(queue.buffered_ms(), 200);
+    }
+
+    #[test]
+    fn queue_tracks_what_it_holds() {
+        let mut queue = queue_of(
+            Rational::new(1, 1000),
+            vec![item(Some(0), 20), item(Some(20), 20)],
+        );
+        let health = queue.health();
+        assert_eq!((health.bytes, health.count), (200, 2));
+        assert!(queue.pop_front().is_some_and(|item| item.pts == Some(0)));
+        let health = queue.health();
+        assert_eq!((health.bytes, health.count), (100, 1));
+        assert_eq!(queue.buffered_ms(), 20);
+        queue.clear();
+        assert!(queue.is_empty());
+        assert_eq!(queue.buffered_ms(), 0);
+    }
+
+    #[test]
+    fn full_channel_gives_the_item_back() {
+        let channel = MediaChannel::new(QueueLimit {
+            duration_ms: i64::MAX,
+            bytes: 250,
+        });
+        channel.set_time_base(Rational::new(1, 1000));
+        for pts in 0..3 {
+            assert!(channel.try_send(item(Some(pts), 1), 0).is_ok());
+        }
+        assert!(channel.is_full());
+        assert!(matches!(
+            channel.try_send(item(Some(3), 1), 0),
+            Err(TestItem { pts: Some(3), .. })
+        ));
+        assert!(channel.try_recv().is_some());
+        assert!(!channel.is_full());
+
+        let channel = MediaChannel::new(QueueLimit {
+            duration_ms: 50,
+            bytes: usize::MAX,
+        });
+        channel.set_time_base(Rational::new(1, 1000));
+        assert!(channel.try_send(item(Some(0), 30), 0).is_ok());
+        assert!(channel.try_send(item(Some(30), 30), 0).is_ok());
+        assert!(channel.try_send(item(Some(60), 30), 0).is_err());
+        channel.set_limit(QueueLimit {
+            duration_ms: 100,
+            bytes: usize::MAX,
+        });
+        assert!(channel.try_send(item(Some(60), 30), 0).is_ok());
+    }
+
+    #[test]
+    fn stale_serial_items_are_dropped() {
+        let channel = MediaChannel::new(QueueLimit {
+            duration_ms: i64::MAX,
+            bytes: usize::MAX,
+        });
+        assert!(channel.try_send(item(Some(0), 1), 0).is_ok());
+        channel.flush(2);
+        assert_eq!(channel.serial(), 2);
+        assert!(channel.try_recv().is_none());
+        // sent before the sender saw the flush, nobody wants it
+        assert!(channel.try_send(item(Some(1), 1), 1).is_ok());
+        assert_eq!(channel.health().count, 0);
+        assert!(channel.try_send(item(Some(2), 1), 2).is_ok());
+        assert!(matches!(
+            channel.try_recv(),
+            Some((TestItem { pts: Some(2), .. }, 2))
+        ));
+        // the end of an older timeline does not end this one
+        channel.finish(1);
+        assert!(!channel.is_finished());
+        channel.finish(2);
+        assert!(channel.is_drained());
+    }
+
+    #[test]
+    fn frame_pool_keeps_the_budget() {
+        let format = AVPixelFormat::AV_PIX_FMT_YUV420P;
+        let size = unsafe { av_image_get_buffer_size(format, 64, 64, FramePool::ALIGN) };
+        let mut pool = FramePool::default();
+        pool.set_max_bytes(size.max(0) as usize * 2);
+        let frames = (0..10)
+            .filter_map(|_| pool.get(format, 64, 64))
+            .collect::<Vec<_>>();
+        // the frames past the budget still get a buffer, the pool does not keep it
+        assert_eq!(frames.len(), 10);
+        assert!(
+            frames
+                .iter()
+                .all(|frame| frame.width() == 64 && !frame.data(0).is_empty())
+        );
+        assert_eq!(pool.pooled_buffers(), 2 + FramePool::SPARE_BUFFERS);
+        drop(frames);
+        let frames = (0..3)
+            .filter_map(|_| pool.get(format, 64, 64))
+            .collect::<Vec<_>>();
+        assert_eq!(frames.len(), 3);
+        assert_eq!(pool.pooled_buffers(), 2 + FramePool::SPARE_BUFFERS);
+        // a new size starts a new pool
+        assert!(pool.get(format, 32, 32).is_some());
+        assert_eq!(pool.pooled_buffers(), 1);
+    }
+}
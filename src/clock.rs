@@ -0,0 +1,496 @@
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+/// a frame further than this from the master clock is not synced,
+/// the clocks start again from it (seek, broken timestamps...)
+const NO_SYNC_THRESHOLD_MS: f64 = 10_000.0;
+/// the sync window is one frame, limited to this range
+const SYNC_WINDOW_MIN_MS: f64 = 40.0;
+const SYNC_WINDOW_MAX_MS: f64 = 100.0;
+/// part of the drift to the audio clock corrected every update of the external clock
+const EXTERNAL_CLOCK_SLEW: f64 = 0.01;
+
+/// the clock which the other streams follow
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ClockSource {
+    /// the audio being heard, video frames are dropped or repeated to follow it
+    Audio,
+    /// the video frames are shown at their own pace, audio is dropped or delayed to follow it
+    Video,
+    /// the system clock, both audio and video follow it
+    External,
+}
+
+/// what to do with the next video frame
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum VideoSync {
+    /// its time is not reached, keep showing the current frame
+    Wait,
+    Show,
+    /// its time is passed by more than the sync window
+    Late,
+}
+/// what to do with the next audio frame
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AudioSync {
+    Play,
+    /// it would be heard too early, keep it for later
+    Wait,
+    /// it would be heard too late
+    Drop,
+}
+
+/// counts of the video frames not shown in time, for diagnostics
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FrameStats {
+    /// late frames skipped before they are converted
+    pub dropped_frames: u64,
+    /// frame durations the shown frame was kept longer because the next one was not ready
+    pub duplicated_frames: u64,
+    /// the video decoder skips the non-reference frames to catch up
+    pub skipping_non_ref: bool,
+    /// video frames shown per second, measured over the last second
+    pub display_fps: f64,
+}
+
+/// a clock which runs at the playback speed from the pts set at an instant
+#[derive(Debug, Clone, Copy)]
+struct RunningClock {
+    pts_ms: Option<f64>,
+    set_instant: Instant,
+    speed: f64,
+    paused: bool,
+}
+impl RunningClock {
+    fn new() -> Self {
+        Self {
+            pts_ms: None,
+            set_instant: Instant::now(),
+            speed: 1.0,
+            paused: false,
+        }
+    }
+    fn get(&self, now: Instant) -> Option<f64> {
+        let pts_ms = self.pts_ms?;
+        if self.paused {
+            return Some(pts_ms);
+        }
+        let elapsed_ms = now
+            .saturating_duration_since(self.set_instant)
+            .as_secs_f64()
+            * 1000.0;
+        Some(pts_ms + elapsed_ms * self.speed)
+    }
+    fn set(&mut self, pts_ms: f64, now: Instant) {
+        self.pts_ms = Some(pts_ms);
+        self.set_instant = now;
+    }
+    fn set_paused(&mut self, paused: bool, now: Instant) {
+        if let Some(pts_ms) = self.get(now) {
+            self.set(pts_ms, now);
+        }
+        self.paused = paused;
+    }
+    fn set_speed(&mut self, speed: f64, now: Instant) {
+        if let Some(pts_ms) = self.get(now) {
+            self.set(pts_ms, now);
+        }
+        self.speed = speed;
+    }
+    fn clear(&mut self) {
+        self.pts_ms = None;
+    }
+}
+
+/// one audio frame appended to the sink
+#[derive(Debug, Clone, Copy)]
+struct QueuedAudio {
+    pts_ms: f64,
+    /// how long it plays, not scaled by the speed
+    duration_ms: f64,
+}
+
+/// the audio, video and external clocks of the playback
+/// and the measured distance between what is heard and what is shown
+pub struct PlaybackClock {
+    source: ClockSource,
+    audio: RunningClock,
+    video: RunningClock,
+    external: RunningClock,
+    /// the frames in the sink, the front one is being heard
+    queued_audio: VecDeque<QueuedAudio>,
+    audio_latency_ms: f64,
+    av_offset_ms: Option<f64>,
+    frame_stats: FrameStats,
+    shown_frames_in_window: u32,
+    fps_window_start: Instant,
+    has_audio: bool,
+    speed: f64,
+    paused: bool,
+}
+impl Default for PlaybackClock {
+    fn default() -> Self {
+        Self {
+            source: ClockSource::Audio,
+            audio: RunningClock::new(),
+            video: RunningClock::new(),
+            external: RunningClock::new(),
+            queued_audio: VecDeque::new(),
+            audio_latency_ms: 0.0,
+            av_offset_ms: None,
+            frame_stats: FrameStats::default(),
+            shown_frames_in_window: 0,
+            fps_window_start: Instant::now(),
+            has_audio: false,
+            speed: 1.0,
+            paused: false,
+        }
+    }
+}
+impl PlaybackClock {
+    /// the clock chosen by the user
+    pub fn source(&self) -> ClockSource {
+        self.source
+    }
+    pub fn set_source(&mut self, source: ClockSource, now: Instant) {
+        if let Some(master_ms) = self.master_ms(now) {
+            // the new master goes on from the same position
+            self.external.set(master_ms, now);
+            self.video.set(master_ms, now);
+        }
+        self.source = source;
+    }
+    /// the input has an audio stream to play
+    pub fn set_has_audio(&mut self, has_audio: bool) {
+        self.has_audio = has_audio;
+    }
+    /// the clock really used, audio can not lead without audio stream
+    fn effective_source(&self) -> ClockSource {
+        if let ClockSource::Audio = self.source {
+            if !self.has_audio {
+                return ClockSource::Video;
+            }
+        }
+        self.source
+    }
+    pub fn set_paused(&mut self, paused: bool, now: Instant) {
+        if paused != self.paused {
+            self.audio.set_paused(paused, now);
+            self.video.set_paused(paused, now);
+            self.external.set_paused(paused, now);
+            self.paused = paused;
+            // the paused time is not counted in the display fps
+            self.shown_frames_in_window = 0;
+            self.fps_window_start = now;
+        }
+    }
+    pub fn set_speed(&mut self, speed: f64, now: Instant) {
+        if (speed - self.speed).abs() > f64::EPSILON {
+            self.audio.set_speed(speed, now);
+            self.video.set_speed(speed, now);
+            self.external.set_speed(speed, now);
+            self.speed = speed;
+        }
+    }
+    /// forget all the clocks, called after the input is seeked
+    pub fn reset(&mut self) {
+        self.audio.clear();
+        self.video.clear();
+        self.external.clear();
+        self.queued_audio.clear();
+        self.audio_latency_ms = 0.0;
+        self.av_offset_ms = None;
+    }
+    /// record one audio frame appended to the sink
+    pub fn audio_frame_queued(&mut self, pts_ms: f64, duration_ms: f64) {
+        self.queued_audio.push_back(QueuedAudio {
+            pts_ms,
+            duration_ms,
+        });
+    }
+    /// set the audio clock to the position being heard
+    /// `sink_len` is the count of frames left in the sink, `sink_pos` the position in the front one
+    pub fn update_audio_output(&mut self, sink_len: usize, sink_pos: Duration, now: Instant) {
+        while self.queued_audio.len() > sink_len {
+            self.queued_audio.pop_front();
+        }
+        if let Some(front) = self.queued_audio.front() {
+            let pos_ms = (sink_pos.as_secs_f64() * 1000.0).min(front.duration_ms);
+            let queued_ms: f64 = self.queued_audio.iter().map(|a| a.duration_ms).sum();
+            self.audio_latency_ms = queued_ms - pos_ms;
+            self.audio.set(front.pts_ms + pos_ms * self.speed, now);
+        } else {
+            // nothing to hear, the clock stops until new audio is appended
+            self.audio_latency_ms = 0.0;
+            self.audio.clear();
+        }
+        if let (Some(external_ms), Some(audio_ms)) = (self.external.get(now), self.audio.get(now)) {
+            let drift = audio_ms - external_ms;
+            if drift.abs() > NO_SYNC_THRESHOLD_MS {
+                self.external.set(audio_ms, now);
+            } else {
+                self.external
+                    .set(external_ms + drift * EXTERNAL_CLOCK_SLEW, now);
+            }
+        }
+    }
+    /// the time the streams follow, the video clock is used until audio is heard
+    pub fn master_ms(&self, now: Instant) -> Option<f64> {
+        match self.effective_source() {
+            ClockSource::Audio => self.audio.get(now).or(self.video.get(now)),
+            ClockSource::Video => self.video.get(now),
+            ClockSource::External => self.external.get(now),
+        }
+    }
+    /// the frame is in sync when it is shown from its pts to one window after it
+    pub fn sync_video(&mut self, pts_ms: f64, frame_duration_ms: f64, now: Instant) -> VideoSync {
+        if let Some(master_ms) = self.master_ms(now) {
+            let diff = pts_ms - master_ms;
+            if diff.abs() > NO_SYNC_THRESHOLD_MS {
+                self.restart_from(pts_ms, now);
+                return VideoSync::Show;
+            }
+            if diff > 0.0 {
+                return VideoSync::Wait;
+            }
+            if diff < -Self::sync_window_ms(frame_duration_ms) {
+                return VideoSync::Late;
+            }
+        } else {
+            self.restart_from(pts_ms, now);
+        }
+        VideoSync::Show
+    }
+    /// record the video frame being shown and measure the offset to the audio
+    pub fn video_frame_shown(&mut self, pts_ms: f64, frame_duration_ms: f64, now: Instant) {
+        let keep_running = if let ClockSource::Video = self.effective_source() {
+            // the video clock keeps its pace while the frames are in time
+            // so the little delay of every shown frame is not added up
+            if let Some(video_ms) = self.video.get(now) {
+                (pts_ms - video_ms).abs() <= Self::sync_window_ms(frame_duration_ms)
+            } else {
+                false
+            }
+        } else {
+            false
+        };
+        if !keep_running {
+            self.video.set(pts_ms, now);
+        }
+        if let Some(reference_ms) = self.audio.get(now).or(self.master_ms(now)) {
+            self.av_offset_ms = Some(pts_ms - reference_ms);
+        }
+        self.shown_frames_in_window += 1;
+        let window = now.saturating_duration_since(self.fps_window_start);
+        if window >= Duration::from_secs(1) {
+            self.frame_stats.display_fps =
+                self.shown_frames_in_window as f64 / window.as_secs_f64();
+            self.shown_frames_in_window = 0;
+            self.fps_window_start = now;
+        }
+    }
+    /// audio follows the master only when it is not the master itself
+    pub fn sync_audio(&mut self, pts_ms: f64, frame_duration_ms: f64, now: Instant) -> AudioSync {
+        if let ClockSource::Audio = self.effective_source() {
+            return AudioSync::Play;
+        }
+        if let Some(master_ms) = self.master_ms(now) {
+            // the frame is heard after the audio already in the sink
+            let heard_ms = master_ms + self.audio_latency_ms * self.speed;
+            let diff = pts_ms - heard_ms;
+            let window = Self::sync_window_ms(frame_duration_ms);
+            if diff.abs() > NO_SYNC_THRESHOLD_MS {
+                self.restart_from(pts_ms, now);
+            } else if diff > window {
+                return AudioSync::Wait;
+            } else if diff < -window {
+                return AudioSync::Drop;
+            }
+        } else {
+            self.restart_from(pts_ms, now);
+        }
+        AudioSync::Play
+    }
+    /// video pts minus the audio being heard when the frame is shown
+    pub fn av_offset_ms(&self) -> Option<f64> {
+        self.av_offset_ms
+    }
+    pub fn frame_stats(&self) -> FrameStats {
+        self.frame_stats
+    }
+    /// called when another input is opened
+    pub fn reset_frame_stats(&mut self) {
+        self.frame_stats = FrameStats::default();
+    }
+    pub fn record_dropped_frame(&mut self) {
+        self.frame_stats.dropped_frames += 1;
+    }
+    pub fn record_duplicated_frame(&mut self) {
+        self.frame_stats.duplicated_frames += 1;
+    }
+    pub fn set_skipping_non_ref(&mut self, skipping_non_ref: bool) {
+        self.frame_stats.skipping_non_ref = skipping_non_ref;
+    }
+    /// how long the audio appended now waits before it is heard
+    pub fn audio_latency_ms(&self) -> f64 {
+        self.audio_latency_ms
+    }
+    fn restart_from(&mut self, pts_ms: f64, now: Instant) {
+        self.video.set(pts_ms, now);
+        self.external.set(pts_ms, now);
+    }
+    fn sync_window_ms(frame_duration_ms: f64) -> f64 {
+        frame_duration_ms.clamp(SYNC_WINDOW_MIN_MS, SYNC_WINDOW_MAX_MS)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_ms(actual: Option<f64>, expected: f64) {
+        assert!(
+            actual.is_some_and(|actual| (actual - expected).abs() < 1e-6),
+            "{:?} != {}",
+            actual,
+            expected
+        );
+    }
+
+    /// a clock whose audio is heard from `pts_ms` at `now`
+    fn hear_audio(clock: &mut PlaybackClock, pts_ms: f64, now: Instant) {
+        clock.update_audio_output(0, Duration::ZERO, now);
+        clock.audio_frame_queued(pts_ms, 1000.0);
+        clock.update_audio_output(1, Duration::ZERO, now);
+    }
+
+    #[test]
+    fn master_selection() {
+        let t0 = Instant::now();
+        let mut clock = PlaybackClock::default();
+        assert_eq!(clock.master_ms(t0), None);
+        // without audio stream the video leads even when audio is chosen
+        clock.video_frame_shown(1000.0, 40.0, t0);
+        assert_ms(clock.master_ms(t0), 1000.0);
+        assert_ms(clock.master_ms(t0 + Duration::from_millis(100)), 1100.0);
+        // the video clock is used until audio is heard
+        clock.set_has_audio(true);
+        assert_ms(clock.master_ms(t0), 1000.0);
+        hear_audio(&mut clock, 5000.0, t0);
+        assert_ms(clock.master_ms(t0), 5000.0);
+        // the new master goes on from the same position
+        clock.set_source(ClockSource::Video, t0);
+        assert_eq!(clock.source(), ClockSource::Video);
+        assert_ms(clock.master_ms(t0), 5000.0);
+        clock.set_source(ClockSource::External, t0);
+        assert_ms(clock.master_ms(t0), 5000.0);
+        clock.reset();
+        assert_eq!(clock.master_ms(t0), None);
+    }
+
+    #[test]
+    fn paused_and_speed() {
+        let t0 = Instant::now();
+        let mut clock = PlaybackClock::default();
+        clock.video_frame_shown(0.0, 40.0, t0);
+        clock.set_paused(true, t0 + Duration::from_millis(100));
+        assert_ms(clock.master_ms(t0 + Duration::from_millis(500)), 100.0);
+        clock.set_paused(false, t0 + Duration::from_millis(500));
+        clock.set_speed(2.0, t0 + Duration::from_millis(500));
+        assert_ms(clock.master_ms(t0 + Duration::from_millis(600)), 300.0);
+    }
+
+    #[test]
+    fn video_sync_window() {
+        let t0 = Instant::now();
+        let now = t0 + Duration::from_millis(1000);
+        let mut clock = PlaybackClock::default();
+        clock.set_source(ClockSource::Video, t0);
+        // the first frame starts the clocks
+        assert_eq!(clock.sync_video(0.0, 40.0, t0), VideoSync::Show);
+        assert_ms(clock.master_ms(now), 1000.0);
+        assert_eq!(clock.sync_video(1010.0, 40.0, now), VideoSync::Wait);
+        assert_eq!(clock.sync_video(1000.0, 40.0, now), VideoSync::Show);
+        // short frames get the 40ms window
+        assert_eq!(clock.sync_video(970.0, 20.0, now), VideoSync::Show);
+        assert_eq!(clock.sync_video(950.0, 20.0, now), VideoSync::Late);
+        // the window is one frame in between
+        assert_eq!(clock.sync_video(950.0, 60.0, now), VideoSync::Show);
+        assert_eq!(clock.sync_video(930.0, 60.0, now), VideoSync::Late);
+        // long frames get the 100ms window
+        assert_eq!(clock.sync_video(920.0, 200.0, now), VideoSync::Show);
+        assert_eq!(clock.sync_video(850.0, 200.0, now), VideoSync::Late);
+    }
+
+    #[test]
+    fn audio_sync_window() {
+        let t0 = Instant::now();
+        let mut clock = PlaybackClock::default();
+        clock.set_has_audio(true);
+        // audio as master is never dropped or delayed
+        assert_eq!(clock.sync_audio(99_000.0, 20.0, t0), AudioSync::Play);
+        clock.set_source(ClockSource::Video, t0);
+        assert_eq!(clock.sync_video(1000.0, 40.0, t0), VideoSync::Show);
+        assert_eq!(clock.sync_audio(1000.0, 20.0, t0), AudioSync::Play);
+        assert_eq!(clock.sync_audio(1100.0, 20.0, t0), AudioSync::Wait);
+        assert_eq!(clock.sync_audio(900.0, 20.0, t0), AudioSync::Drop);
+        // the audio in the sink is heard first
+        clock.audio_frame_queued(950.0, 50.0);
+        clock.update_audio_output(1, Duration::ZERO, t0);
+        assert_eq!(clock.audio_latency_ms(), 50.0);
+        assert_eq!(clock.sync_audio(1050.0, 20.0, t0), AudioSync::Play);
+        assert_eq!(clock.sync_audio(1000.0, 20.0, t0), AudioSync::Drop);
+    }
+
+    #[test]
+    fn no_sync_threshold() {
+        let t0 = Instant::now();
+        let mut clock = PlaybackClock::default();
+        clock.set_source(ClockSource::Video, t0);
+        assert_eq!(clock.sync_video(0.0, 40.0, t0), VideoSync::Show);
+        // 9s ahead is only early, 11s ahead starts the clocks from the frame
+        assert_eq!(clock.sync_video(9000.0, 40.0, t0), VideoSync::Wait);
+        assert_eq!(clock.sync_video(11_000.0, 40.0, t0), VideoSync::Show);
+        assert_ms(clock.master_ms(t0), 11_000.0);
+        clock.set_has_audio(true);
+        assert_eq!(clock.sync_audio(-5000.0, 20.0, t0), AudioSync::Play);
+        assert_ms(clock.master_ms(t0), -5000.0);
+    }
+
+    #[test]
+    fn external_clock_slew() {
+        let t0 = Instant::now();
+        let mut clock = PlaybackClock::default();
+        clock.set_has_audio(true);
+        clock.set_source(ClockSource::External, t0);
+        assert_eq!(clock.sync_video(0.0, 40.0, t0), VideoSync::Show);
+        // a small drift to the audio is corrected slowly
+        hear_audio(&mut clock, 100.0, t0);
+        assert_ms(clock.master_ms(t0), 100.0 * EXTERNAL_CLOCK_SLEW);
+        // a drift past the no sync threshold is taken at once
+        hear_audio(&mut clock, 50_000.0, t0);
+        assert_ms(clock.master_ms(t0), 50_000.0);
+    }
+
+    #[test]
+    fn av_offset_measurement() {
+        let t0 = Instant::now();
+        let mut clock = PlaybackClock::default();
+        clock.set_has_audio(true);
+        assert_eq!(clock.av_offset_ms(), None);
+        clock.audio_frame_queued(1000.0, 100.0);
+        clock.audio_frame_queued(1100.0, 100.0);
+        clock.update_audio_output(2, Duration::from_millis(40), t0);
+        assert_ms(Some(clock.audio_latency_ms()), 160.0);
+        clock.video_frame_shown(1060.0, 40.0, t0);
+        assert_ms(clock.av_offset_ms(), 20.0);
+        // the first frame is played out of the sink
+        let now = t0 + Duration::from_millis(70);
+        clock.update_audio_output(1, Duration::from_millis(10), now);
+        assert_ms(Some(clock.audio_latency_ms()), 90.0);
+        clock.video_frame_shown(1100.0, 40.0, now);
+        assert_ms(clock.av_offset_ms(), -10.0);
+    }
+}
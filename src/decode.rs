@@ -1,20 +1,30 @@
 use std::{
     collections::VecDeque,
-    ffi::CString,
-    path::{Path, PathBuf},
+    ffi::CStr,
+    io::Write,
+    path::Path,
     ptr::{null, null_mut},
-    sync::{Arc, atomic::AtomicBool},
+    sync::{
+        Arc,
+        atomic::{AtomicBool, AtomicI64, AtomicUsize},
+        mpsc::{Receiver, RecvTimeoutError, Sender, SyncSender, TryRecvError},
+    },
+    thread::JoinHandle,
+    time::{Duration, Instant},
 };
 
 use derive_builder::Builder;
 use ffmpeg_the_third::{
     ChannelLayout, Packet, Rational, Stream,
+    color::{Range, Space, TransferCharacteristic},
     ffi::{
-        AV_CHANNEL_LAYOUT_STEREO, AVPixelFormat, AVSEEK_FLAG_BACKWARD, SwrContext,
-        av_frame_get_buffer, av_hwdevice_ctx_create, av_hwframe_transfer_data,
+        AV_CHANNEL_LAYOUT_STEREO, AV_CODEC_HW_CONFIG_METHOD_HW_DEVICE_CTX, AVColorSpace, AVDiscard,
+        AVHWDeviceType, AVHWFramesContext, AVPixelFormat, AVSEEK_FLAG_BACKWARD, FF_THREAD_FRAME,
+        FF_THREAD_SLICE, SWS_CS_DEFAULT, SwrContext, av_frame_copy_props, av_hwdevice_ctx_create,
+        av_hwdevice_get_type_name, av_hwdevice_iterate_types, av_hwframe_transfer_data,
         av_image_copy_to_buffer, av_image_get_buffer_size, avcodec_get_hw_config,
-        avfilter_get_by_name, avfilter_graph_create_filter, avfilter_link, swr_alloc_set_opts2,
-        swr_convert_frame, swr_free, swr_init,
+        swr_alloc_set_opts2, swr_convert_frame, swr_free, swr_init, sws_getCoefficients,
+        sws_setColorspaceDetails,
     },
     filter::Graph,
     format::{Pixel, sample::Type, stream::Disposition},
@@ -23,45 +33,27 @@ use ffmpeg_the_third::{
 };
 
 use time::format_description;
-use tokio::{
-    io::AsyncWriteExt,
-    runtime::Handle,
-    sync::{Notify, RwLock},
-    task::JoinHandle,
-};
-use tracing::{Instrument, Level, info, span, warn};
-
-use crate::{CURRENT_EXE_PATH, PlayerError, PlayerResult};
-/// this wrapper type should be protected manually to
-/// keep memory safe in multi threads
-/// means need to wrap an Arc and a Lock to use it in multi threads
-pub struct ManualProtectedInput(ffmpeg_the_third::format::context::Input);
-
-unsafe impl Sync for ManualProtectedInput {}
-/// this wrapper type should be protected manually to
-/// keep memory safe in multi threads
-/// means need to wrap an Arc and a Lock to use it in multi threads
-pub struct ManualProtectedVideoDecoder(ffmpeg_the_third::decoder::Video);
+use tokio::sync::{RwLock, oneshot};
+use tracing::{Level, info, span, warn};
 
-unsafe impl Sync for ManualProtectedVideoDecoder {}
-/// this wrapper type should be protected manually to
-/// keep memory safe in multi threads
-/// means need to wrap an Arc and a Lock to use it in multi threads
-pub struct ManualProtectedAudioDecoder(ffmpeg_the_third::decoder::Audio);
-
-unsafe impl Sync for ManualProtectedAudioDecoder {}
-/// this wrapper type should be protected manually to
-/// keep memory safe in multi threads
-/// means need to wrap an Arc and a Lock to use it in multi threads
-pub struct ManualProtectedResampler(pub *mut SwrContext);
-unsafe impl Send for ManualProtectedResampler {}
-unsafe impl Sync for ManualProtectedResampler {}
-/// this wrapper type should be protected manually to
-/// keep memory safe in multi threads
-/// means need to wrap an Arc and a Lock to use it in multi threads
-pub struct ManualProtectedConverter(pub scaling::Context);
-unsafe impl Send for ManualProtectedConverter {}
-unsafe impl Sync for ManualProtectedConverter {}
+use crate::{
+    CURRENT_EXE_PATH, PlayerError, PlayerResult,
+    buffer::{BufferHealth, BufferLimits, FramePool, MediaChannel, QueueItem, QueueLimit},
+    subtitle::SubtitleCues,
+    yuv_render,
+};
+/// a worker with nothing to do waits this long for a command before it tries again
+const WORKER_IDLE_WAIT: Duration = Duration::from_millis(5);
+/// the demux ends the input after this many read errors in a row, a broken input
+/// would be retried forever otherwise
+const MAX_DEMUX_READ_ERRORS: usize = 50;
+/// the video decode worker publishes its stats this often instead of after every packet
+const DECODE_STATS_PUBLISH_INTERVAL: Duration = Duration::from_millis(250);
+/// subtitle packets are decoded as soon as they are read, only a runaway queue is limited
+const SUBTITLE_PACKET_LIMIT: QueueLimit = QueueLimit {
+    duration_ms: i64::MAX,
+    bytes: 4 * 1024 * 1024,
+};
 /// indicate which stream in the input is chose as main stream
 #[derive(Debug, Clone, Copy)]
 pub enum MainStream {
@@ -69,121 +61,217 @@ pub enum MainStream {
     Audio,
 }
 
+/// how the input is seeked
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SeekMode {
+    /// play from the keyframe near the timestamp, quick enough for scrubbing
+    Fast,
+    /// decode from the keyframe before the timestamp and drop
+    /// the frames until the timestamp is reached
+    Accurate,
+}
+
+/// describe one audio or subtitle stream of the input so the user can choose
+/// which language or commentary to use
+#[derive(Debug, Clone)]
+pub struct StreamTrack {
+    pub stream_index: usize,
+    pub language: Option<String>,
+    pub title: Option<String>,
+    pub is_default: bool,
+}
+impl StreamTrack {
+    /// build the track description from the stream metadata
+    fn from_stream(stream: &Stream<'_>) -> Self {
+        let metadata = stream.metadata();
+        Self {
+            stream_index: stream.index(),
+            language: metadata.get("language").map(|s| s.to_string()),
+            title: metadata.get("title").map(|s| s.to_string()),
+            is_default: stream.disposition().contains(Disposition::DEFAULT),
+        }
+    }
+    /// text shown in the track picker
+    pub fn label(&self) -> String {
+        let mut label = format!("#{}", self.stream_index);
+        if let Some(language) = &self.language {
+            label.push(' ');
+            label.push_str(language);
+        }
+        if let Some(title) = &self.title {
+            label.push_str(" - ");
+            label.push_str(title);
+        }
+        label
+    }
+}
+
 /// represent all the details and relevent variables about
 /// video format, decode, detail and hardware accelerate
 /// the main struct of decode module to manage input and decode
+/// the input and the ffmpeg contexts are owned by the worker threads,
+/// this struct only talks to them through channels
 pub struct TinyDecoder {
     video_stream_index: usize,
     audio_stream_index: usize,
+    audio_tracks: Vec<StreamTrack>,
+    subtitle_stream_index: Option<usize>,
+    subtitle_tracks: Vec<StreamTrack>,
     cover_stream_index: usize,
     main_stream: MainStream,
     video_time_base: Rational,
     audio_time_base: Rational,
     video_frame_rect: [u32; 2],
+    video_frame_duration: i64,
     format_duration: i64,
+    start_time_ms: i64,
     end_timestamp: i64,
     end_time_formatted_string: String,
-    format_input: Arc<RwLock<Option<ManualProtectedInput>>>,
-    video_decoder: Arc<RwLock<Option<ManualProtectedVideoDecoder>>>,
-    audio_decoder: Arc<RwLock<Option<ManualProtectedAudioDecoder>>>,
-    converter_ctx: Option<ManualProtectedConverter>,
-    resampler_ctx: Option<ManualProtectedResampler>,
-    video_frame_cache_queue: Arc<RwLock<VecDeque<ffmpeg_the_third::frame::Video>>>,
-    audio_frame_cache_queue: Arc<RwLock<VecDeque<ffmpeg_the_third::frame::Audio>>>,
-    audio_packet_cache_queue: Arc<RwLock<VecDeque<ffmpeg_the_third::packet::Packet>>>,
-    video_packet_cache_queue: Arc<RwLock<VecDeque<ffmpeg_the_third::packet::Packet>>>,
-    demux_exit_flag: Arc<AtomicBool>,
-    decode_exit_flag: Arc<AtomicBool>,
-    demux_task_handle: Option<JoinHandle<()>>,
-    decode_task_handle: Option<JoinHandle<()>>,
+    color_matrix_override: ColorMatrixOverride,
+    color_range_override: ColorRangeOverride,
+    video_renderer: VideoRenderer,
+    audio_packet_channel: Arc<MediaChannel<Packet>>,
+    video_packet_channel: Arc<MediaChannel<Packet>>,
+    subtitle_packet_channel: Arc<MediaChannel<Packet>>,
+    audio_frame_channel: Arc<MediaChannel<Audio>>,
+    video_frame_channel: Arc<MediaChannel<Video>>,
+    subtitle_cues: Arc<RwLock<SubtitleCues>>,
+    video_seek_target_pts: Arc<AtomicI64>,
+    audio_seek_target_pts: Arc<AtomicI64>,
+    /// the first serial the audio decoder of the playing track decodes
+    audio_decoder_serial: Arc<AtomicUsize>,
+    playback_speed: f64,
+    audio_tempo: Option<AudioTempo>,
+    audio_tempo_frame_queue: VecDeque<ffmpeg_the_third::frame::Audio>,
+    audio_tempo_flush_flag: AtomicBool,
+    video_filter_chain: VideoFilterChain,
+    decode_stats: Arc<RwLock<DecodeStats>>,
+    video_codec_name: String,
+    audio_codec_name: String,
+    video_frame_rate: f64,
+    seek_serial: AtomicUsize,
+    workers: Option<DecodeWorkers>,
     hardware_config_flag: Arc<AtomicBool>,
+    hardware_policy: HardwarePolicy,
+    video_threading: DecoderThreading,
+    audio_threading: DecoderThreading,
+    hardware_device_type: Option<AVHWDeviceType>,
+    hardware_fallback_pts: Arc<AtomicI64>,
+    video_skip_non_ref: bool,
     cover_pic_data: Arc<RwLock<Option<Vec<u8>>>>,
-    runtime_handle: Handle,
-    demux_thread_notify: Arc<Notify>,
-    decode_thread_notify: Arc<Notify>,
 }
 impl TinyDecoder {
     /// init Decoder and new Struct
-    /// `runtime_handle` is the handle of the tokio runtime in async_context
-    pub fn new(runtime_handle: Handle) -> PlayerResult<Self> {
+    pub fn new() -> PlayerResult<Self> {
         ffmpeg_the_third::init().map_err(|e| PlayerError::Internal(e.to_string()))?;
+        let limits = BufferLimits::default();
         Ok(Self {
             video_stream_index: usize::MAX,
             audio_stream_index: usize::MAX,
+            audio_tracks: vec![],
+            subtitle_stream_index: None,
+            subtitle_tracks: vec![],
             cover_stream_index: usize::MAX,
             main_stream: MainStream::Audio,
             video_time_base: Rational::new(1, 1),
             audio_time_base: Rational::new(1, 1),
             video_frame_rect: [0, 0],
+            video_frame_duration: 1,
             format_duration: 0,
+            start_time_ms: 0,
             end_timestamp: 0,
             end_time_formatted_string: String::new(),
-            format_input: Arc::new(RwLock::new(None)),
-            video_decoder: Arc::new(RwLock::new(None)),
-            audio_decoder: Arc::new(RwLock::new(None)),
-            converter_ctx: None,
-            resampler_ctx: None,
-            video_frame_cache_queue: std::sync::Arc::new(RwLock::new(VecDeque::new())),
-            audio_frame_cache_queue: std::sync::Arc::new(RwLock::new(VecDeque::new())),
-            audio_packet_cache_queue: std::sync::Arc::new(RwLock::new(VecDeque::new())),
-            video_packet_cache_queue: std::sync::Arc::new(RwLock::new(VecDeque::new())),
-            demux_exit_flag: Arc::new(AtomicBool::new(false)),
-            decode_exit_flag: Arc::new(AtomicBool::new(false)),
-            demux_task_handle: None,
-            decode_task_handle: None,
+            color_matrix_override: ColorMatrixOverride::Auto,
+            color_range_override: ColorRangeOverride::Auto,
+            video_renderer: VideoRenderer::Swscale,
+            audio_packet_channel: Arc::new(MediaChannel::new(limits.audio_packets)),
+            video_packet_channel: Arc::new(MediaChannel::new(limits.video_packets)),
+            subtitle_packet_channel: Arc::new(MediaChannel::new(SUBTITLE_PACKET_LIMIT)),
+            audio_frame_channel: Arc::new(MediaChannel::new(limits.audio_frames)),
+            video_frame_channel: Arc::new(MediaChannel::new(limits.video_frames)),
+            subtitle_cues: Arc::new(RwLock::new(SubtitleCues::default())),
+            video_seek_target_pts: Arc::new(AtomicI64::new(i64::MIN)),
+            audio_seek_target_pts: Arc::new(AtomicI64::new(i64::MIN)),
+            audio_decoder_serial: Arc::new(AtomicUsize::new(0)),
+            playback_speed: 1.0,
+            audio_tempo: None,
+            audio_tempo_frame_queue: VecDeque::new(),
+            audio_tempo_flush_flag: AtomicBool::new(false),
+            video_filter_chain: VideoFilterChain::default(),
+            decode_stats: Arc::new(RwLock::new(DecodeStats::default())),
+            video_codec_name: String::new(),
+            audio_codec_name: String::new(),
+            video_frame_rate: 0.0,
+            seek_serial: AtomicUsize::new(0),
+            workers: None,
             hardware_config_flag: Arc::new(AtomicBool::new(false)),
+            hardware_policy: HardwarePolicy::Auto,
+            video_threading: DecoderThreading::default_video(),
+            audio_threading: DecoderThreading::default_audio(),
+            hardware_device_type: None,
+            hardware_fallback_pts: Arc::new(AtomicI64::new(i64::MIN)),
+            video_skip_non_ref: false,
             cover_pic_data: Arc::new(RwLock::new(None)),
-            runtime_handle,
-            demux_thread_notify: Arc::new(Notify::new()),
-            decode_thread_notify: Arc::new(Notify::new()),
         })
     }
     /// reset all fields to the initial state
     /// this is to make the decoder ready for fresh input
     async fn reset_tiny_decoder_states(&mut self) {
         self.audio_stream_index = usize::MAX;
+        self.audio_tracks.clear();
+        self.subtitle_stream_index = None;
+        self.subtitle_tracks.clear();
         self.video_stream_index = usize::MAX;
         self.cover_stream_index = usize::MAX;
         self.main_stream = MainStream::Audio;
-        *self.audio_decoder.write().await = None;
         self.audio_time_base = Rational::new(1, 1);
-        self.converter_ctx = None;
         self.cover_pic_data = Arc::new(RwLock::new(None));
-        self.decode_task_handle = None;
-        self.decode_exit_flag
-            .store(false, std::sync::atomic::Ordering::Relaxed);
-        self.demux_task_handle = None;
-        self.demux_exit_flag
-            .store(false, std::sync::atomic::Ordering::Relaxed);
         self.end_time_formatted_string = String::new();
         self.end_timestamp = 0;
         self.format_duration = 0;
-        *self.format_input.write().await = None;
+        self.start_time_ms = 0;
         self.hardware_config_flag
             .store(false, std::sync::atomic::Ordering::Relaxed);
-        self.resampler_ctx = None;
-        *self.video_decoder.write().await = None;
+        self.hardware_device_type = None;
+        self.hardware_fallback_pts
+            .store(i64::MIN, std::sync::atomic::Ordering::Relaxed);
+        self.video_skip_non_ref = false;
         self.video_frame_rect = [0, 0];
+        self.video_frame_duration = 1;
         self.video_time_base = Rational::new(1, 1);
-        self.audio_packet_cache_queue.write().await.clear();
-        self.video_packet_cache_queue.write().await.clear();
-        self.audio_frame_cache_queue.write().await.clear();
-        self.video_frame_cache_queue.write().await.clear();
+        *self.decode_stats.write().await = DecodeStats::default();
+        self.video_codec_name.clear();
+        self.audio_codec_name.clear();
+        self.video_frame_rate = 0.0;
+        self.subtitle_cues.write().await.clear();
+        self.video_seek_target_pts
+            .store(i64::MIN, std::sync::atomic::Ordering::Relaxed);
+        self.audio_seek_target_pts
+            .store(i64::MIN, std::sync::atomic::Ordering::Relaxed);
+        let serial = self.next_seek_serial();
+        self.flush_channels(serial);
+        self.audio_tempo_flush_flag
+            .store(true, std::sync::atomic::Ordering::Relaxed);
     }
     /// called when user selected a file path to play
     /// init all the details from the file selected
     pub async fn set_file_path_and_init_par(&mut self, path: &Path) -> PlayerResult<()> {
         info!("ffmpeg version{}", ffmpeg_the_third::format::version());
-        if self.demux_task_handle.is_some() {
-            self.stop_demux_and_decode().await;
-            self.reset_tiny_decoder_states().await;
-        }
-        let format_input = ffmpeg_the_third::format::input(path)
-            .map_err(|e| PlayerError::Internal(e.to_string()))?;
+        // a failed open leaves tracks and indices behind without starting the workers
+        self.stop_workers();
+        self.reset_tiny_decoder_states().await;
+        std::fs::metadata(path).map_err(|e| PlayerError::io(path, e))?;
+        let format_input = ffmpeg_the_third::format::input(path).map_err(|source| {
+            PlayerError::UnsupportedFormat {
+                path: path.to_path_buf(),
+                source,
+            }
+        })?;
         info!("input construct finished");
         let mut cover_stream = None;
         let mut video_stream = None;
-        let mut audio_stream = None;
+        let mut audio_stream: Option<Stream<'_>> = None;
+        let mut subtitle_stream = None;
         for item in format_input.streams() {
             let stream_type = item.parameters().medium();
             if stream_type == ffmpeg_the_third::util::media::Type::Video {
@@ -196,7 +284,26 @@ impl TinyDecoder {
                 }
             } else if stream_type == ffmpeg_the_third::util::media::Type::Audio {
                 info!("audio stream was found");
-                audio_stream = Some(item);
+                let track = StreamTrack::from_stream(&item);
+                // prefer the track the container marks as default, otherwise keep the first one
+                let replace_chosen = match &audio_stream {
+                    Some(chosen) => {
+                        track.is_default && !chosen.disposition().contains(Disposition::DEFAULT)
+                    }
+                    None => true,
+                };
+                if replace_chosen {
+                    audio_stream = Some(item);
+                }
+                self.audio_tracks.push(track);
+            } else if stream_type == ffmpeg_the_third::util::media::Type::Subtitle {
+                info!("subtitle stream was found");
+                let track = StreamTrack::from_stream(&item);
+                // only turn on the subtitle the container marks as default
+                if track.is_default && subtitle_stream.is_none() {
+                    subtitle_stream = Some(item);
+                }
+                self.subtitle_tracks.push(track);
             } else if stream_type == ffmpeg_the_third::util::media::Type::Attachment {
                 info!("attachment stream was found");
                 cover_stream = Some(item);
@@ -204,6 +311,9 @@ impl TinyDecoder {
         }
         if audio_stream.is_none() && video_stream.is_none() {
             info!("no valid stream found");
+            return Err(PlayerError::NoPlayableStream {
+                path: path.to_path_buf(),
+            });
         }
         if let Some(stream) = &cover_stream {
             info!("cover stream found");
@@ -213,13 +323,30 @@ impl TinyDecoder {
         if let Some(stream) = &audio_stream {
             self.audio_stream_index = stream.index();
             self.audio_time_base = stream.time_base();
+            self.set_audio_channels_time_base(self.audio_time_base);
+            self.audio_codec_name = stream.parameters().id().name().to_string();
             info!("audio time_base==={}", self.audio_time_base);
         }
 
         if let Some(stream) = &video_stream {
             self.video_stream_index = stream.index();
             self.video_time_base = stream.time_base();
+            self.video_packet_channel
+                .set_time_base(self.video_time_base);
+            self.video_frame_channel.set_time_base(self.video_time_base);
             info!("video time_base==={}", self.video_time_base);
+            let mut frame_rate = stream.avg_frame_rate();
+            if frame_rate.numerator() <= 0 || frame_rate.denominator() <= 0 {
+                frame_rate = Rational::new(25, 1);
+            }
+            self.video_frame_rate = f64::from(frame_rate);
+            self.video_codec_name = stream.parameters().id().name().to_string();
+            // one frame duration in the video time base
+            self.video_frame_duration = (self.video_time_base.denominator() as i64
+                * frame_rate.denominator() as i64
+                / self.video_time_base.numerator() as i64
+                / frame_rate.numerator() as i64)
+                .max(1);
             if audio_stream.is_none() {
                 self.main_stream = MainStream::Video;
             }
@@ -229,6 +356,11 @@ impl TinyDecoder {
         // format_input.duration() number unit is us
         info!("total duration {} us", format_input.duration());
         self.format_duration = format_input.duration();
+        // the start time unit is us, timestamps of some formats like mpegts don't start from 0
+        let start_time = unsafe { (*format_input.as_ptr()).start_time };
+        if start_time != ffmpeg_the_third::ffi::AV_NOPTS_VALUE {
+            self.start_time_ms = start_time / 1000;
+        }
         let adur_ts = {
             if let MainStream::Audio = self.main_stream {
                 format_input.duration() * self.audio_time_base.denominator() as i64
@@ -243,468 +375,437 @@ impl TinyDecoder {
         self.end_timestamp = adur_ts;
         self.compute_and_set_end_time_str(adur_ts);
 
+        let mut video_decoder = None;
         if let Some(video_stream) = &video_stream {
-            let video_decoder = self
-                .choose_decoder_with_hardware_prefer(video_stream)
-                .await?;
-
-            let converter = ffmpeg_the_third::software::converter(
-                (video_decoder.width(), video_decoder.height()),
-                Pixel::YUV420P,
-                Pixel::RGBA,
-            )
-            .map_err(|e| PlayerError::Internal(e.to_string()))?;
-            self.converter_ctx = Some(ManualProtectedConverter(converter));
+            let (decoder, hardware_device_type) = Self::choose_decoder_with_hardware_prefer(
+                video_stream,
+                self.hardware_policy,
+                self.video_threading,
+            )?;
+            self.set_hardware_device_type(hardware_device_type);
+            // the rgba converter is created from the first decoded frame,
+            // the real pixel format is unknown until then with hardware decoding
 
-            info!("video decode format{:#?}", video_decoder.format());
-            self.video_frame_rect = [video_decoder.width(), video_decoder.height()];
-            let mut v_decoder = self.video_decoder.write().await;
-            *v_decoder = Some(ManualProtectedVideoDecoder(video_decoder));
+            info!("video decode format{:#?}", decoder.format());
+            self.video_frame_rect = [decoder.width(), decoder.height()];
+            video_decoder = Some(decoder);
         }
+        let mut audio_decoder = None;
         if let Some(audio_stream) = &audio_stream {
-            let audio_decoder_ctx =
-                ffmpeg_the_third::codec::Context::from_parameters(audio_stream.parameters())
-                    .map_err(|e| PlayerError::Internal(e.to_string()))?;
-
-            let mut audio_decoder = audio_decoder_ctx
-                .decoder()
-                .audio()
-                .map_err(|e| PlayerError::Internal(e.to_string()))?;
-            unsafe {
-                if audio_decoder.ch_layout().channels() == 2 {
-                    audio_decoder.set_ch_layout(ChannelLayout::STEREO);
-                }
-                let mut swr_ctx = null_mut();
-                let r = swr_alloc_set_opts2(
-                    &mut swr_ctx,
-                    &AV_CHANNEL_LAYOUT_STEREO,
-                    ffmpeg_the_third::ffi::AVSampleFormat::AV_SAMPLE_FMT_FLT,
-                    48000,
-                    audio_decoder.ch_layout().as_ptr(),
-                    audio_decoder.format().into(),
-                    audio_decoder.rate() as i32,
-                    0,
-                    null_mut(),
-                );
-                if r < 0 {
-                    info!("swr ctx create err");
+            audio_decoder = Some(Self::create_audio_decoder(
+                audio_stream,
+                self.audio_threading,
+            )?);
+        }
+        let mut subtitle_decoder = None;
+        if let Some(subtitle_stream) = &subtitle_stream {
+            match Self::create_subtitle_decoder(subtitle_stream) {
+                Ok(decoder) => {
+                    self.subtitle_stream_index = Some(subtitle_stream.index());
+                    subtitle_decoder = Some(decoder);
                 }
-                let r = swr_init(swr_ctx);
-                if r < 0 {
-                    info!("swr init err");
+                Err(e) => {
+                    warn!("subtitle decoder create err {}", e);
                 }
-                self.resampler_ctx = Some(ManualProtectedResampler(swr_ctx));
             }
+        }
+        info!("par init finished!!!");
+        self.start_workers(format_input, video_decoder, audio_decoder, subtitle_decoder)
+    }
+    /// open the decoder of an audio stream, its output is resampled
+    /// to the format of the audio player by the audio decode thread
+    fn create_audio_decoder(
+        audio_stream: &Stream<'_>,
+        threading: DecoderThreading,
+    ) -> PlayerResult<ffmpeg_the_third::decoder::Audio> {
+        let mut audio_decoder_ctx =
+            ffmpeg_the_third::codec::Context::from_parameters(audio_stream.parameters())
+                .map_err(Self::codec_error(audio_stream))?;
+        threading.apply(&mut audio_decoder_ctx, 0);
 
-            let mut a_decoder = self.audio_decoder.write().await;
-            *a_decoder = Some(ManualProtectedAudioDecoder(audio_decoder));
+        let mut audio_decoder = audio_decoder_ctx
+            .decoder()
+            .audio()
+            .map_err(Self::codec_error(audio_stream))?;
+        if audio_decoder.ch_layout().channels() == 2 {
+            audio_decoder.set_ch_layout(ChannelLayout::STEREO);
+        }
+        Ok(audio_decoder)
+    }
+    /// switch the playing audio track to another audio stream of the same input
+    /// `current_ts` is the main stream timestamp being played, the input is seeked back
+    /// to it so the position is kept, return the position in the new main stream time base
+    pub async fn switch_audio_track(
+        &mut self,
+        stream_index: usize,
+        current_ts: i64,
+    ) -> PlayerResult<i64> {
+        if stream_index == self.audio_stream_index {
+            return Ok(current_ts);
         }
+        if !self
+            .audio_tracks
+            .iter()
+            .any(|track| track.stream_index == stream_index)
         {
-            let mut input = self.format_input.write().await;
-            *input = Some(ManualProtectedInput(format_input));
+            return Err(PlayerError::TrackNotFound(stream_index));
         }
-        info!("par init finished!!!");
-        self.start_process_input().await;
-        Ok(())
+        let threading = self.audio_threading;
+        let (audio_decoder, time_base, codec_name) = self
+            .request_from_demux(|reply| DemuxCommand::OpenAudioDecoder {
+                stream_index,
+                threading,
+                reply,
+            })
+            .await?;
+        self.audio_codec_name = codec_name;
+        self.send_command(
+            |workers| &workers.demux_command_sender,
+            DemuxCommand::SetAudioStream(stream_index),
+        );
+        self.audio_stream_index = stream_index;
+        let old_time_base = self.audio_time_base;
+        self.audio_time_base = time_base;
+        self.set_audio_channels_time_base(time_base);
+        info!(
+            "audio track switched to stream {}, time_base==={}",
+            stream_index, time_base
+        );
+        let mut new_ts = current_ts;
+        if let MainStream::Audio = self.main_stream {
+            new_ts = current_ts * old_time_base.numerator() as i64 * time_base.denominator() as i64
+                / old_time_base.denominator() as i64
+                / time_base.numerator() as i64;
+            self.end_timestamp = self.format_duration * time_base.denominator() as i64
+                / time_base.numerator() as i64
+                / 1_000_000;
+        }
+        // the new decoder only gets the packets of the timeline started by this seek,
+        // the queued packets of the old track are dropped with the old timeline
+        self.seek_with_audio_decoder(new_ts, SeekMode::Accurate, Some(audio_decoder))
+            .await;
+        Ok(new_ts)
     }
-    /// the loop of demuxing video file
-    async fn packet_demux_process(demux_context: DemuxContext) {
-        info!("enter demux");
-        loop {
-            if demux_context
-                .demux_exit_flag
-                .load(std::sync::atomic::Ordering::Relaxed)
-            {
-                break;
-            }
-            /*
-            choose to lock the packet vec first stick this in other functions
-             */
-
+    fn set_audio_channels_time_base(&self, time_base: Rational) {
+        self.audio_packet_channel.set_time_base(time_base);
+        self.audio_frame_channel.set_time_base(time_base);
+    }
+    /// open the decoder of a subtitle stream
+    /// the packet time base is needed by ffmpeg to give the subtitle pts and duration
+    fn create_subtitle_decoder(
+        subtitle_stream: &Stream<'_>,
+    ) -> PlayerResult<ffmpeg_the_third::decoder::Subtitle> {
+        let mut subtitle_decoder_ctx =
+            ffmpeg_the_third::codec::Context::from_parameters(subtitle_stream.parameters())
+                .map_err(Self::codec_error(subtitle_stream))?;
+        unsafe {
+            (*subtitle_decoder_ctx.as_mut_ptr()).pkt_timebase = subtitle_stream.time_base().into();
+        }
+        subtitle_decoder_ctx
+            .decoder()
+            .subtitle()
+            .map_err(Self::codec_error(subtitle_stream))
+    }
+    /// switch the shown subtitle to another subtitle stream, `None` turns the subtitle off
+    pub async fn switch_subtitle_track(&mut self, stream_index: Option<usize>) -> PlayerResult<()> {
+        let subtitle_decoder = if let Some(stream_index) = stream_index {
+            if !self
+                .subtitle_tracks
+                .iter()
+                .any(|track| track.stream_index == stream_index)
             {
-                let mut audio_packet_vec = demux_context.audio_packet_cache_queue.write().await;
-                let mut video_packet_vec = demux_context.video_packet_cache_queue.write().await;
-                let audio_stream_idx = demux_context.audio_stream_index;
-                let video_stream_idx = demux_context.video_stream_index;
-                let cover_index = demux_context.cover_stream_index;
-                let mut cover_pic_data = demux_context.cover_image_data.write().await;
-                let mut input = demux_context.format_input.write().await;
-                if let Some(input) = &mut *input {
-                    match input.0.packets().next() {
-                        Some(Ok((stream, packet))) => {
-                            if stream.index() == cover_index {
-                                if let Some(d) = packet.data() {
-                                    *cover_pic_data = Some(d.to_vec());
-                                }
-                            } else if stream.index() == audio_stream_idx {
-                                audio_packet_vec.push_back(packet);
-                            } else if stream.index() == video_stream_idx {
-                                video_packet_vec.push_back(packet);
-                            }
-                        }
-                        Some(Err(ffmpeg_the_third::util::error::Error::Eof)) => {
-                            info!("demux process hit the end");
-                        }
-                        None => {}
-                        _ => {}
-                    }
-                }
+                return Err(PlayerError::TrackNotFound(stream_index));
             }
-            demux_context.demux_thread_notify.notified().await;
-        }
+            Some(
+                self.request_from_demux(|reply| DemuxCommand::OpenSubtitleDecoder {
+                    stream_index,
+                    reply,
+                })
+                .await?,
+            )
+        } else {
+            None
+        };
+        // the demux drops the packets of the old track, the subtitle thread its cues
+        self.send_command(
+            |workers| &workers.demux_command_sender,
+            DemuxCommand::SetSubtitleStream(stream_index),
+        );
+        self.send_command(
+            |workers| &workers.subtitle_command_sender,
+            SubtitleDecodeCommand::SetDecoder(subtitle_decoder),
+        );
+        self.subtitle_cues.write().await.clear();
+        self.subtitle_stream_index = stream_index;
+        Ok(())
     }
-    ///convert the hardware output frame to middle format YUV420P
-    async fn convert_hardware_frame(
-        hardware_config: Arc<AtomicBool>,
-        hardware_frame_converter: Arc<RwLock<Option<ManualProtectedConverter>>>,
+    /// all the subtitle tracks found in the input
+    pub fn subtitle_tracks(&self) -> &Vec<StreamTrack> {
+        &self.subtitle_tracks
+    }
+    /// stream index of the subtitle track being shown
+    pub fn subtitle_stream_index(&self) -> Option<usize> {
+        self.subtitle_stream_index
+    }
+    /// give an Arc of the decoded subtitle cues
+    pub fn subtitle_cues(&self) -> Arc<RwLock<SubtitleCues>> {
+        self.subtitle_cues.clone()
+    }
+    /// all the audio tracks found in the input
+    pub fn audio_tracks(&self) -> &Vec<StreamTrack> {
+        &self.audio_tracks
+    }
+    /// stream index of the audio track being played
+    pub fn audio_stream_index(&self) -> usize {
+        self.audio_stream_index
+    }
+    /// download the hardware output frame to a software frame
+    /// the frame keeps the format given by the driver(NV12, P010...),
+    /// it is converted to rgba only once when it is played
+    /// return None if the transfer failed, the device is not usable then
+    fn transfer_hardware_frame(
         video_frame_tmp: Video,
-    ) -> Video {
-        if hardware_config.load(std::sync::atomic::Ordering::Relaxed) {
-            unsafe {
-                let mut transfered_frame = ffmpeg_the_third::frame::Video::empty();
-                if 0 != av_hwframe_transfer_data(
-                    transfered_frame.as_mut_ptr(),
-                    video_frame_tmp.as_ptr(),
-                    0,
-                ) {
-                    warn!("hardware frame transfer to software frame err");
-                }
-
+        frame_pool: &mut FramePool,
+    ) -> Option<Video> {
+        unsafe {
+            let hw_frames_ctx = (*video_frame_tmp.as_ptr()).hw_frames_ctx;
+            if hw_frames_ctx.is_null() {
+                return Some(video_frame_tmp);
+            }
+            // download at the size of the hardware surfaces like ffmpeg does,
+            // then crop to the picture size
+            let frames_ctx = (*hw_frames_ctx).data as *const AVHWFramesContext;
+            let mut transfered_frame = frame_pool
+                .get(
+                    (*frames_ctx).sw_format,
+                    (*frames_ctx).width as u32,
+                    (*frames_ctx).height as u32,
+                )
+                .unwrap_or_else(Video::empty);
+            if 0 != av_hwframe_transfer_data(
+                transfered_frame.as_mut_ptr(),
+                video_frame_tmp.as_ptr(),
+                0,
+            ) {
+                warn!("hardware frame transfer to software frame err");
+                return None;
+            }
+            (*transfered_frame.as_mut_ptr()).width = video_frame_tmp.width() as i32;
+            (*transfered_frame.as_mut_ptr()).height = video_frame_tmp.height() as i32;
+            // keep pts, duration and the color properties
+            if 0 > av_frame_copy_props(transfered_frame.as_mut_ptr(), video_frame_tmp.as_ptr()) {
                 transfered_frame.set_pts(video_frame_tmp.pts());
-                let mut default_frame = Video::empty();
-                {
-                    let mut hardware_frame_converter_guard = hardware_frame_converter.write().await;
-                    if let Some(hardware_frame_converter) = &mut *hardware_frame_converter_guard {
-                        if hardware_frame_converter
-                            .0
-                            .run(&transfered_frame, &mut default_frame)
-                            .is_ok()
-                        {
-                            default_frame.set_pts(transfered_frame.pts());
-                            return default_frame;
-                        }
-                    } else if let Ok(mut ctx) = ffmpeg_the_third::software::converter(
-                        (video_frame_tmp.width(), video_frame_tmp.height()),
-                        transfered_frame.format(),
-                        Pixel::YUV420P,
-                    ) {
-                        info!("transfered_frame format: {:?}", transfered_frame.format());
-                        if ctx.run(&transfered_frame, &mut default_frame).is_ok() {
-                            default_frame.set_pts(transfered_frame.pts());
-                            *hardware_frame_converter_guard = Some(ManualProtectedConverter(ctx));
-                            return default_frame;
-                        }
-                    }
-                }
             }
+            Some(transfered_frame)
         }
-        video_frame_tmp
     }
-    /// the loop of decoding demuxed packet
-    async fn frame_decode_process(decode_context: DecodeContext) {
-        info!("enter decode");
-        let mut p = PathBuf::new();
-        let mut graph = Graph::new();
-        if decode_context.video_decoder.read().await.is_some() {
-            if let Ok(exe_path) = CURRENT_EXE_PATH.as_ref() {
-                if let Some(exe_folder) = exe_path.parent() {
-                    p = exe_folder.join("app_font.ttf");
-                    if tokio::fs::File::open(&p).await.is_err() {
-                        if let Ok(mut file) = tokio::fs::File::create_new(&p).await {
-                            if file.write_all(crate::appui::MAPLE_FONT).await.is_ok() {}
+    /// write the bundled font beside the exe for the watermark drawtext filter
+    /// return the font path escaped for the filter args
+    fn prepare_watermark_font() -> Option<String> {
+        if let Ok(exe_path) = CURRENT_EXE_PATH.as_ref() {
+            if let Some(exe_folder) = exe_path.parent() {
+                let p = exe_folder.join("app_font.ttf");
+                if std::fs::File::open(&p).is_err() {
+                    if let Ok(mut file) = std::fs::File::create_new(&p) {
+                        if file.write_all(crate::appui::MAPLE_FONT).is_err() {
+                            warn!("write watermark font error");
                         }
                     }
                 }
-            }
-
-            if let Some(font_path_str) = p.to_str() {
-                let mut font_path_str = font_path_str.replace("\\", "/");
-                if let Some(idx) = font_path_str.find(':') {
-                    font_path_str.insert(idx, '\\');
-                    unsafe {
-                        if let Ok(c_str_buffer) = CString::new("buffer") {
-                            if let Ok(c_str_buffersrc) = CString::new("buffersrc") {
-                                if let Ok(c_str_buffersrc_args) = CString::new(format!(
-                                    "video_size={}x{}:pix_fmt={}:time_base={}/{}:pixel_aspect=1/1",
-                                    decode_context.video_frame_rect[0],
-                                    decode_context.video_frame_rect[1],
-                                    AVPixelFormat::AV_PIX_FMT_YUV420P as i32,
-                                    decode_context.video_time_base.numerator(),
-                                    decode_context.video_time_base.denominator(),
-                                )) {
-                                    if let Ok(c_str_drawtext) = CString::new("drawtext") {
-                                        if let Ok(c_str_draw) = CString::new("draw") {
-                                            if let Ok(c_str_draw_args) = CString::new(format!(
-                                                "text='Tiny Player':fontfile={}:fontsize=26:fontcolor=white@0.3:x=w-text_w-10:y=10",
-                                                font_path_str
-                                            )) {
-                                                if let Ok(c_str_buffersink) =
-                                                    CString::new("buffersink")
-                                                {
-                                                    if let Ok(c_str_sink) = CString::new("sink") {
-                                                        let buffersrc_filter = avfilter_get_by_name(
-                                                            c_str_buffer.as_ptr(),
-                                                        );
-                                                        // graph free will automatically free filterctx
-                                                        let mut buffersrc_ctx = null_mut();
-                                                        let draw_filter = avfilter_get_by_name(
-                                                            c_str_drawtext.as_ptr(),
-                                                        );
-                                                        let mut drawtext_ctx = null_mut();
-                                                        let buffersink_filter =
-                                                            avfilter_get_by_name(
-                                                                c_str_buffersink.as_ptr(),
-                                                            );
-                                                        let mut buffersink_ctx = null_mut();
-                                                        let r = avfilter_graph_create_filter(
-                                                            &mut buffersrc_ctx,
-                                                            buffersrc_filter,
-                                                            c_str_buffersrc.as_ptr(),
-                                                            c_str_buffersrc_args.as_ptr(),
-                                                            null_mut(),
-                                                            graph.as_mut_ptr(),
-                                                        );
-                                                        if r < 0 {
-                                                            info!("create buffer filter err");
-                                                        }
-                                                        let r = avfilter_graph_create_filter(
-                                                            &mut drawtext_ctx,
-                                                            draw_filter,
-                                                            c_str_draw.as_ptr(),
-                                                            c_str_draw_args.as_ptr(),
-                                                            null_mut(),
-                                                            graph.as_mut_ptr(),
-                                                        );
-                                                        if r < 0 {
-                                                            info!("create drawtext filter err");
-                                                        }
-                                                        let r = avfilter_graph_create_filter(
-                                                            &mut buffersink_ctx,
-                                                            buffersink_filter,
-                                                            c_str_sink.as_ptr(),
-                                                            null(),
-                                                            null_mut(),
-                                                            graph.as_mut_ptr(),
-                                                        );
-                                                        if r < 0 {
-                                                            info!("create buffersink filter err");
-                                                        }
-                                                        let r = avfilter_link(
-                                                            buffersrc_ctx,
-                                                            0,
-                                                            drawtext_ctx,
-                                                            0,
-                                                        );
-                                                        if r < 0 {
-                                                            info!("link src and drawtext err");
-                                                        }
-                                                        let r = avfilter_link(
-                                                            drawtext_ctx,
-                                                            0,
-                                                            buffersink_ctx,
-                                                            0,
-                                                        );
-                                                        if r < 0 {
-                                                            info!("link drawtext and sink err");
-                                                        }
-                                                        if graph.validate().is_ok() {
-                                                            info!(
-                                                                "graph validate success!dump:\n{}",
-                                                                graph.dump()
-                                                            );
-                                                        }
-                                                    }
-                                                }
-                                            }
-                                        }
-                                    }
-                                }
-                            }
-                        }
-                    }
+                if let Some(font_path_str) = p.to_str() {
+                    // ':' separates the filter options, escape the one of the windows drive
+                    return Some(font_path_str.replace('\\', "/").replace(':', "\\:"));
                 }
             }
         }
-        let hardware_frame_converter = Arc::new(RwLock::new(None));
-        loop {
-            if decode_context
-                .decode_exit_flag
-                .load(std::sync::atomic::Ordering::Relaxed)
-            {
-                break;
-            }
-            if !decode_context
-                .audio_packet_cache_queue
-                .read()
-                .await
-                .is_empty()
-            {
-                let mut audio_packet_cache_vec =
-                    decode_context.audio_packet_cache_queue.write().await;
-                let mut a_frame_vec = decode_context.audio_frame_cache_queue.write().await;
-                let mut audio_decoder = decode_context.audio_decoder.write().await;
-                if audio_packet_cache_vec.len() < 10 {
-                    decode_context.demux_thread_notify.notify_one();
-                }
-                if let Some(front_packet) = audio_packet_cache_vec.pop_front() {
-                    if let Some(decoder) = &mut *audio_decoder {
-                        if decoder.0.send_packet(&front_packet).is_ok() {
-                            loop {
-                                let mut audio_frame_tmp = ffmpeg_the_third::frame::Audio::empty();
-
-                                if decoder.0.receive_frame(&mut audio_frame_tmp).is_err() {
-                                    break;
-                                }
-
-                                a_frame_vec.push_back(audio_frame_tmp);
-                            }
-                        }
-                    }
-                }
-            } else {
-                decode_context.demux_thread_notify.notify_one();
-            }
-            if !decode_context
-                .video_packet_cache_queue
-                .read()
-                .await
-                .is_empty()
-            {
-                let mut video_packet_cache_vec =
-                    decode_context.video_packet_cache_queue.write().await;
-                let mut v_frame_vec = decode_context.video_frame_cache_queue.write().await;
-                let mut v_decoder = decode_context.video_decoder.write().await;
-                // info!("video frame vec len{}", frames.len());
-                if video_packet_cache_vec.len() < 10 {
-                    decode_context.demux_thread_notify.notify_one();
-                }
-                if let Some(front_packet) = video_packet_cache_vec.pop_front() {
-                    if let Some(decoder) = &mut *v_decoder {
-                        if decoder.0.send_packet(&front_packet).is_ok() {
-                            loop {
-                                let mut video_frame_tmp = ffmpeg_the_third::frame::Video::empty();
-
-                                if decoder.0.receive_frame(&mut video_frame_tmp).is_err() {
-                                    break;
-                                }
-
-                                let video_frame = TinyDecoder::convert_hardware_frame(
-                                    decode_context.hardware_config_flag.clone(),
-                                    hardware_frame_converter.clone(),
-                                    video_frame_tmp,
-                                )
-                                .await;
-
-                                if let Some(mut ctx) = graph.get("buffersrc") {
-                                    if ctx.source().add(&video_frame).is_ok() {
-                                        let mut filtered_frame =
-                                            ffmpeg_the_third::frame::Video::empty();
-                                        if let Some(mut ctx) = graph.get("sink") {
-                                            if ctx.sink().frame(&mut filtered_frame).is_ok() {
-                                                v_frame_vec.push_back(filtered_frame);
-                                            }
-                                        }
-                                    }
-                                }
-                            }
-                        }
-                    }
-                }
-            } else {
-                decode_context.demux_thread_notify.notify_one();
+        None
+    }
+    /// check if a decoded frame ends before the accurate seek target
+    /// the target is cleared once a frame reaches it
+    fn is_before_seek_target(seek_target_pts: &mut i64, pts: Option<i64>, duration: i64) -> bool {
+        if *seek_target_pts == i64::MIN {
+            return false;
+        }
+        if let Some(pts) = pts {
+            if pts + duration.max(1) <= *seek_target_pts {
+                return true;
             }
-            decode_context.decode_thread_notify.notified().await;
         }
+        *seek_target_pts = i64::MIN;
+        false
     }
-    /// start the demux and decode task
-    async fn start_process_input(&mut self) {
-        if let Ok(demux_context) = DemuxContextBuilder::default()
+    /// move the input and the decoders to their worker threads and start them
+    fn start_workers(
+        &mut self,
+        format_input: ffmpeg_the_third::format::context::Input,
+        video_decoder: Option<ffmpeg_the_third::decoder::Video>,
+        audio_decoder: Option<ffmpeg_the_third::decoder::Audio>,
+        subtitle_decoder: Option<ffmpeg_the_third::decoder::Subtitle>,
+    ) -> PlayerResult<()> {
+        let serial = self.seek_serial.load(std::sync::atomic::Ordering::Relaxed);
+        let demux_context = DemuxContextBuilder::default()
+            .format_input(format_input)
             .audio_stream_index(self.audio_stream_index)
             .video_stream_index(self.video_stream_index)
-            .format_input(self.format_input.clone())
-            .audio_packet_cache_queue(self.audio_packet_cache_queue.clone())
-            .video_packet_cache_queue(self.video_packet_cache_queue.clone())
+            .subtitle_stream_index(self.subtitle_stream_index.unwrap_or(usize::MAX))
             .cover_stream_index(self.cover_stream_index)
+            .audio_packet_channel(self.audio_packet_channel.clone())
+            .video_packet_channel(self.video_packet_channel.clone())
+            .subtitle_packet_channel(self.subtitle_packet_channel.clone())
             .cover_image_data(self.cover_pic_data.clone())
-            .demux_exit_flag(self.demux_exit_flag.clone())
-            .demux_thread_notify(self.demux_thread_notify.clone())
+            .serial(serial)
             .build()
-        {
-            self.demux_task_handle = Some(self.runtime_handle.spawn(async move {
-                let demux_span = span!(Level::INFO, "demux");
-                let _demux_entered = demux_span.enter();
-                Self::packet_demux_process(demux_context)
-                    .in_current_span()
-                    .await;
-            }));
-        } else {
-            warn!("build demux context error!");
-        }
-
-        if let Ok(decode_context) = DecodeContextBuilder::default()
-            .video_decoder(self.video_decoder.clone())
-            .audio_decoder(self.audio_decoder.clone())
-            .video_frame_cache_queue(self.video_frame_cache_queue.clone())
-            .audio_frame_cache_queue(self.audio_frame_cache_queue.clone())
-            .audio_packet_cache_queue(self.audio_packet_cache_queue.clone())
-            .video_packet_cache_queue(self.video_packet_cache_queue.clone())
+            .map_err(|e| PlayerError::Internal(format!("build demux context error: {}", e)))?;
+        self.audio_decoder_serial
+            .store(serial, std::sync::atomic::Ordering::Relaxed);
+        let audio_context = AudioDecodeContextBuilder::default()
+            .decoder(audio_decoder)
+            .packet_channel(self.audio_packet_channel.clone())
+            .frame_channel(self.audio_frame_channel.clone())
+            .seek_target_pts(self.audio_seek_target_pts.clone())
+            .decoder_serial(self.audio_decoder_serial.clone())
+            .serial(serial)
+            .build()
+            .map_err(|e| {
+                PlayerError::Internal(format!("build audio decode context error: {}", e))
+            })?;
+        let video_context = VideoDecodeContextBuilder::default()
+            .decoder(video_decoder)
+            .packet_channel(self.video_packet_channel.clone())
+            .frame_channel(self.video_frame_channel.clone())
+            .seek_target_pts(self.video_seek_target_pts.clone())
+            .serial(serial)
+            .time_base(self.video_time_base)
+            .filter_chain(self.video_filter_chain.clone())
+            .decode_stats(self.decode_stats.clone())
             .hardware_config_flag(self.hardware_config_flag.clone())
-            .decode_exit_flag(self.decode_exit_flag.clone())
-            .video_time_base(self.video_time_base)
+            .hardware_fallback_pts(self.hardware_fallback_pts.clone())
+            .build()
+            .map_err(|e| {
+                PlayerError::Internal(format!("build video decode context error: {}", e))
+            })?;
+        let subtitle_context = SubtitleDecodeContextBuilder::default()
+            .decoder(subtitle_decoder)
+            .packet_channel(self.subtitle_packet_channel.clone())
+            .subtitle_cues(self.subtitle_cues.clone())
             .video_frame_rect(self.video_frame_rect)
-            .decode_thread_notify(self.decode_thread_notify.clone())
-            .demux_thread_notify(self.demux_thread_notify.clone())
+            .serial(serial)
             .build()
-        {
-            self.decode_task_handle = Some(self.runtime_handle.spawn(async move {
-                let demux_span = span!(Level::INFO, "decode");
-                let _demux_entered = demux_span.enter();
-                Self::frame_decode_process(decode_context)
-                    .in_current_span()
-                    .await;
-            }));
-        } else {
-            warn!("build decode context error!");
+            .map_err(|e| {
+                PlayerError::Internal(format!("build subtitle decode context error: {}", e))
+            })?;
+
+        let (demux_command_sender, demux_command_receiver) = std::sync::mpsc::channel();
+        let (audio_command_sender, audio_command_receiver) = std::sync::mpsc::channel();
+        let (video_command_sender, video_command_receiver) = std::sync::mpsc::channel();
+        let (subtitle_command_sender, subtitle_command_receiver) = std::sync::mpsc::channel();
+        // one frame is converted at a time, the present task waits for it
+        let (convert_request_sender, convert_request_receiver) = std::sync::mpsc::sync_channel(1);
+        let thread_handles = vec![
+            Self::spawn_worker("demux", move || {
+                let _entered = span!(Level::INFO, "demux").entered();
+                run_pipeline_worker(&mut DemuxWorker::new(demux_context), demux_command_receiver);
+            })?,
+            Self::spawn_worker("audio decode", move || {
+                let _entered = span!(Level::INFO, "audio decode").entered();
+                // the resampler is created here, it never leaves this thread
+                run_pipeline_worker(
+                    &mut AudioDecodeWorker::new(audio_context),
+                    audio_command_receiver,
+                );
+            })?,
+            Self::spawn_worker("video decode", move || {
+                let _entered = span!(Level::INFO, "video decode").entered();
+                run_pipeline_worker(
+                    &mut VideoDecodeWorker::new(video_context),
+                    video_command_receiver,
+                );
+            })?,
+            Self::spawn_worker("subtitle decode", move || {
+                let _entered = span!(Level::INFO, "subtitle decode").entered();
+                run_pipeline_worker(
+                    &mut SubtitleDecodeWorker::new(subtitle_context),
+                    subtitle_command_receiver,
+                );
+            })?,
+            Self::spawn_worker("video convert", move || {
+                let _entered = span!(Level::INFO, "video convert").entered();
+                VideoConverter::default().run(convert_request_receiver);
+            })?,
+        ];
+        self.workers = Some(DecodeWorkers {
+            demux_command_sender,
+            audio_command_sender,
+            video_command_sender,
+            subtitle_command_sender,
+            convert_request_sender,
+            thread_handles,
+        });
+        Ok(())
+    }
+    fn spawn_worker(
+        name: &str,
+        work: impl FnOnce() + Send + 'static,
+    ) -> PlayerResult<JoinHandle<()>> {
+        std::thread::Builder::new()
+            .name(name.to_string())
+            .spawn(work)
+            .map_err(|e| PlayerError::Internal(format!("spawn {} thread err: {}", name, e)))
+    }
+    /// send a command to one worker of the input, nothing is done without input
+    fn send_command<C>(&self, sender: impl FnOnce(&DecodeWorkers) -> &Sender<C>, command: C) {
+        if let Some(workers) = &self.workers {
+            if sender(workers).send(command).is_err() {
+                warn!("the worker thread exited before the command was sent");
+            }
         }
     }
-    /// called by the main thread pull one audio frame from the queue
-    /// in addition, do the resample
-    pub async fn pull_one_audio_play_frame(&mut self) -> Option<ffmpeg_the_third::frame::Audio> {
-        if let Some(resampler_ctx) = &mut self.resampler_ctx {
-            let mut res = ffmpeg_the_third::frame::Audio::empty();
-            res.set_format(ffmpeg_the_third::format::Sample::F32(Type::Packed));
-            res.set_ch_layout(ChannelLayout::STEREO);
-            res.set_rate(48000);
-
-            {
-                let mut a_frame_vec = self.audio_frame_cache_queue.write().await;
-                if a_frame_vec.len() < 10 {
-                    self.decode_thread_notify.notify_one();
-                }
-                if !a_frame_vec.is_empty() {
-                    if let Some(raw_frame) = a_frame_vec.pop_front() {
-                        unsafe {
-                            let r = swr_convert_frame(
-                                resampler_ctx.0,
-                                res.as_mut_ptr(),
-                                raw_frame.as_ptr(),
-                            );
-                            if r == 0 {
-                                if let Some(pts) = raw_frame.pts() {
-                                    res.set_pts(Some(pts));
-                                    res.set_rate(48000);
-                                    return Some(res);
-                                }
-                            } else {
-                                info!("resample err{}", r);
-                            }
-                        }
-                    }
-                }
+    /// ask the demux thread, the owner of the input, for something made from the input
+    async fn request_from_demux<R>(
+        &self,
+        command: impl FnOnce(oneshot::Sender<PlayerResult<R>>) -> DemuxCommand,
+    ) -> PlayerResult<R> {
+        let workers = self.workers.as_ref().ok_or(PlayerError::NoInput)?;
+        let (reply_sender, reply_receiver) = oneshot::channel();
+        workers
+            .demux_command_sender
+            .send(command(reply_sender))
+            .map_err(|_e| PlayerError::Internal("demux thread exited".to_string()))?;
+        reply_receiver
+            .await
+            .map_err(|_e| PlayerError::Internal("demux thread exited".to_string()))?
+    }
+    /// called by the present task to pull one resampled audio frame from the channel
+    /// and stretch it by the atempo filter when the speed is not 1x
+    pub fn pull_one_audio_play_frame(&mut self) -> Option<ffmpeg_the_third::frame::Audio> {
+        if self
+            .audio_tempo_flush_flag
+            .swap(false, std::sync::atomic::Ordering::Relaxed)
+        {
+            // drop the audio buffered in the filter before the seek
+            self.audio_tempo_frame_queue.clear();
+            if let Err(e) = self.set_playback_speed(self.playback_speed) {
+                warn!("rebuild audio tempo filter err {}", e);
             }
         }
-
-        None
+        if let Some(frame) = self.audio_tempo_frame_queue.pop_front() {
+            return Some(frame);
+        }
+        if self.audio_frame_channel.is_drained() {
+            // the filter is rebuilt by the next seek
+            if let Some(mut audio_tempo) = self.audio_tempo.take() {
+                self.audio_tempo_frame_queue.extend(audio_tempo.drain());
+                return self.audio_tempo_frame_queue.pop_front();
+            }
+            return None;
+        }
+        let (audio_frame, _) = self.audio_frame_channel.try_recv()?;
+        if let Some(audio_tempo) = &mut self.audio_tempo {
+            self.audio_tempo_frame_queue
+                .extend(audio_tempo.filter(&audio_frame));
+            return self.audio_tempo_frame_queue.pop_front();
+        }
+        Some(audio_frame)
     }
     pub async fn _convert_frame_data_to_no_padding_layout(res: &mut Video) -> Box<[u8]> {
         unsafe {
@@ -733,56 +834,308 @@ impl TinyDecoder {
         }
     }
 
-    /// pull one frame from the video cache queue
-    /// in additon, do the convert and if the input changed(caused by source or hard acce)
-    /// set the new converter, only change the out put format, dont change the width and height which
-    /// have been used in the ui thread
-    pub async fn pull_one_video_play_frame(&mut self) -> Option<ffmpeg_the_third::frame::Video> {
-        if let Some(converter_ctx) = &mut self.converter_ctx {
-            let mut res = ffmpeg_the_third::frame::Video::empty();
-
-            let mut return_val = None;
-            {
-                let mut v_frame_vec = self.video_frame_cache_queue.write().await;
-                if v_frame_vec.len() < 5 {
-                    self.decode_thread_notify.notify_one();
-                }
-                if !v_frame_vec.is_empty() {
-                    if let Some(raw_frame) = v_frame_vec.pop_front() {
-                        // info!("raw frame pts{}", raw_frame.pts().unwrap());
-                        unsafe {
-                            let frame = res.as_mut_ptr();
-                            (*frame).width = raw_frame.width() as i32;
-                            (*frame).height = raw_frame.height() as i32;
-                            (*frame).format = AVPixelFormat::AV_PIX_FMT_RGBA as i32;
-
-                            // align to 256 to meet the wgpu requirement
-                            let align = 256;
-                            if 0 > av_frame_get_buffer(frame, align) {
-                                warn!("av_frame_get_buffer error!");
-                            }
-                        }
-                        if converter_ctx.0.run(&raw_frame, &mut res).is_ok() {
-                            if let Some(pts) = raw_frame.pts() {
-                                res.set_pts(Some(pts));
-                            }
-
-                            return_val = Some(res);
-                        }
+    /// pull one frame from the video channel and let the convert thread turn it to rgba
+    /// the output keeps the width and height which have been used in the ui thread
+    /// with the gpu renderer the frames the shader reads are given as they are decoded,
+    /// their color tags are replaced by the resolved matrix and range
+    pub async fn pull_one_video_play_frame(&mut self) -> Option<VideoPlayFrame> {
+        if self.video_stream_index == usize::MAX {
+            return None;
+        }
+        let (mut raw_frame, _) = self.video_frame_channel.try_recv()?;
+        let (colorspace, full_range) = self.frame_color_details(&raw_frame);
+        if self.video_renderer == VideoRenderer::GpuYuv && yuv_render::can_draw(&raw_frame) {
+            raw_frame.set_color_space(colorspace);
+            raw_frame.set_color_range(if full_range { Range::JPEG } else { Range::MPEG });
+            return Some(VideoPlayFrame::Yuv(raw_frame));
+        }
+        let workers = self.workers.as_ref()?;
+        let (reply_sender, reply_receiver) = oneshot::channel();
+        let request = ConvertRequest {
+            frame: raw_frame,
+            output_rect: self.video_frame_rect,
+            color_details: (AVColorSpace::from(colorspace) as i32, full_range),
+            reply: reply_sender,
+        };
+        if workers.convert_request_sender.send(request).is_err() {
+            warn!("video convert thread exited");
+            return None;
+        }
+        Some(VideoPlayFrame::Converting(reply_receiver))
+    }
+    /// the color matrix and if the source is full range for one frame
+    /// the user override wins over the frame tags,
+    /// untagged frames are treated as bt709 for hd and bt601 for sd
+    /// the swscale coefficients ids share the values of the matrix
+    fn frame_color_details(&self, frame: &Video) -> (Space, bool) {
+        let colorspace = match self.color_matrix_override {
+            ColorMatrixOverride::Bt601 => Space::BT470BG,
+            ColorMatrixOverride::Bt709 => Space::BT709,
+            ColorMatrixOverride::Bt2020 => Space::BT2020NCL,
+            ColorMatrixOverride::Auto => match frame.color_space() {
+                Space::Unspecified | Space::Reserved => {
+                    if frame.height() >= 720 {
+                        Space::BT709
+                    } else {
+                        Space::BT470BG
                     }
                 }
+                space => space,
+            },
+        };
+        let full_range = match self.color_range_override {
+            ColorRangeOverride::Limited => false,
+            ColorRangeOverride::Full => true,
+            ColorRangeOverride::Auto => {
+                frame.color_range() == Range::JPEG
+                    || matches!(
+                        frame.format(),
+                        Pixel::YUVJ420P | Pixel::YUVJ422P | Pixel::YUVJ444P | Pixel::YUVJ440P
+                    )
+            }
+        };
+        (colorspace, full_range)
+    }
+    /// choose if the frames are turned to rgb by swscale or by the shader of the ui
+    pub fn set_video_renderer(&mut self, renderer: VideoRenderer) {
+        info!("video renderer changed to {:?}", renderer);
+        self.video_renderer = renderer;
+    }
+    pub fn video_renderer(&self) -> VideoRenderer {
+        self.video_renderer
+    }
+    /// force the color matrix and range for files with broken color tags
+    pub fn set_color_override(&mut self, matrix: ColorMatrixOverride, range: ColorRangeOverride) {
+        info!("color override changed to {:?} {:?}", matrix, range);
+        self.color_matrix_override = matrix;
+        self.color_range_override = range;
+    }
+    pub fn color_matrix_override(&self) -> ColorMatrixOverride {
+        self.color_matrix_override
+    }
+    pub fn color_range_override(&self) -> ColorRangeOverride {
+        self.color_range_override
+    }
+    /// change the hardware policy and reopen the video decoder with it
+    /// `current_ts` is the main stream timestamp being played, the input is seeked back to it
+    pub async fn set_hardware_policy(
+        &mut self,
+        policy: HardwarePolicy,
+        current_ts: i64,
+    ) -> PlayerResult<()> {
+        info!("hardware policy changed to {:?}", policy);
+        self.hardware_policy = policy;
+        self.reopen_video_decoder(policy, current_ts).await
+    }
+    pub fn hardware_policy(&self) -> HardwarePolicy {
+        self.hardware_policy
+    }
+    /// change the threading of the decoders and reopen them with it
+    /// `current_ts` is the main stream timestamp being played, the input is seeked back to it
+    pub async fn set_decoder_threading(
+        &mut self,
+        video_threading: DecoderThreading,
+        audio_threading: DecoderThreading,
+        current_ts: i64,
+    ) -> PlayerResult<()> {
+        info!(
+            "decoder threading changed, video:{:?} audio:{:?}",
+            video_threading, audio_threading
+        );
+        self.video_threading = video_threading;
+        self.audio_threading = audio_threading;
+        self.reopen_audio_decoder().await?;
+        if self.video_stream_index == usize::MAX {
+            self.seek_timestamp_to_decode(current_ts, SeekMode::Accurate)
+                .await;
+            Ok(())
+        } else {
+            self.reopen_video_decoder(self.hardware_policy, current_ts)
+                .await
+        }
+    }
+    /// replace the audio decoder of the playing track, the caller seeks the input after it
+    async fn reopen_audio_decoder(&mut self) -> PlayerResult<()> {
+        let stream_index = self.audio_stream_index;
+        if stream_index == usize::MAX {
+            return Ok(());
+        }
+        let threading = self.audio_threading;
+        let (audio_decoder, _, _) = self
+            .request_from_demux(|reply| DemuxCommand::OpenAudioDecoder {
+                stream_index,
+                threading,
+                reply,
+            })
+            .await?;
+        // same track and codec, it is used from the current timeline
+        let serial = self.audio_frame_channel.serial();
+        self.send_command(
+            |workers| &workers.audio_command_sender,
+            AudioDecodeCommand::SetDecoder(audio_decoder, serial),
+        );
+        Ok(())
+    }
+    /// text of the active video decode path shown in the ui
+    pub fn video_decode_path(&self) -> String {
+        if self.video_stream_index == usize::MAX {
+            return "no video".to_string();
+        }
+        if let Some(device_type) = self.hardware_device_type {
+            if self
+                .hardware_config_flag
+                .load(std::sync::atomic::Ordering::Relaxed)
+            {
+                return format!("hardware({})", Self::hardware_device_name(device_type));
             }
-            return return_val;
         }
-        None
+        "software".to_string()
+    }
+    /// replace the video decoder of the current input and seek back to `current_ts`
+    async fn reopen_video_decoder(
+        &mut self,
+        policy: HardwarePolicy,
+        current_ts: i64,
+    ) -> PlayerResult<()> {
+        if self.video_stream_index == usize::MAX {
+            return Ok(());
+        }
+        self.replace_video_decoder(policy).await?;
+        self.seek_timestamp_to_decode(current_ts, SeekMode::Accurate)
+            .await;
+        Ok(())
+    }
+    /// open a new video decoder with `policy` for the video thread, the caller seeks after it
+    async fn replace_video_decoder(&mut self, policy: HardwarePolicy) -> PlayerResult<()> {
+        let stream_index = self.video_stream_index;
+        let threading = self.video_threading;
+        let (video_decoder, hardware_device_type) = self
+            .request_from_demux(|reply| DemuxCommand::OpenVideoDecoder {
+                stream_index,
+                policy,
+                threading,
+                reply,
+            })
+            .await?;
+        self.send_command(
+            |workers| &workers.video_command_sender,
+            VideoDecodeCommand::SetDecoder(video_decoder),
+        );
+        self.video_skip_non_ref = false;
+        self.set_hardware_device_type(hardware_device_type);
+        self.hardware_fallback_pts
+            .store(i64::MIN, std::sync::atomic::Ordering::Relaxed);
+        Ok(())
+    }
+    /// convert a main stream timestamp to milliseconds
+    pub fn main_stream_ts_to_ms(&self, ts: i64) -> i64 {
+        let time_base = if let MainStream::Audio = self.main_stream {
+            &self.audio_time_base
+        } else {
+            &self.video_time_base
+        };
+        ts * 1000 * time_base.numerator() as i64 / time_base.denominator() as i64
+    }
+    /// inverse of `main_stream_ts_to_ms`
+    pub fn ms_to_main_stream_ts(&self, ms: i64) -> i64 {
+        let time_base = if let MainStream::Audio = self.main_stream {
+            &self.audio_time_base
+        } else {
+            &self.video_time_base
+        };
+        ms * time_base.denominator() as i64 / time_base.numerator() as i64 / 1000
+    }
+    pub fn video_ts_to_ms(&self, ts: i64) -> f64 {
+        ts as f64 * 1000.0 * self.video_time_base.numerator() as f64
+            / self.video_time_base.denominator() as f64
+    }
+    pub fn audio_ts_to_ms(&self, ts: i64) -> f64 {
+        ts as f64 * 1000.0 * self.audio_time_base.numerator() as f64
+            / self.audio_time_base.denominator() as f64
+    }
+    /// the duration of one frame given by the frame rate
+    pub fn video_frame_duration_ms(&self) -> f64 {
+        self.video_ts_to_ms(self.video_frame_duration)
+    }
+    /// the main stream timestamp of the first hardware frame the decode thread failed
+    /// to transfer, the engine falls back to software decoding and seeks back to it
+    pub fn take_hardware_fallback_ts(&self) -> Option<i64> {
+        let hardware_fallback_pts = self
+            .hardware_fallback_pts
+            .swap(i64::MIN, std::sync::atomic::Ordering::Relaxed);
+        if hardware_fallback_pts == i64::MIN {
+            return None;
+        }
+        Some(self.video_pts_to_main_ts(hardware_fallback_pts))
+    }
+    /// reopen the video decoder in software mode, the caller seeks back to the failed frame
+    pub async fn fall_back_to_software_decoding(&mut self) -> PlayerResult<()> {
+        warn!("hardware decoding failed, fallback to software decoding");
+        if self.video_stream_index == usize::MAX {
+            return Ok(());
+        }
+        self.replace_video_decoder(HardwarePolicy::Software).await
+    }
+    pub fn video_codec_name(&self) -> &String {
+        &self.video_codec_name
+    }
+    pub fn audio_codec_name(&self) -> &String {
+        &self.audio_codec_name
+    }
+    /// the average frame rate of the video stream
+    pub fn video_frame_rate(&self) -> f64 {
+        self.video_frame_rate
+    }
+    /// decode time and bitrate measured by the decode thread
+    pub async fn decode_stats(&self) -> DecodeStats {
+        *self.decode_stats.read().await
+    }
+    /// pts of a queued video frame, index 0 is the one the next
+    /// `pull_one_video_play_frame` gives
+    pub fn queued_video_frame_pts(&self, index: usize) -> Option<i64> {
+        self.video_frame_channel
+            .peek(index, |frame| frame.pts().or(frame.timestamp()))
+            .flatten()
+    }
+    /// drop the next video frame before any convert work is done on it
+    pub fn drop_one_video_frame(&self) {
+        self.video_frame_channel.try_recv();
+    }
+    /// let the video decoder skip the non-reference frames, used when the
+    /// video lags far behind the clock, the frames after them are not damaged
+    pub fn set_video_skip_non_ref(&mut self, skip: bool) {
+        if skip == self.video_skip_non_ref || self.video_stream_index == usize::MAX {
+            return;
+        }
+        info!("video decoder skip non-reference frames: {}", skip);
+        self.send_command(
+            |workers| &workers.video_command_sender,
+            VideoDecodeCommand::SetSkipNonRef(skip),
+        );
+        self.video_skip_non_ref = skip;
+    }
+    /// changed every time the input is seeked or replaced, the frames pulled after
+    /// a change start a new timeline
+    pub fn seek_serial(&self) -> usize {
+        self.seek_serial.load(std::sync::atomic::Ordering::Relaxed)
     }
-    /// get v time base used to check time and compare to sync
-    pub fn video_time_base(&self) -> &Rational {
-        &self.video_time_base
+    /// start a new timeline, every item of the older ones is dropped
+    fn next_seek_serial(&self) -> usize {
+        self.seek_serial
+            .fetch_add(1, std::sync::atomic::Ordering::Relaxed)
+            + 1
     }
-    /// get a time base used to check time and compare to sync
-    pub fn audio_time_base(&self) -> &Rational {
-        &self.audio_time_base
+    /// empty every channel of the pipeline and move them to the timeline of `serial`
+    fn flush_channels(&self, serial: usize) {
+        self.audio_packet_channel.flush(serial);
+        self.video_packet_channel.flush(serial);
+        self.subtitle_packet_channel.flush(serial);
+        self.audio_frame_channel.flush(serial);
+        self.video_frame_channel.flush(serial);
+    }
+    /// the start time of the input, subtitle files beside the video are relative to it
+    pub fn start_time_ms(&self) -> i64 {
+        self.start_time_ms
     }
     /// get the calculated end time str
     pub fn end_time_formatted_string(&self) -> &String {
@@ -797,84 +1150,210 @@ impl TinyDecoder {
     pub fn end_ts(&self) -> i64 {
         self.end_timestamp
     }
-    /// seek the input to a selected timestamp
-    /// use the ffi function to enable seek all the frames
-    /// the ffmpeg_the_third::ffi::AVSEEK_FLAG_ANY flag makes sure
-    /// the seek would go as I want, to an exact frame
-    pub fn seek_timestamp_to_decode(&self, ts: i64) {
-        {
-            let mut audio_packet_cache_vec = self
-                .runtime_handle
-                .block_on(self.audio_packet_cache_queue.write());
-            let mut video_packet_cache_vec = self
-                .runtime_handle
-                .block_on(self.video_packet_cache_queue.write());
-            let mut audio_cache_vec = self
-                .runtime_handle
-                .block_on(self.audio_frame_cache_queue.write());
-            let mut video_cache_vec = self
-                .runtime_handle
-                .block_on(self.video_frame_cache_queue.write());
-            audio_packet_cache_vec.clear();
-            video_packet_cache_vec.clear();
-            audio_cache_vec.clear();
-            video_cache_vec.clear();
-
-            let mut input = self.runtime_handle.block_on(self.format_input.write());
-            let main_stream_idx = {
-                if let MainStream::Audio = self.main_stream {
-                    self.audio_stream_index
-                } else {
-                    self.video_stream_index
-                }
-            };
-            let main_stream_time_base = {
-                if let MainStream::Audio = self.main_stream {
-                    &self.audio_time_base
-                } else {
-                    &self.video_time_base
-                }
-            };
-            unsafe {
-                info!("seek timestamp:{}", ts);
-                if let Some(input) = &mut *input {
-                    let res = ffmpeg_the_third::ffi::avformat_seek_file(
-                        input.0.as_mut_ptr(),
-                        main_stream_idx as i32,
-                        ts - main_stream_time_base.denominator() as i64
-                            / main_stream_time_base.numerator() as i64,
-                        ts,
-                        ts + main_stream_time_base.denominator() as i64
-                            / main_stream_time_base.numerator() as i64,
-                        AVSEEK_FLAG_BACKWARD,
-                    );
-                    if res != 0 {
-                        info!("seek err num:{res}");
-                    }
-                }
-            }
-            self.runtime_handle.block_on(self.flush_decoders());
+    /// change the play speed, 0.25x to 4x
+    /// the audio is stretched by the atempo filter so the pitch is kept
+    pub fn set_playback_speed(&mut self, speed: f64) -> PlayerResult<()> {
+        let speed = speed.clamp(0.25, 4.0);
+        self.audio_tempo = if (speed - 1.0).abs() < 0.001 {
+            None
+        } else {
+            Some(AudioTempo::new(speed, self.audio_time_base)?)
+        };
+        self.audio_tempo_frame_queue.clear();
+        self.playback_speed = speed;
+        info!("playback speed changed to {}", speed);
+        Ok(())
+    }
+    /// the play speed, 1.0 means normal speed
+    pub fn playback_speed(&self) -> f64 {
+        self.playback_speed
+    }
+    /// change the duration and bytes budgets of the packet and frame channels
+    pub fn set_buffer_limits(&self, limits: BufferLimits) {
+        info!("buffer limits changed to {:?}", limits);
+        self.audio_packet_channel.set_limit(limits.audio_packets);
+        self.video_packet_channel.set_limit(limits.video_packets);
+        self.audio_frame_channel.set_limit(limits.audio_frames);
+        self.video_frame_channel.set_limit(limits.video_frames);
+    }
+    /// how much every packet and frame channel holds now
+    pub fn buffer_health(&self) -> BufferHealth {
+        BufferHealth {
+            audio_packets: self.audio_packet_channel.health(),
+            video_packets: self.video_packet_channel.health(),
+            audio_frames: self.audio_frame_channel.health(),
+            video_frames: self.video_frame_channel.health(),
         }
     }
-    /// use the file detail to compute the video duration and make str to inform the user
-    fn compute_and_set_end_time_str(&mut self, end_ts: i64) {
-        let sec_num = {
+    /// replace the video filter chain, a chain ffmpeg can't parse is refused
+    /// the decode thread rebuilds its filter graph before the next decoded frame
+    pub fn set_video_filter_chain(&mut self, chain: VideoFilterChain) -> PlayerResult<()> {
+        chain.validate()?;
+        info!("video filter chain changed to {}", chain.filters);
+        self.video_filter_chain = chain.clone();
+        self.send_command(
+            |workers| &workers.video_command_sender,
+            VideoDecodeCommand::SetFilterChain(chain),
+        );
+        Ok(())
+    }
+    /// convert a video frame pts to the main stream timestamp
+    pub fn video_pts_to_main_ts(&self, video_pts: i64) -> i64 {
+        if let MainStream::Audio = self.main_stream {
+            video_pts
+                * self.video_time_base.numerator() as i64
+                * self.audio_time_base.denominator() as i64
+                / self.video_time_base.denominator() as i64
+                / self.audio_time_base.numerator() as i64
+        } else {
+            video_pts
+        }
+    }
+    /// seek to the frame before the video frame being shown, the next pulled
+    /// video frame is the previous one
+    pub async fn seek_to_previous_video_frame(&self, current_video_pts: i64) {
+        // aim at the middle of the previous frame so the rounding of the
+        // time base convert can't pick a wrong frame
+        let target_video_pts = current_video_pts - (self.video_frame_duration + 1) / 2;
+        self.seek_timestamp_to_decode(
+            self.video_pts_to_main_ts(target_video_pts),
+            SeekMode::Accurate,
+        )
+        .await;
+    }
+    /// seek the input to a selected timestamp of the main stream
+    /// `SeekMode::Fast` starts from the keyframe near the timestamp
+    /// `SeekMode::Accurate` starts from the keyframe before the timestamp and the
+    /// decode threads drop the frames until the timestamp is reached
+    /// a new timeline is started, the channels are emptied and the demux thread seeks the input,
+    /// the decode threads flush their decoders once they see the new timeline
+    pub async fn seek_timestamp_to_decode(&self, ts: i64, seek_mode: SeekMode) {
+        self.seek_with_audio_decoder(ts, seek_mode, None).await;
+    }
+    /// `seek_timestamp_to_decode` which also replaces the audio decoder from the new timeline on
+    /// the audio thread waits for the decoder before it decodes a packet of that timeline
+    async fn seek_with_audio_decoder(
+        &self,
+        ts: i64,
+        seek_mode: SeekMode,
+        audio_decoder: Option<ffmpeg_the_third::decoder::Audio>,
+    ) {
+        let main_stream_idx = {
             if let MainStream::Audio = self.main_stream {
-                end_ts * self.audio_time_base.numerator() as i64
-                    / self.audio_time_base.denominator() as i64
+                self.audio_stream_index
             } else {
-                end_ts * self.video_time_base.numerator() as i64
-                    / self.video_time_base.denominator() as i64
+                self.video_stream_index
             }
         };
-        let sec = (sec_num % 60) as u8;
-        let min_num = sec_num / 60;
-        let min = (min_num % 60) as u8;
-        let hour_num = min_num / 60;
-        let hour = hour_num as u8;
-        info!("hour{},min{},sec{}", hour, min, sec);
-        if let Ok(time) = time::Time::from_hms(hour, min, sec) {
-            if let Ok(formatter) = format_description::parse("[hour]:[minute]:[second]") {
+        let main_stream_time_base = {
+            if let MainStream::Audio = self.main_stream {
+                &self.audio_time_base
+            } else {
+                &self.video_time_base
+            }
+        };
+        let (video_target, audio_target) = if let SeekMode::Accurate = seek_mode {
+            if let MainStream::Audio = self.main_stream {
+                (
+                    ts * self.audio_time_base.numerator() as i64
+                        * self.video_time_base.denominator() as i64
+                        / self.audio_time_base.denominator() as i64
+                        / self.video_time_base.numerator() as i64,
+                    ts,
+                )
+            } else {
+                (
+                    ts,
+                    ts * self.video_time_base.numerator() as i64
+                        * self.audio_time_base.denominator() as i64
+                        / self.video_time_base.denominator() as i64
+                        / self.audio_time_base.numerator() as i64,
+                )
+            }
+        } else {
+            (i64::MIN, i64::MIN)
+        };
+        let (seek_stream_index, seek_ts, min_ts, max_ts) = if let SeekMode::Accurate = seek_mode {
+            // the keyframe must not be after the timestamp, or the frames
+            // between them can never be reached. the audio packets don't tell where
+            // the video keyframes are, so the video stream is seeked whenever there is one
+            if self.video_stream_index != usize::MAX {
+                (
+                    self.video_stream_index,
+                    video_target,
+                    i64::MIN,
+                    video_target,
+                )
+            } else {
+                (main_stream_idx, ts, i64::MIN, ts)
+            }
+        } else {
+            (
+                main_stream_idx,
+                ts,
+                ts - main_stream_time_base.denominator() as i64
+                    / main_stream_time_base.numerator() as i64,
+                ts + main_stream_time_base.denominator() as i64
+                    / main_stream_time_base.numerator() as i64,
+            )
+        };
+        // the targets are read by the decode threads when they follow the new serial
+        self.video_seek_target_pts
+            .store(video_target, std::sync::atomic::Ordering::Relaxed);
+        self.audio_seek_target_pts
+            .store(audio_target, std::sync::atomic::Ordering::Relaxed);
+        let serial = self.next_seek_serial();
+        self.flush_channels(serial);
+        {
+            // the cues around the target are kept so a step back doesn't blank the
+            // subtitle, the copies decoded again after the seek are skipped by `push`
+            let target_ms = self.main_stream_ts_to_ms(ts);
+            let mut subtitle_cues = self.subtitle_cues.write().await;
+            if !subtitle_cues.spans(target_ms) {
+                subtitle_cues.clear();
+            }
+        }
+        self.audio_tempo_flush_flag
+            .store(true, std::sync::atomic::Ordering::Relaxed);
+        if let Some(audio_decoder) = audio_decoder {
+            self.audio_decoder_serial
+                .store(serial, std::sync::atomic::Ordering::Relaxed);
+            self.send_command(
+                |workers| &workers.audio_command_sender,
+                AudioDecodeCommand::SetDecoder(audio_decoder, serial),
+            );
+        }
+        info!("seek timestamp:{} mode:{:?}", ts, seek_mode);
+        self.send_command(
+            |workers| &workers.demux_command_sender,
+            DemuxCommand::Seek {
+                stream_index: seek_stream_index,
+                min_ts,
+                ts: seek_ts,
+                max_ts,
+                serial,
+            },
+        );
+    }
+    /// use the file detail to compute the video duration and make str to inform the user
+    fn compute_and_set_end_time_str(&mut self, end_ts: i64) {
+        let sec_num = {
+            if let MainStream::Audio = self.main_stream {
+                end_ts * self.audio_time_base.numerator() as i64
+                    / self.audio_time_base.denominator() as i64
+            } else {
+                end_ts * self.video_time_base.numerator() as i64
+                    / self.video_time_base.denominator() as i64
+            }
+        };
+        let sec = (sec_num % 60) as u8;
+        let min_num = sec_num / 60;
+        let min = (min_num % 60) as u8;
+        let hour_num = min_num / 60;
+        let hour = hour_num as u8;
+        info!("hour{},min{},sec{}", hour, min, sec);
+        if let Ok(time) = time::Time::from_hms(hour, min, sec) {
+            if let Ok(formatter) = format_description::parse("[hour]:[minute]:[second]") {
                 if let Ok(s) = time.format(&formatter) {
                     self.end_time_formatted_string = s;
                 }
@@ -887,11 +1366,6 @@ impl TinyDecoder {
     pub fn cover_pic_data(&self) -> Arc<RwLock<Option<Vec<u8>>>> {
         self.cover_pic_data.clone()
     }
-    /// determin if the input is exist
-    pub async fn is_input_exist(&self) -> bool {
-        let input = self.format_input.write().await;
-        input.is_some()
-    }
     /// read the mainstream
     pub fn main_stream(&self) -> &MainStream {
         &self.main_stream
@@ -900,142 +1374,1629 @@ impl TinyDecoder {
     pub fn _video_stream_idx(&self) -> usize {
         self.video_stream_index
     }
-    /// stop demux and decode
-    async fn stop_demux_and_decode(&mut self) {
-        self.demux_exit_flag
-            .store(true, std::sync::atomic::Ordering::Relaxed);
-        self.demux_thread_notify.notify_one();
-        if let Some(handle) = &mut self.demux_task_handle {
-            if handle.await.is_ok() {
-                info!("demux thread join success");
-            }
-        }
-        self.decode_exit_flag
-            .store(true, std::sync::atomic::Ordering::Relaxed);
-        self.decode_thread_notify.notify_one();
-        if let Some(handle) = &mut self.decode_task_handle {
-            if handle.await.is_ok() {
-                info!("decode thread join success");
+    /// close the command channels so the worker threads exit, then wait for them
+    /// the input and the ffmpeg contexts are freed by the threads owning them
+    fn stop_workers(&mut self) {
+        if let Some(workers) = self.workers.take() {
+            let DecodeWorkers {
+                demux_command_sender,
+                audio_command_sender,
+                video_command_sender,
+                subtitle_command_sender,
+                convert_request_sender,
+                thread_handles,
+            } = workers;
+            drop((
+                demux_command_sender,
+                audio_command_sender,
+                video_command_sender,
+                subtitle_command_sender,
+                convert_request_sender,
+            ));
+            for handle in thread_handles {
+                let name = handle.thread().name().unwrap_or("worker").to_string();
+                if handle.join().is_err() {
+                    warn!("{} thread panicked", name);
+                }
             }
+            info!("demux and decode threads exit gracefully");
         }
     }
-    /// flush decoder , be called after seek file is done
-    async fn flush_decoders(&self) {
-        let mut a_decoder = self.audio_decoder.write().await;
-        if let Some(a) = &mut *a_decoder {
-            a.0.flush();
-        }
-        let mut v_decoder = self.video_decoder.write().await;
-        if let Some(v) = &mut *v_decoder {
-            v.0.flush();
+    /// true once every packet of the input is read
+    pub fn is_demux_ended(&self) -> bool {
+        self.video_packet_channel.is_finished()
+    }
+    /// true once the main stream is decoded to the end and all its frames are pulled
+    pub fn is_main_stream_ended(&self) -> bool {
+        if let MainStream::Audio = self.main_stream {
+            self.audio_frame_channel.is_drained() && self.audio_tempo_frame_queue.is_empty()
+        } else {
+            self.is_video_ended()
         }
     }
+    /// true once the video is decoded to the end and all its frames are pulled
+    pub fn is_video_ended(&self) -> bool {
+        self.video_frame_channel.is_drained()
+    }
 }
 
 impl TinyDecoder {
-    /// enable hardware accelerate for video decode, currently use d3d12 only on windows
-    /// others like vulkan are in developing
-    /// fallback to softerware decoder if doesnt support
-    async fn choose_decoder_with_hardware_prefer(
-        &mut self,
+    /// open the video decoder with the hardware device chosen by the policy
+    /// every hardware config of the codec is tried in order,
+    /// fallback to software decoder if none of them works
+    fn choose_decoder_with_hardware_prefer(
         stream: &Stream<'_>,
-    ) -> PlayerResult<ffmpeg_the_third::decoder::Video> {
-        let codec_ctx = ffmpeg_the_third::codec::Context::from_parameters(stream.parameters())
-            .map_err(|e| PlayerError::Internal(e.to_string()))?;
+        policy: HardwarePolicy,
+        threading: DecoderThreading,
+    ) -> PlayerResult<(ffmpeg_the_third::decoder::Video, Option<AVHWDeviceType>)> {
+        let mut codec_ctx = ffmpeg_the_third::codec::Context::from_parameters(stream.parameters())
+            .map_err(Self::codec_error(stream))?;
+        let pixels = unsafe {
+            let parameters = stream.parameters().as_ptr();
+            (*parameters).width.max(0) as u64 * (*parameters).height.max(0) as u64
+        };
+        threading.apply(&mut codec_ctx, pixels);
 
         let mut decoder = codec_ctx
             .decoder()
             .video()
-            .map_err(|e| PlayerError::Internal(e.to_string()))?;
+            .map_err(Self::codec_error(stream))?;
+        if let HardwarePolicy::Software = policy {
+            info!("software decoding is forced");
+            return Ok((decoder, None));
+        }
         unsafe {
             if let Some(codec) = &decoder.codec() {
-                let hw_config = avcodec_get_hw_config(codec.as_ptr(), 0);
-
-                if hw_config.is_null() {
-                    warn!("currently doesn't support hardware accelerate");
-                    Ok(decoder)
-                } else {
+                let mut config_idx = 0;
+                loop {
+                    let hw_config = avcodec_get_hw_config(codec.as_ptr(), config_idx);
+                    if hw_config.is_null() {
+                        break;
+                    }
+                    config_idx += 1;
+                    let device_type = (*hw_config).device_type;
+                    info!(
+                        "hardware config found: {}",
+                        Self::hardware_device_name(device_type)
+                    );
+                    if (*hw_config).methods & AV_CODEC_HW_CONFIG_METHOD_HW_DEVICE_CTX as i32 == 0 {
+                        continue;
+                    }
+                    if let HardwarePolicy::Device(wanted_type) = policy {
+                        if wanted_type != device_type {
+                            continue;
+                        }
+                    }
                     let mut hw_device_ctx = null_mut();
                     if 0 != av_hwdevice_ctx_create(
                         &mut hw_device_ctx,
-                        (*hw_config).device_type,
+                        device_type,
                         null(),
                         null_mut(),
                         0,
                     ) {
-                        warn!("hw device create err");
-                        return Ok(decoder);
+                        warn!(
+                            "hw device {} create err",
+                            Self::hardware_device_name(device_type)
+                        );
+                        continue;
                     }
                     (*decoder.as_mut_ptr()).hw_device_ctx = hw_device_ctx;
-
-                    self.hardware_config_flag
-                        .store(true, std::sync::atomic::Ordering::Relaxed);
-                    warn!("hardware decode acceleration is on!");
-                    Ok(decoder)
+                    warn!(
+                        "hardware decode acceleration is on! device: {}",
+                        Self::hardware_device_name(device_type)
+                    );
+                    return Ok((decoder, Some(device_type)));
                 }
+                warn!("no usable hardware device, use software decoding");
+                Ok((decoder, None))
             } else {
-                Err(PlayerError::Internal(
-                    "err when config hardware acc".to_string(),
+                Err(PlayerError::Hardware(
+                    "the decoder has no codec to config hardware acc".to_string(),
                 ))
             }
         }
     }
+    /// map the error of opening the decoder of `stream` to a codec error
+    fn codec_error(stream: &Stream<'_>) -> impl Fn(ffmpeg_the_third::Error) -> PlayerError {
+        let codec = stream.parameters().id().name().to_string();
+        move |source| PlayerError::Codec {
+            codec: codec.clone(),
+            source,
+        }
+    }
+    /// the short name of a hardware device type, like `vaapi` or `cuda`
+    pub fn hardware_device_name(device_type: AVHWDeviceType) -> String {
+        unsafe {
+            let name = av_hwdevice_get_type_name(device_type);
+            if name.is_null() {
+                "unknown".to_string()
+            } else {
+                CStr::from_ptr(name).to_string_lossy().to_string()
+            }
+        }
+    }
+    /// the hardware device types supported by the linked ffmpeg
+    pub fn supported_hardware_devices() -> Vec<AVHWDeviceType> {
+        let mut device_types = vec![];
+        let mut device_type = AVHWDeviceType::AV_HWDEVICE_TYPE_NONE;
+        loop {
+            device_type = unsafe { av_hwdevice_iterate_types(device_type) };
+            if device_type == AVHWDeviceType::AV_HWDEVICE_TYPE_NONE {
+                break;
+            }
+            device_types.push(device_type);
+        }
+        device_types
+    }
+    fn set_hardware_device_type(&mut self, device_type: Option<AVHWDeviceType>) {
+        self.hardware_config_flag
+            .store(device_type.is_some(), std::sync::atomic::Ordering::Relaxed);
+        self.hardware_device_type = device_type;
+    }
 }
 impl Drop for TinyDecoder {
-    /// handle some struct that have to be free manually
+    /// stop the worker threads, the ffmpeg contexts they own are freed with them
     fn drop(&mut self) {
-        self.demux_exit_flag
-            .store(true, std::sync::atomic::Ordering::Relaxed);
-        self.decode_exit_flag
-            .store(true, std::sync::atomic::Ordering::Relaxed);
-        self.decode_thread_notify.notify_waiters();
-        let demux_task_handle = self.demux_task_handle.take();
-        let decode_task_handle = self.decode_task_handle.take();
-        self.runtime_handle.spawn(async move {
-            demux_task_handle
-                .ok_or(PlayerError::Internal("join demux thread err".to_string()))?
-                .await
-                .map_err(|_e| PlayerError::Internal("join demux thread err".to_string()))?;
-            decode_task_handle
-                .ok_or(PlayerError::Internal("join decode thread err".to_string()))?
-                .await
-                .map_err(|_e| PlayerError::Internal("join decode thread err".to_string()))?;
-            info!("demux and decode thread exit gracefully");
-            PlayerResult::Ok(())
-        });
-
-        if let Some(ctx) = &mut self.resampler_ctx {
-            unsafe {
-                swr_free(&mut ctx.0);
+        self.stop_workers();
+    }
+}
+/// atempo filter graph to change the audio speed without changing the pitch
+/// works on the resampled stereo f32 48000hz frames
+pub struct AudioTempo {
+    graph: Graph,
+    speed: f64,
+    /// the time base of the audio stream, the pts of the pushed frames use it
+    time_base: Rational,
+    /// the pts of the first pushed frame
+    start_pts: Option<i64>,
+    /// the samples taken out of the filter since the first pushed frame
+    out_samples: i64,
+}
+impl AudioTempo {
+    /// the sample rate of the resampled frames
+    const SAMPLE_RATE: i64 = 48000;
+    fn new(speed: f64, time_base: Rational) -> PlayerResult<Self> {
+        let mut graph = Graph::new();
+        let abuffer = ffmpeg_the_third::filter::find("abuffer")
+            .ok_or(PlayerError::Filter("abuffer filter not found".to_string()))?;
+        let abuffersink = ffmpeg_the_third::filter::find("abuffersink").ok_or(
+            PlayerError::Filter("abuffersink filter not found".to_string()),
+        )?;
+        graph
+            .add(
+                &abuffer,
+                "in",
+                &format!(
+                    "time_base={}/{}:sample_rate={}:sample_fmt=flt:channel_layout=stereo",
+                    time_base.numerator(),
+                    time_base.denominator(),
+                    Self::SAMPLE_RATE
+                ),
+            )
+            .map_err(|e| PlayerError::Filter(e.to_string()))?;
+        graph
+            .add(&abuffersink, "out", "")
+            .map_err(|e| PlayerError::Filter(e.to_string()))?;
+        graph
+            .output("in", 0)
+            .and_then(|parser| parser.input("out", 0))
+            .and_then(|parser| parser.parse(&Self::atempo_chain(speed)))
+            .map_err(|e| PlayerError::Filter(e.to_string()))?;
+        graph
+            .validate()
+            .map_err(|e| PlayerError::Filter(e.to_string()))?;
+        Ok(Self {
+            graph,
+            speed,
+            time_base,
+            start_pts: None,
+            out_samples: 0,
+        })
+    }
+    /// one atempo filter only accepts 0.5 to 2.0 without quality loss
+    /// chain several of them for the other speeds
+    fn atempo_chain(speed: f64) -> String {
+        let mut speed = speed;
+        let mut filters = vec![];
+        while speed < 0.5 {
+            filters.push("atempo=0.5".to_string());
+            speed /= 0.5;
+        }
+        while speed > 2.0 {
+            filters.push("atempo=2.0".to_string());
+            speed /= 2.0;
+        }
+        filters.push(format!("atempo={speed}"));
+        filters.join(",")
+    }
+    /// push one frame into the filter and take all the stretched frames out
+    fn filter(&mut self, frame: &Audio) -> Vec<Audio> {
+        if let Some(mut ctx) = self.graph.get("in") {
+            if ctx.source().add(frame).is_ok() {
+                if self.start_pts.is_none() {
+                    self.start_pts = frame.pts();
+                }
+                return self.take_stretched_frames();
+            }
+        }
+        vec![]
+    }
+    /// end the input of the filter and take the audio still kept in it
+    fn drain(&mut self) -> Vec<Audio> {
+        if let Some(mut ctx) = self.graph.get("in") {
+            if ctx.source().flush().is_ok() {
+                return self.take_stretched_frames();
+            }
+        }
+        vec![]
+    }
+    /// the out frames get the pts of the media they were made from so the main stream
+    /// clock keeps counting the media time, one out sample covers `speed` media samples
+    fn take_stretched_frames(&mut self) -> Vec<Audio> {
+        let mut res = vec![];
+        if let Some(mut ctx) = self.graph.get("out") {
+            loop {
+                let mut stretched_frame = Audio::empty();
+                if ctx.sink().frame(&mut stretched_frame).is_err() {
+                    break;
+                }
+                let media_samples = (self.out_samples as f64 * self.speed).round() as i64;
+                let offset = media_samples * self.time_base.denominator() as i64
+                    / (Self::SAMPLE_RATE * self.time_base.numerator().max(1) as i64);
+                stretched_frame.set_pts(self.start_pts.map(|start_pts| start_pts + offset));
+                self.out_samples += stretched_frame.samples() as i64;
+                res.push(stretched_frame);
             }
         }
+        res
     }
 }
+/// what the decode task measured on the video stream, for diagnostics
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DecodeStats {
+    /// average time to decode, download and filter one frame
+    pub video_decode_ms: f64,
+    /// bitrate of the video packets decoded in the last second of media
+    pub video_bitrate_kbps: f64,
+    /// format of the decoded frames, after the hardware download
+    pub video_pixel_format: Option<Pixel>,
+    bitrate_window_bytes: usize,
+    bitrate_window_duration: i64,
+}
+impl DecodeStats {
+    fn record_video_decode(&mut self, elapsed: Duration, frames: usize, format: Option<Pixel>) {
+        if frames > 0 {
+            let frame_ms = elapsed.as_secs_f64() * 1000.0 / frames as f64;
+            // moving average, one slow frame does not hide the trend
+            self.video_decode_ms = if self.video_decode_ms == 0.0 {
+                frame_ms
+            } else {
+                self.video_decode_ms * 0.9 + frame_ms * 0.1
+            };
+        }
+        if format.is_some() {
+            self.video_pixel_format = format;
+        }
+    }
+    fn record_video_packet(&mut self, bytes: usize, duration: i64, time_base: Rational) {
+        self.bitrate_window_bytes += bytes;
+        self.bitrate_window_duration += duration.max(0);
+        let window_secs = self.bitrate_window_duration as f64 * time_base.numerator() as f64
+            / time_base.denominator().max(1) as f64;
+        if window_secs >= 1.0 {
+            self.video_bitrate_kbps = self.bitrate_window_bytes as f64 * 8.0 / 1000.0 / window_secs;
+            self.bitrate_window_bytes = 0;
+            self.bitrate_window_duration = 0;
+        }
+    }
+}
+/// the threading of one decoder
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DecoderThreading {
+    /// decode several frames at once, the fastest but adds a few frames of delay
+    pub frame: bool,
+    /// split one frame into slices, only helps if the stream is encoded with slices
+    pub slice: bool,
+    /// 0 means choose by the cpu count and the video resolution
+    pub count: usize,
+}
+impl DecoderThreading {
+    pub fn default_video() -> Self {
+        Self {
+            frame: true,
+            slice: true,
+            count: 0,
+        }
+    }
+    /// audio decoding is cheap, more threads only add delay
+    pub fn default_audio() -> Self {
+        Self {
+            frame: false,
+            slice: false,
+            count: 1,
+        }
+    }
+    /// set the threading to a codec context before it is opened
+    /// `pixels` is the frame size of the video, 0 for audio
+    fn apply(&self, codec_ctx: &mut ffmpeg_the_third::codec::Context, pixels: u64) {
+        let count = if self.count == 0 {
+            Self::auto_count(pixels)
+        } else {
+            self.count
+        };
+        let mut thread_type = 0;
+        if self.frame {
+            thread_type |= FF_THREAD_FRAME as i32;
+        }
+        if self.slice {
+            thread_type |= FF_THREAD_SLICE as i32;
+        }
+        unsafe {
+            let ctx = codec_ctx.as_mut_ptr();
+            (*ctx).thread_count = count as i32;
+            (*ctx).thread_type = thread_type;
+        }
+        info!("decoder threads:{} type:{}", count, thread_type);
+    }
+    /// one thread per cpu, limited by what the resolution can keep busy
+    fn auto_count(pixels: u64) -> usize {
+        let cpu_count = std::thread::available_parallelism()
+            .map(|n| n.get())
+            .unwrap_or(1);
+        let max_count = if pixels >= 3840 * 2160 {
+            16
+        } else if pixels >= 1920 * 1080 {
+            8
+        } else if pixels > 0 {
+            4
+        } else {
+            1
+        };
+        cpu_count.min(max_count)
+    }
+}
+/// how the video decoder chooses between hardware and software decoding
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HardwarePolicy {
+    /// try every hardware device the codec supports, software if none works
+    Auto,
+    /// only try this hardware device type, software if it doesn't work
+    Device(AVHWDeviceType),
+    /// never use hardware decoding
+    Software,
+}
+/// where the yuv frames are turned to rgb
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum VideoRenderer {
+    /// the planes are uploaded as textures and converted by a shader,
+    /// the formats the shader can't read still go through swscale
+    GpuYuv,
+    /// swscale converts every frame to rgba on the cpu
+    Swscale,
+}
+/// the yuv to rgb matrix used by the rgba converter
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ColorMatrixOverride {
+    /// follow the frame colorspace tag
+    Auto,
+    Bt601,
+    Bt709,
+    Bt2020,
+}
+/// the yuv value range used by the rgba converter
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ColorRangeOverride {
+    /// follow the frame color range tag
+    Auto,
+    /// 16-235, normal video
+    Limited,
+    /// 0-255, jpeg and some screen recordings
+    Full,
+}
+/// the curve used to squeeze the hdr brightness into the sdr range
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ToneMapOperator {
+    /// filmic curve, keeps the highlight details
+    Hable,
+    /// simple curve, a little dark but never clips
+    Reinhard,
+    /// keeps the in range colors and only compresses the highlights
+    Mobius,
+}
+impl ToneMapOperator {
+    /// the value of the ffmpeg tonemap filter option
+    fn filter_name(&self) -> &'static str {
+        match self {
+            Self::Hable => "hable",
+            Self::Reinhard => "reinhard",
+            Self::Mobius => "mobius",
+        }
+    }
+}
+/// the user configured video filters applied to every decoded video frame
+#[derive(Debug, Clone)]
+pub struct VideoFilterChain {
+    /// an ffmpeg filter chain like `hflip,eq=contrast=1.2`, parsed by ffmpeg as it is
+    /// so the commas inside the filter options are kept
+    pub filters: String,
+    /// draw the app name at the top right corner, off unless the user turns it on
+    pub watermark: bool,
+    /// tone map the pq and hlg frames to sdr before any other filter
+    pub tone_mapping: bool,
+    pub tone_map_operator: ToneMapOperator,
+}
+impl Default for VideoFilterChain {
+    fn default() -> Self {
+        Self {
+            filters: String::new(),
+            watermark: false,
+            tone_mapping: true,
+            tone_map_operator: ToneMapOperator::Hable,
+        }
+    }
+}
+impl VideoFilterChain {
+    /// take a filter chain text like `hflip,eq=contrast=1.2`
+    pub fn set_filters_from_str(&mut self, chain: &str) {
+        self.filters = chain.trim().to_string();
+    }
+    /// build the graph for a small frame to report the parse errors of the filter text
+    /// before the decode thread uses the chain
+    pub fn validate(&self) -> PlayerResult<()> {
+        VideoFilterGraph::new(
+            self,
+            &Video::new(Pixel::YUV420P, 64, 64),
+            Rational::new(1, 25),
+            None,
+        )
+        .map(|_| ())
+    }
+    /// the graph description parsed between the buffer source and sink
+    fn description(&self, font_path: Option<&str>, tone_map: bool) -> String {
+        let mut filters = vec![];
+        if tone_map {
+            // linearize, convert the bt2020 primaries to bt709 in linear light,
+            // tone map, then encode back to bt709 sdr
+            filters.push("zscale=t=linear:npl=100".to_string());
+            filters.push("format=gbrpf32le".to_string());
+            filters.push("zscale=p=bt709".to_string());
+            filters.push(format!(
+                "tonemap=tonemap={}:desat=0",
+                self.tone_map_operator.filter_name()
+            ));
+            filters.push("zscale=t=bt709:m=bt709:r=tv".to_string());
+            filters.push("format=yuv420p".to_string());
+        }
+        if !self.filters.is_empty() {
+            filters.push(self.filters.clone());
+        }
+        if self.watermark {
+            if let Some(font_path) = font_path {
+                filters.push(format!(
+                    "drawtext=text='Tiny Player':fontfile='{}':fontsize=26:fontcolor=white@0.3:x=w-text_w-10:y=10",
+                    font_path
+                ));
+            }
+        }
+        if filters.is_empty() {
+            "null".to_string()
+        } else {
+            filters.join(",")
+        }
+    }
+}
+/// the filter graph built from a `VideoFilterChain` for one frame size and format
+struct VideoFilterGraph {
+    graph: Graph,
+    width: u32,
+    height: u32,
+    format: Pixel,
+    hdr: bool,
+}
+impl VideoFilterGraph {
+    fn new(
+        chain: &VideoFilterChain,
+        frame: &Video,
+        time_base: Rational,
+        font_path: Option<&str>,
+    ) -> PlayerResult<Self> {
+        let hdr = Self::is_hdr_frame(frame);
+        // zscale comes from libzimg which is not in every ffmpeg build
+        let tone_map = if hdr && chain.tone_mapping {
+            if ffmpeg_the_third::filter::find("zscale").is_some() {
+                true
+            } else {
+                warn!("zscale filter not found, hdr frames are not tone mapped");
+                false
+            }
+        } else {
+            false
+        };
+        let mut graph = Graph::new();
+        let buffer = ffmpeg_the_third::filter::find("buffer")
+            .ok_or(PlayerError::Filter("buffer filter not found".to_string()))?;
+        let buffersink = ffmpeg_the_third::filter::find("buffersink").ok_or(
+            PlayerError::Filter("buffersink filter not found".to_string()),
+        )?;
+        graph
+            .add(
+                &buffer,
+                "in",
+                &format!(
+                    "video_size={}x{}:pix_fmt={}:time_base={}/{}:pixel_aspect=1/1",
+                    frame.width(),
+                    frame.height(),
+                    AVPixelFormat::from(frame.format()) as i32,
+                    time_base.numerator(),
+                    time_base.denominator(),
+                ),
+            )
+            .map_err(|e| PlayerError::Filter(e.to_string()))?;
+        graph
+            .add(&buffersink, "out", "")
+            .map_err(|e| PlayerError::Filter(e.to_string()))?;
+        graph
+            .output("in", 0)
+            .and_then(|parser| parser.input("out", 0))
+            .and_then(|parser| parser.parse(&chain.description(font_path, tone_map)))
+            .map_err(|e| PlayerError::Filter(e.to_string()))?;
+        graph
+            .validate()
+            .map_err(|e| PlayerError::Filter(e.to_string()))?;
+        info!("graph validate success!dump:\n{}", graph.dump());
+        Ok(Self {
+            graph,
+            width: frame.width(),
+            height: frame.height(),
+            format: frame.format(),
+            hdr,
+        })
+    }
+    /// pq(hdr10) and hlg frames need tone mapping to look right on a sdr screen
+    fn is_hdr_frame(frame: &Video) -> bool {
+        matches!(
+            frame.color_transfer_characteristic(),
+            TransferCharacteristic::SMPTE2084 | TransferCharacteristic::ARIB_STD_B67
+        )
+    }
+    /// true if the graph input matches the frame size, format and transfer
+    fn accepts(&self, frame: &Video) -> bool {
+        self.width == frame.width()
+            && self.height == frame.height()
+            && self.format == frame.format()
+            && self.hdr == Self::is_hdr_frame(frame)
+    }
+    /// push one frame into the graph and take all the filtered frames out
+    fn filter(&mut self, frame: &Video) -> Vec<Video> {
+        if let Some(mut ctx) = self.graph.get("in") {
+            if ctx.source().add(frame).is_ok() {
+                return self.take_filtered_frames();
+            }
+        }
+        vec![]
+    }
+    /// end the input of the graph and take the frames still kept in it
+    /// the graph accepts no frame after it
+    fn drain(&mut self) -> Vec<Video> {
+        if let Some(mut ctx) = self.graph.get("in") {
+            if ctx.source().flush().is_ok() {
+                return self.take_filtered_frames();
+            }
+        }
+        vec![]
+    }
+    fn take_filtered_frames(&mut self) -> Vec<Video> {
+        let mut res = vec![];
+        if let Some(mut ctx) = self.graph.get("out") {
+            loop {
+                let mut filtered_frame = Video::empty();
+                if ctx.sink().frame(&mut filtered_frame).is_err() {
+                    break;
+                }
+                res.push(filtered_frame);
+            }
+        }
+        res
+    }
+}
+/// one thread of the pipeline, it owns the ffmpeg contexts it uses
+/// and is driven by `run_pipeline_worker`
+trait PipelineWorker {
+    type Command;
+    fn handle_command(&mut self, command: Self::Command);
+    /// do one piece of work, return false if there was nothing to do
+    fn step(&mut self) -> bool;
+}
+/// run the worker until its command channel is closed
+fn run_pipeline_worker<W: PipelineWorker>(worker: &mut W, command_receiver: Receiver<W::Command>) {
+    info!("worker start");
+    let mut idle = false;
+    loop {
+        let command = if idle {
+            match command_receiver.recv_timeout(WORKER_IDLE_WAIT) {
+                Ok(command) => Some(command),
+                Err(RecvTimeoutError::Timeout) => None,
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+        } else {
+            match command_receiver.try_recv() {
+                Ok(command) => Some(command),
+                Err(TryRecvError::Empty) => None,
+                Err(TryRecvError::Disconnected) => break,
+            }
+        };
+        if let Some(command) = command {
+            worker.handle_command(command);
+        }
+        idle = !worker.step();
+    }
+    info!("worker exit");
+}
+/// send the decoded frames waiting for room, return false if some are still waiting
+fn send_pending_frames<T: QueueItem>(
+    pending_frames: &mut VecDeque<T>,
+    frame_channel: &MediaChannel<T>,
+    serial: usize,
+) -> bool {
+    while let Some(frame) = pending_frames.pop_front() {
+        if let Err(frame) = frame_channel.try_send(frame, serial) {
+            pending_frames.push_front(frame);
+            return false;
+        }
+    }
+    true
+}
+
+/// the command channels and the threads of one input
+struct DecodeWorkers {
+    demux_command_sender: Sender<DemuxCommand>,
+    audio_command_sender: Sender<AudioDecodeCommand>,
+    video_command_sender: Sender<VideoDecodeCommand>,
+    subtitle_command_sender: Sender<SubtitleDecodeCommand>,
+    convert_request_sender: SyncSender<ConvertRequest>,
+    thread_handles: Vec<JoinHandle<()>>,
+}
+
+enum DemuxCommand {
+    /// seek the input and send the packets read after it with `serial`
+    Seek {
+        stream_index: usize,
+        min_ts: i64,
+        ts: i64,
+        max_ts: i64,
+        serial: usize,
+    },
+    SetAudioStream(usize),
+    SetSubtitleStream(Option<usize>),
+    /// the decoders are opened from the stream parameters of the input,
+    /// so they are made by the thread owning it
+    OpenVideoDecoder {
+        stream_index: usize,
+        policy: HardwarePolicy,
+        threading: DecoderThreading,
+        reply: oneshot::Sender<
+            PlayerResult<(ffmpeg_the_third::decoder::Video, Option<AVHWDeviceType>)>,
+        >,
+    },
+    OpenAudioDecoder {
+        stream_index: usize,
+        threading: DecoderThreading,
+        reply: oneshot::Sender<PlayerResult<(ffmpeg_the_third::decoder::Audio, Rational, String)>>,
+    },
+    OpenSubtitleDecoder {
+        stream_index: usize,
+        reply: oneshot::Sender<PlayerResult<ffmpeg_the_third::decoder::Subtitle>>,
+    },
+}
 #[derive(Builder)]
+#[builder(pattern = "owned")]
 struct DemuxContext {
+    pub format_input: ffmpeg_the_third::format::context::Input,
     pub audio_stream_index: usize,
     pub video_stream_index: usize,
+    pub subtitle_stream_index: usize,
     pub cover_stream_index: usize,
-    pub format_input: Arc<RwLock<Option<ManualProtectedInput>>>,
-    pub audio_packet_cache_queue: Arc<RwLock<VecDeque<Packet>>>,
-    pub video_packet_cache_queue: Arc<RwLock<VecDeque<Packet>>>,
+    pub audio_packet_channel: Arc<MediaChannel<Packet>>,
+    pub video_packet_channel: Arc<MediaChannel<Packet>>,
+    pub subtitle_packet_channel: Arc<MediaChannel<Packet>>,
     pub cover_image_data: Arc<RwLock<Option<Vec<u8>>>>,
-    pub demux_exit_flag: Arc<AtomicBool>,
-    pub demux_thread_notify: Arc<Notify>,
+    pub serial: usize,
+}
+/// reads the packets of the input and routes them to the packet channels
+struct DemuxWorker {
+    context: DemuxContext,
+    /// a packet read while its channel was full, it is sent before the next read
+    pending_packet: Option<(usize, Packet)>,
+    eof: bool,
+    /// the read errors since the last packet, the ones asking to try again are not counted
+    read_error_count: usize,
+}
+impl DemuxWorker {
+    fn new(context: DemuxContext) -> Self {
+        Self {
+            context,
+            pending_packet: None,
+            eof: false,
+            read_error_count: 0,
+        }
+    }
+    fn packet_channel(&self, stream_index: usize) -> Option<&Arc<MediaChannel<Packet>>> {
+        if stream_index == self.context.audio_stream_index {
+            Some(&self.context.audio_packet_channel)
+        } else if stream_index == self.context.video_stream_index {
+            Some(&self.context.video_packet_channel)
+        } else if stream_index == self.context.subtitle_stream_index {
+            Some(&self.context.subtitle_packet_channel)
+        } else {
+            None
+        }
+    }
+    /// return false if the channel is full, the packet is kept until it has room
+    fn route_packet(&mut self, stream_index: usize, packet: Packet) -> bool {
+        if let Some(channel) = self.packet_channel(stream_index) {
+            if let Err(packet) = channel.try_send(packet, self.context.serial) {
+                self.pending_packet = Some((stream_index, packet));
+                return false;
+            }
+        }
+        true
+    }
+    fn is_stream_full(&self, stream_index: usize, channel: &MediaChannel<Packet>) -> bool {
+        stream_index == usize::MAX || channel.is_full()
+    }
+    fn finish_channels(&self) {
+        let serial = self.context.serial;
+        self.context.audio_packet_channel.finish(serial);
+        self.context.video_packet_channel.finish(serial);
+        self.context.subtitle_packet_channel.finish(serial);
+    }
+    fn reply_decoder<R>(
+        &self,
+        stream_index: usize,
+        reply: oneshot::Sender<PlayerResult<R>>,
+        open: impl FnOnce(&Stream<'_>) -> PlayerResult<R>,
+    ) {
+        let result = self
+            .context
+            .format_input
+            .stream(stream_index)
+            .ok_or(PlayerError::TrackNotFound(stream_index))
+            .and_then(|stream| open(&stream));
+        if reply.send(result).is_err() {
+            warn!(
+                "the decoder of stream {} is not waited any more",
+                stream_index
+            );
+        }
+    }
+}
+impl PipelineWorker for DemuxWorker {
+    type Command = DemuxCommand;
+    fn handle_command(&mut self, command: DemuxCommand) {
+        match command {
+            DemuxCommand::Seek {
+                stream_index,
+                min_ts,
+                ts,
+                max_ts,
+                serial,
+            } => {
+                unsafe {
+                    let res = ffmpeg_the_third::ffi::avformat_seek_file(
+                        self.context.format_input.as_mut_ptr(),
+                        stream_index as i32,
+                        min_ts,
+                        ts,
+                        max_ts,
+                        AVSEEK_FLAG_BACKWARD,
+                    );
+                    if res != 0 {
+                        info!("seek err num:{res}");
+                    }
+                }
+                // the demux reads again from the seeked position
+                self.pending_packet = None;
+                self.context.serial = serial;
+                self.eof = false;
+                self.read_error_count = 0;
+            }
+            DemuxCommand::SetAudioStream(stream_index) => {
+                self.context.audio_stream_index = stream_index;
+            }
+            DemuxCommand::SetSubtitleStream(stream_index) => {
+                self.context.subtitle_stream_index = stream_index.unwrap_or(usize::MAX);
+                self.context.subtitle_packet_channel.clear();
+            }
+            DemuxCommand::OpenVideoDecoder {
+                stream_index,
+                policy,
+                threading,
+                reply,
+            } => {
+                self.reply_decoder(stream_index, reply, |stream| {
+                    TinyDecoder::choose_decoder_with_hardware_prefer(stream, policy, threading)
+                });
+            }
+            DemuxCommand::OpenAudioDecoder {
+                stream_index,
+                threading,
+                reply,
+            } => {
+                self.reply_decoder(stream_index, reply, |stream| {
+                    let decoder = TinyDecoder::create_audio_decoder(stream, threading)?;
+                    Ok((
+                        decoder,
+                        stream.time_base(),
+                        stream.parameters().id().name().to_string(),
+                    ))
+                });
+            }
+            DemuxCommand::OpenSubtitleDecoder {
+                stream_index,
+                reply,
+            } => {
+                self.reply_decoder(stream_index, reply, TinyDecoder::create_subtitle_decoder);
+            }
+        }
+    }
+    fn step(&mut self) -> bool {
+        if let Some((stream_index, packet)) = self.pending_packet.take() {
+            if !self.route_packet(stream_index, packet) {
+                return false;
+            }
+        }
+        // read until every played stream has its budget buffered
+        if self.eof
+            || (self.is_stream_full(
+                self.context.audio_stream_index,
+                &self.context.audio_packet_channel,
+            ) && self.is_stream_full(
+                self.context.video_stream_index,
+                &self.context.video_packet_channel,
+            ))
+        {
+            return false;
+        }
+        let next_packet = self
+            .context
+            .format_input
+            .packets()
+            .next()
+            .map(|res| res.map(|(stream, packet)| (stream.index(), packet)));
+        match next_packet {
+            Some(Ok((stream_index, packet))) => {
+                self.read_error_count = 0;
+                if stream_index == self.context.cover_stream_index {
+                    if let Some(d) = packet.data() {
+                        *self.context.cover_image_data.blocking_write() = Some(d.to_vec());
+                    }
+                    true
+                } else {
+                    self.route_packet(stream_index, packet)
+                }
+            }
+            Some(Err(ffmpeg_the_third::util::error::Error::Eof)) | None => {
+                // the decode threads drain the decoders once the packets left are used
+                info!("demux process hit the end");
+                self.eof = true;
+                self.finish_channels();
+                false
+            }
+            Some(Err(ffmpeg_the_third::util::error::Error::Other {
+                errno: ffmpeg_the_third::util::error::EAGAIN,
+            })) => false,
+            Some(Err(e)) => {
+                warn!("demux read packet err: {}", e);
+                self.read_error_count += 1;
+                if self.read_error_count >= MAX_DEMUX_READ_ERRORS {
+                    // the packets read so far are still played, then the playback ends
+                    warn!(
+                        "demux gives up the input after {} read errors",
+                        self.read_error_count
+                    );
+                    self.eof = true;
+                    self.finish_channels();
+                }
+                false
+            }
+        }
+    }
 }
 
+/// converts the decoded audio to the stereo f32 48000hz format of the audio player
+/// it is created and freed in the audio decode thread
+struct Resampler(*mut SwrContext);
+impl Resampler {
+    fn new(decoder: &ffmpeg_the_third::decoder::Audio) -> Option<Self> {
+        unsafe {
+            let mut swr_ctx = null_mut();
+            let r = swr_alloc_set_opts2(
+                &mut swr_ctx,
+                &AV_CHANNEL_LAYOUT_STEREO,
+                ffmpeg_the_third::ffi::AVSampleFormat::AV_SAMPLE_FMT_FLT,
+                48000,
+                decoder.ch_layout().as_ptr(),
+                decoder.format().into(),
+                decoder.rate() as i32,
+                0,
+                null_mut(),
+            );
+            if r < 0 {
+                warn!("swr ctx create err");
+                return None;
+            }
+            let resampler = Self(swr_ctx);
+            if swr_init(resampler.0) < 0 {
+                warn!("swr init err");
+                return None;
+            }
+            Some(resampler)
+        }
+    }
+    /// the output keeps the pts and duration of the decoded frame
+    fn convert(&mut self, raw_frame: &Audio) -> Option<Audio> {
+        let pts = raw_frame.pts()?;
+        let mut res = ffmpeg_the_third::frame::Audio::empty();
+        res.set_format(ffmpeg_the_third::format::Sample::F32(Type::Packed));
+        res.set_ch_layout(ChannelLayout::STEREO);
+        res.set_rate(48000);
+        unsafe {
+            let r = swr_convert_frame(self.0, res.as_mut_ptr(), raw_frame.as_ptr());
+            if r != 0 {
+                info!("resample err{}", r);
+                return None;
+            }
+            (*res.as_mut_ptr()).duration = (*raw_frame.as_ptr()).duration;
+        }
+        res.set_pts(Some(pts));
+        res.set_rate(48000);
+        Some(res)
+    }
+}
+impl Drop for Resampler {
+    fn drop(&mut self) {
+        unsafe {
+            swr_free(&mut self.0);
+        }
+    }
+}
+
+enum AudioDecodeCommand {
+    /// the decoder and the first serial it decodes
+    SetDecoder(ffmpeg_the_third::decoder::Audio, usize),
+}
 #[derive(Builder)]
-struct DecodeContext {
-    pub audio_decoder: Arc<RwLock<Option<ManualProtectedAudioDecoder>>>,
-    pub video_decoder: Arc<RwLock<Option<ManualProtectedVideoDecoder>>>,
-    pub audio_packet_cache_queue: Arc<RwLock<VecDeque<Packet>>>,
-    pub video_packet_cache_queue: Arc<RwLock<VecDeque<Packet>>>,
-    pub audio_frame_cache_queue: Arc<RwLock<VecDeque<Audio>>>,
-    pub video_frame_cache_queue: Arc<RwLock<VecDeque<Video>>>,
+#[builder(pattern = "owned")]
+struct AudioDecodeContext {
+    pub decoder: Option<ffmpeg_the_third::decoder::Audio>,
+    pub packet_channel: Arc<MediaChannel<Packet>>,
+    pub frame_channel: Arc<MediaChannel<Audio>>,
+    pub seek_target_pts: Arc<AtomicI64>,
+    /// the packets of older serials than this are not for the installed decoder
+    pub decoder_serial: Arc<AtomicUsize>,
+    pub serial: usize,
+}
+/// decodes the audio packets and resamples the frames for the audio player
+struct AudioDecodeWorker {
+    context: AudioDecodeContext,
+    /// the first serial the installed decoder decodes
+    decoder_serial: usize,
+    /// a decoder waiting for the worker to follow its serial
+    pending_decoder: Option<(ffmpeg_the_third::decoder::Audio, usize)>,
+    resampler: Option<Resampler>,
+    seek_target_pts: i64,
+    pending_frames: VecDeque<Audio>,
+    drained: bool,
+}
+impl AudioDecodeWorker {
+    fn new(context: AudioDecodeContext) -> Self {
+        let resampler = context.decoder.as_ref().and_then(Resampler::new);
+        let seek_target_pts = context
+            .seek_target_pts
+            .load(std::sync::atomic::Ordering::Relaxed);
+        Self {
+            decoder_serial: context.serial,
+            context,
+            pending_decoder: None,
+            resampler,
+            seek_target_pts,
+            pending_frames: VecDeque::new(),
+            drained: false,
+        }
+    }
+    /// drop the state of the old timeline and decode the packets of `serial`
+    fn follow_serial(&mut self, serial: usize) {
+        if let Some(decoder) = &mut self.context.decoder {
+            decoder.flush();
+        }
+        self.context.serial = serial;
+        self.pending_frames.clear();
+        self.drained = false;
+        self.seek_target_pts = self
+            .context
+            .seek_target_pts
+            .load(std::sync::atomic::Ordering::Relaxed);
+    }
+    /// use the pending decoder once the worker is in its timeline
+    fn install_pending_decoder(&mut self) {
+        if self
+            .pending_decoder
+            .as_ref()
+            .is_some_and(|(_, serial)| *serial <= self.context.serial)
+        {
+            if let Some((decoder, serial)) = self.pending_decoder.take() {
+                self.resampler = Resampler::new(&decoder);
+                self.context.decoder = Some(decoder);
+                self.decoder_serial = serial;
+            }
+        }
+    }
+    /// send one packet to the decoder, `None` drains it
+    fn decode(&mut self, packet: Option<&Packet>) {
+        if let Some(decoder) = &mut self.context.decoder {
+            let sent = if let Some(packet) = packet {
+                decoder.send_packet(packet).is_ok()
+            } else {
+                decoder.send_eof().is_ok()
+            };
+            if sent {
+                loop {
+                    let mut audio_frame_tmp = ffmpeg_the_third::frame::Audio::empty();
+                    if decoder.receive_frame(&mut audio_frame_tmp).is_err() {
+                        break;
+                    }
+                    if TinyDecoder::is_before_seek_target(
+                        &mut self.seek_target_pts,
+                        audio_frame_tmp.pts(),
+                        unsafe { (*audio_frame_tmp.as_ptr()).duration },
+                    ) {
+                        continue;
+                    }
+                    if let Some(resampler) = &mut self.resampler {
+                        if let Some(res) = resampler.convert(&audio_frame_tmp) {
+                            self.pending_frames.push_back(res);
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+impl PipelineWorker for AudioDecodeWorker {
+    type Command = AudioDecodeCommand;
+    fn handle_command(&mut self, command: AudioDecodeCommand) {
+        match command {
+            AudioDecodeCommand::SetDecoder(decoder, serial) => {
+                self.pending_decoder = Some((decoder, serial));
+                self.install_pending_decoder();
+            }
+        }
+    }
+    fn step(&mut self) -> bool {
+        let serial = self.context.packet_channel.serial();
+        if serial != self.context.serial {
+            self.follow_serial(serial);
+            self.install_pending_decoder();
+        }
+        if self.decoder_serial
+            < self
+                .context
+                .decoder_serial
+                .load(std::sync::atomic::Ordering::Relaxed)
+        {
+            // the decoder of the switched track is still in the command channel
+            return false;
+        }
+        if !send_pending_frames(
+            &mut self.pending_frames,
+            &self.context.frame_channel,
+            self.context.serial,
+        ) {
+            return false;
+        }
+        if self.drained {
+            self.context.frame_channel.finish(self.context.serial);
+            return false;
+        }
+        if let Some((packet, packet_serial)) = self.context.packet_channel.try_recv() {
+            // the next step follows the new timeline
+            if packet_serial == self.context.serial {
+                self.decode(Some(&packet));
+            }
+            true
+        } else if self.context.packet_channel.is_drained() {
+            self.decode(None);
+            info!("audio decoder drained");
+            self.drained = true;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+enum VideoDecodeCommand {
+    SetDecoder(ffmpeg_the_third::decoder::Video),
+    SetFilterChain(VideoFilterChain),
+    SetSkipNonRef(bool),
+}
+#[derive(Builder)]
+#[builder(pattern = "owned")]
+struct VideoDecodeContext {
+    pub decoder: Option<ffmpeg_the_third::decoder::Video>,
+    pub packet_channel: Arc<MediaChannel<Packet>>,
+    pub frame_channel: Arc<MediaChannel<Video>>,
+    pub seek_target_pts: Arc<AtomicI64>,
+    pub serial: usize,
+    pub time_base: Rational,
+    pub filter_chain: VideoFilterChain,
+    pub decode_stats: Arc<RwLock<DecodeStats>>,
     pub hardware_config_flag: Arc<AtomicBool>,
-    pub decode_exit_flag: Arc<AtomicBool>,
-    pub video_time_base: Rational,
+    pub hardware_fallback_pts: Arc<AtomicI64>,
+}
+/// decodes the video packets, downloads the hardware frames and applies the filter chain
+struct VideoDecodeWorker {
+    context: VideoDecodeContext,
+    watermark_font_path: Option<String>,
+    filter_graph: Option<VideoFilterGraph>,
+    /// a chain which failed to build is not retried until it is changed
+    filter_build_failed: bool,
+    /// buffers of the frames downloaded from the hardware surfaces
+    frame_pool: FramePool,
+    seek_target_pts: i64,
+    pending_frames: VecDeque<Video>,
+    drained: bool,
+    /// the stats measured since the last publish, the shared ones are not locked per packet
+    decode_stats: DecodeStats,
+    decode_stats_published_at: Instant,
+}
+impl VideoDecodeWorker {
+    fn new(context: VideoDecodeContext) -> Self {
+        let seek_target_pts = context
+            .seek_target_pts
+            .load(std::sync::atomic::Ordering::Relaxed);
+        Self {
+            context,
+            watermark_font_path: TinyDecoder::prepare_watermark_font(),
+            filter_graph: None,
+            filter_build_failed: false,
+            frame_pool: FramePool::default(),
+            seek_target_pts,
+            pending_frames: VecDeque::new(),
+            drained: false,
+            decode_stats: DecodeStats::default(),
+            decode_stats_published_at: Instant::now(),
+        }
+    }
+    /// copy the local stats to the shared ones, skipped while a reader holds them
+    fn publish_decode_stats(&mut self) {
+        if let Ok(mut decode_stats) = self.context.decode_stats.try_write() {
+            *decode_stats = self.decode_stats;
+            self.decode_stats_published_at = Instant::now();
+        }
+    }
+    fn reset_filter_graph(&mut self) {
+        self.filter_graph = None;
+        self.filter_build_failed = false;
+    }
+    /// drop the state of the old timeline and decode the packets of `serial`
+    fn follow_serial(&mut self, serial: usize) {
+        if let Some(decoder) = &mut self.context.decoder {
+            decoder.flush();
+        }
+        // filters like tmix keep frames inside the graph, rebuild it to drop them
+        self.reset_filter_graph();
+        self.context.serial = serial;
+        self.pending_frames.clear();
+        self.drained = false;
+        self.seek_target_pts = self
+            .context
+            .seek_target_pts
+            .load(std::sync::atomic::Ordering::Relaxed);
+    }
+    /// run the decoded frame through the filter graph, the graph is rebuilt
+    /// when the decoder output changes size or format in the middle of the stream
+    fn filter_frame(&mut self, video_frame: Video) {
+        if let Some(graph) = &self.filter_graph {
+            if !graph.accepts(&video_frame) {
+                self.reset_filter_graph();
+            }
+        }
+        if self.filter_graph.is_none() && !self.filter_build_failed {
+            match VideoFilterGraph::new(
+                &self.context.filter_chain,
+                &video_frame,
+                self.context.time_base,
+                self.watermark_font_path.as_deref(),
+            ) {
+                Ok(graph) => self.filter_graph = Some(graph),
+                Err(e) => {
+                    warn!("build video filter graph error: {}", e);
+                    self.filter_build_failed = true;
+                }
+            }
+        }
+        if let Some(graph) = &mut self.filter_graph {
+            self.pending_frames.extend(graph.filter(&video_frame));
+        } else {
+            self.pending_frames.push_back(video_frame);
+        }
+    }
+    /// send one packet to the decoder, `None` drains it
+    fn decode(&mut self, packet: Option<&Packet>) {
+        let decode_start = Instant::now();
+        // the downloaded frames wait in the frame channel, the pool keeps what its budget holds
+        self.frame_pool
+            .set_max_bytes(self.context.frame_channel.limit().bytes);
+        let mut decoded_frames = 0;
+        let mut decoded_format = None;
+        let mut video_frames = vec![];
+        if let Some(decoder) = &mut self.context.decoder {
+            let sent = if let Some(packet) = packet {
+                decoder.send_packet(packet).is_ok()
+            } else {
+                decoder.send_eof().is_ok()
+            };
+            if sent {
+                loop {
+                    let mut video_frame_tmp = ffmpeg_the_third::frame::Video::empty();
+                    if decoder.receive_frame(&mut video_frame_tmp).is_err() {
+                        break;
+                    }
+                    decoded_frames += 1;
+                    // drop the frames before the accurate seek target
+                    // before any convert work is done on them
+                    if TinyDecoder::is_before_seek_target(
+                        &mut self.seek_target_pts,
+                        video_frame_tmp.pts(),
+                        unsafe { (*video_frame_tmp.as_ptr()).duration },
+                    ) {
+                        continue;
+                    }
+                    let pts = video_frame_tmp.pts();
+                    if let Some(video_frame) =
+                        TinyDecoder::transfer_hardware_frame(video_frame_tmp, &mut self.frame_pool)
+                    {
+                        decoded_format = Some(video_frame.format());
+                        video_frames.push(video_frame);
+                    } else {
+                        // the engine reopens the decoder in software mode
+                        // and seeks back to the first failed frame
+                        self.context
+                            .hardware_config_flag
+                            .store(false, std::sync::atomic::Ordering::Relaxed);
+                        let _ = self.context.hardware_fallback_pts.compare_exchange(
+                            i64::MIN,
+                            pts.unwrap_or(0),
+                            std::sync::atomic::Ordering::Relaxed,
+                            std::sync::atomic::Ordering::Relaxed,
+                        );
+                    }
+                }
+            }
+        }
+        for video_frame in video_frames {
+            self.filter_frame(video_frame);
+        }
+        self.decode_stats.record_video_decode(
+            decode_start.elapsed(),
+            decoded_frames,
+            decoded_format,
+        );
+        if let Some(packet) = packet {
+            self.decode_stats.record_video_packet(
+                packet.size(),
+                packet.duration(),
+                self.context.time_base,
+            );
+        }
+        // the drained decoder decodes nothing more, its last stats are published at once
+        if packet.is_none()
+            || self.decode_stats_published_at.elapsed() >= DECODE_STATS_PUBLISH_INTERVAL
+        {
+            self.publish_decode_stats();
+        }
+    }
+}
+impl PipelineWorker for VideoDecodeWorker {
+    type Command = VideoDecodeCommand;
+    fn handle_command(&mut self, command: VideoDecodeCommand) {
+        match command {
+            VideoDecodeCommand::SetDecoder(decoder) => {
+                self.context.decoder = Some(decoder);
+                self.reset_filter_graph();
+            }
+            VideoDecodeCommand::SetFilterChain(chain) => {
+                self.context.filter_chain = chain;
+                self.reset_filter_graph();
+            }
+            VideoDecodeCommand::SetSkipNonRef(skip) => {
+                if let Some(decoder) = &mut self.context.decoder {
+                    unsafe {
+                        (*decoder.as_mut_ptr()).skip_frame = if skip {
+                            AVDiscard::AVDISCARD_NONREF
+                        } else {
+                            AVDiscard::AVDISCARD_DEFAULT
+                        };
+                    }
+                }
+            }
+        }
+    }
+    fn step(&mut self) -> bool {
+        let serial = self.context.packet_channel.serial();
+        if serial != self.context.serial {
+            self.follow_serial(serial);
+        }
+        if !send_pending_frames(
+            &mut self.pending_frames,
+            &self.context.frame_channel,
+            self.context.serial,
+        ) {
+            return false;
+        }
+        if self.drained {
+            self.context.frame_channel.finish(self.context.serial);
+            return false;
+        }
+        if let Some((packet, packet_serial)) = self.context.packet_channel.try_recv() {
+            // the next step follows the new timeline
+            if packet_serial == self.context.serial {
+                self.decode(Some(&packet));
+            }
+            true
+        } else if self.context.packet_channel.is_drained() {
+            self.decode(None);
+            // filters like tmix still hold the last frames
+            if let Some(graph) = &mut self.filter_graph {
+                self.pending_frames.extend(graph.drain());
+            }
+            info!("video decoder drained");
+            self.drained = true;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+enum SubtitleDecodeCommand {
+    /// `None` turns the subtitle off
+    SetDecoder(Option<ffmpeg_the_third::decoder::Subtitle>),
+}
+#[derive(Builder)]
+#[builder(pattern = "owned")]
+struct SubtitleDecodeContext {
+    pub decoder: Option<ffmpeg_the_third::decoder::Subtitle>,
+    pub packet_channel: Arc<MediaChannel<Packet>>,
+    pub subtitle_cues: Arc<RwLock<SubtitleCues>>,
     pub video_frame_rect: [u32; 2],
-    pub demux_thread_notify: Arc<Notify>,
-    pub decode_thread_notify: Arc<Notify>,
+    pub serial: usize,
+}
+/// decodes the subtitle packets into the shared cues as soon as they are read
+struct SubtitleDecodeWorker {
+    context: SubtitleDecodeContext,
+}
+impl SubtitleDecodeWorker {
+    fn new(context: SubtitleDecodeContext) -> Self {
+        Self { context }
+    }
+    fn decode(&mut self, packet: &Packet) {
+        if let Some(decoder) = &mut self.context.decoder {
+            let mut subtitle = ffmpeg_the_third::codec::subtitle::Subtitle::new();
+            if let Ok(true) = decoder.decode(packet, &mut subtitle) {
+                let (fallback_pts_ms, canvas) = unsafe {
+                    let ctx = decoder.as_ptr();
+                    let time_base = (*ctx).pkt_timebase;
+                    let packet_ts = packet.pts().or(packet.dts());
+                    let fallback_pts_ms =
+                        packet_ts.unwrap_or(0) * 1000 * time_base.num.max(1) as i64
+                            / time_base.den.max(1) as i64;
+                    let canvas = if (*ctx).width > 0 && (*ctx).height > 0 {
+                        [(*ctx).width as u32, (*ctx).height as u32]
+                    } else {
+                        self.context.video_frame_rect
+                    };
+                    (fallback_pts_ms, canvas)
+                };
+                let has_rects = subtitle.rects().next().is_some();
+                let cues = crate::subtitle::cues_from_decoded_subtitle(
+                    &mut subtitle,
+                    fallback_pts_ms,
+                    canvas,
+                );
+                let mut subtitle_cues = self.context.subtitle_cues.blocking_write();
+                subtitle_cues.remove_ended_before(fallback_pts_ms);
+                if has_rects {
+                    for cue in cues {
+                        subtitle_cues.push(cue);
+                    }
+                } else {
+                    // empty picture subtitle means clear the screen
+                    subtitle_cues.close_open_cues(fallback_pts_ms);
+                }
+            }
+        }
+    }
+}
+impl PipelineWorker for SubtitleDecodeWorker {
+    type Command = SubtitleDecodeCommand;
+    fn handle_command(&mut self, command: SubtitleDecodeCommand) {
+        match command {
+            SubtitleDecodeCommand::SetDecoder(decoder) => {
+                self.context.decoder = decoder;
+                self.context.subtitle_cues.blocking_write().clear();
+            }
+        }
+    }
+    fn step(&mut self) -> bool {
+        let serial = self.context.packet_channel.serial();
+        if serial != self.context.serial {
+            if let Some(decoder) = &mut self.context.decoder {
+                decoder.flush();
+            }
+            self.context.serial = serial;
+        }
+        if let Some((packet, packet_serial)) = self.context.packet_channel.try_recv() {
+            if packet_serial == self.context.serial {
+                self.decode(&packet);
+            }
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// a video frame pulled for playing
+/// the conversion is waited for after the decoder lock is released
+pub enum VideoPlayFrame {
+    /// drawn by the yuv shader as it is
+    Yuv(Video),
+    /// being converted to rgba by the convert thread
+    Converting(oneshot::Receiver<Option<Video>>),
+}
+impl VideoPlayFrame {
+    pub async fn wait(self) -> Option<Video> {
+        match self {
+            VideoPlayFrame::Yuv(frame) => Some(frame),
+            VideoPlayFrame::Converting(reply_receiver) => reply_receiver.await.ok().flatten(),
+        }
+    }
+}
+/// a decoded frame to convert to the rgba frame shown by the ui
+struct ConvertRequest {
+    frame: Video,
+    output_rect: [u32; 2],
+    /// the swscale coefficients id and if the source is full range
+    color_details: (i32, bool),
+    reply: oneshot::Sender<Option<Video>>,
+}
+/// owns the swscale context, which must stay in one thread
+#[derive(Default)]
+struct VideoConverter {
+    converter: Option<scaling::Context>,
+    color_details: Option<(i32, bool)>,
+    /// the rgba frames are given back once the ui shows the next one
+    frame_pool: FramePool,
+}
+impl VideoConverter {
+    /// serve the requests until the sender is dropped
+    fn run(&mut self, request_receiver: Receiver<ConvertRequest>) {
+        info!("worker start");
+        while let Ok(request) = request_receiver.recv() {
+            let res = self.convert(&request.frame, request.output_rect, request.color_details);
+            if request.reply.send(res).is_err() {
+                info!("converted frame is not waited any more");
+            }
+        }
+        info!("worker exit");
+    }
+    /// set the new converter, only change the input format and size, the output keeps the
+    /// width and height which have been used in the ui thread
+    fn convert(
+        &mut self,
+        raw_frame: &Video,
+        output_rect: [u32; 2],
+        color_details: (i32, bool),
+    ) -> Option<Video> {
+        let [width, height] = output_rect;
+        let converter_matches = if let Some(converter) = &self.converter {
+            let input = converter.input();
+            input.format == raw_frame.format()
+                && input.width == raw_frame.width()
+                && input.height == raw_frame.height()
+        } else {
+            false
+        };
+        if !converter_matches {
+            info!(
+                "create video converter from {:?} {}x{}",
+                raw_frame.format(),
+                raw_frame.width(),
+                raw_frame.height()
+            );
+            match scaling::Context::get(
+                raw_frame.format(),
+                raw_frame.width(),
+                raw_frame.height(),
+                Pixel::RGBA,
+                width,
+                height,
+                scaling::Flags::BILINEAR,
+            ) {
+                Ok(converter) => {
+                    self.converter = Some(converter);
+                    self.color_details = None;
+                }
+                Err(e) => {
+                    warn!("create video converter error: {}", e);
+                    self.converter = None;
+                }
+            }
+        }
+        if let Some(converter) = &mut self.converter {
+            if self.color_details != Some(color_details) {
+                let (colorspace, full_range) = color_details;
+                info!(
+                    "set converter colorspace:{} full range:{}",
+                    colorspace, full_range
+                );
+                unsafe {
+                    // the rgba output is always full range
+                    if 0 > sws_setColorspaceDetails(
+                        converter.as_mut_ptr(),
+                        sws_getCoefficients(colorspace),
+                        full_range as i32,
+                        sws_getCoefficients(SWS_CS_DEFAULT as i32),
+                        1,
+                        0,
+                        1 << 16,
+                        1 << 16,
+                    ) {
+                        warn!("set converter colorspace details error");
+                    }
+                }
+                self.color_details = Some(color_details);
+            }
+            let mut res = self
+                .frame_pool
+                .get(AVPixelFormat::AV_PIX_FMT_RGBA, width, height)?;
+            if converter.run(raw_frame, &mut res).is_ok() {
+                if let Some(pts) = raw_frame.pts() {
+                    res.set_pts(Some(pts));
+                }
+                return Some(res);
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn filter_chain_text_is_one_segment() {
+        let mut chain = VideoFilterChain::default();
+        assert_eq!(chain.description(Some("font.ttf"), false), "null");
+        chain.set_filters_from_str(" select='not(mod(n\\,2))',scale=w=iw/2:h=-1 ");
+        assert_eq!(
+            chain.description(None, false),
+            "select='not(mod(n\\,2))',scale=w=iw/2:h=-1"
+        );
+    }
+
+    #[test]
+    fn watermark_is_opt_in() {
+        let mut chain = VideoFilterChain::default();
+        assert!(!chain.watermark);
+        chain.set_filters_from_str("hflip");
+        chain.watermark = true;
+        let description = chain.description(Some("font.ttf"), false);
+        assert!(description.starts_with("hflip,drawtext="));
+        assert!(description.contains("fontfile='font.ttf'"));
+    }
 }
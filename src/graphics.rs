@@ -0,0 +1,343 @@
+use std::{fs::File, path::PathBuf, sync::Arc};
+
+use eframe::{
+    egui_wgpu::{WgpuConfiguration, WgpuSetup, WgpuSetupCreateNew},
+    wgpu::{Adapter, Backends, DeviceType, Instance, InstanceDescriptor, Surface},
+};
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::CURRENT_EXE_PATH;
+
+/// the wgpu backends the window can be drawn with
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum GraphicsBackend {
+    /// every backend of the platform, wgpu picks the adapter
+    Auto,
+    Vulkan,
+    Gl,
+}
+impl GraphicsBackend {
+    pub const ALL: [GraphicsBackend; 3] = [
+        GraphicsBackend::Auto,
+        GraphicsBackend::Vulkan,
+        GraphicsBackend::Gl,
+    ];
+    pub fn name(&self) -> &'static str {
+        match self {
+            GraphicsBackend::Auto => "auto",
+            GraphicsBackend::Vulkan => "vulkan",
+            GraphicsBackend::Gl => "gl",
+        }
+    }
+    fn from_name(name: &str) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|backend| backend.name().eq_ignore_ascii_case(name))
+    }
+    fn backends(&self) -> Backends {
+        match self {
+            GraphicsBackend::Auto => Backends::all(),
+            GraphicsBackend::Vulkan => Backends::VULKAN,
+            GraphicsBackend::Gl => Backends::GL,
+        }
+    }
+}
+
+/// the preference of an adapter type, lower is better,
+/// none if the adapter can't be used with the settings
+fn adapter_rank(device_type: DeviceType, software_adapter: bool) -> Option<u8> {
+    if software_adapter && device_type != DeviceType::Cpu {
+        return None;
+    }
+    Some(match device_type {
+        DeviceType::DiscreteGpu => 0,
+        DeviceType::IntegratedGpu => 1,
+        DeviceType::VirtualGpu => 2,
+        DeviceType::Other => 3,
+        DeviceType::Cpu => 4,
+    })
+}
+
+/// how the window is drawn, read before the window is created
+/// the ui saves it beside the exe, the changes apply at the next start
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct GraphicsSettings {
+    pub backend: GraphicsBackend,
+    /// only use the software adapters like lavapipe or llvmpipe
+    pub software_adapter: bool,
+}
+impl Default for GraphicsSettings {
+    fn default() -> Self {
+        Self {
+            backend: GraphicsBackend::Vulkan,
+            software_adapter: false,
+        }
+    }
+}
+impl GraphicsSettings {
+    fn file_path() -> Option<PathBuf> {
+        if let Ok(exe_path) = CURRENT_EXE_PATH.as_ref() {
+            if let Some(exe_folder) = exe_path.parent() {
+                return Some(exe_folder.join("graphics.json"));
+            }
+        }
+        None
+    }
+    /// the saved settings, the default ones if there are none
+    pub fn load() -> Self {
+        if let Some(path) = Self::file_path() {
+            if let Ok(file) = File::open(&path) {
+                match serde_json::from_reader(file) {
+                    Ok(settings) => return settings,
+                    Err(e) => warn!("graphics settings {} err {}", path.display(), e),
+                }
+            }
+        }
+        Self::default()
+    }
+    pub fn save(&self) {
+        if let Some(path) = Self::file_path() {
+            match File::create(&path) {
+                Ok(file) => {
+                    if let Err(e) = serde_json::to_writer_pretty(file, self) {
+                        warn!("graphics settings save err {}", e);
+                    }
+                }
+                Err(e) => warn!("graphics settings {} create err {}", path.display(), e),
+            }
+        }
+    }
+    /// the command line wins over the saved settings for this run
+    /// `--backend <auto|vulkan|gl>` chooses the backend, `--software-adapter` forces a software one
+    pub fn apply_args(&mut self, mut args: impl Iterator<Item = String>) {
+        while let Some(arg) = args.next() {
+            if arg == "--software-adapter" {
+                self.software_adapter = true;
+            } else if let Some(name) = arg.strip_prefix("--backend") {
+                let name = if let Some(name) = name.strip_prefix('=') {
+                    Some(name.to_string())
+                } else if name.is_empty() {
+                    args.next()
+                } else {
+                    None
+                };
+                if let Some(backend) = name.as_deref().and_then(GraphicsBackend::from_name) {
+                    self.backend = backend;
+                } else {
+                    warn!(
+                        "unknown graphics backend {:?}, use auto, vulkan or gl",
+                        name
+                    );
+                }
+            }
+        }
+    }
+    /// the chosen backend first, then the others to try when it can't start
+    pub fn backend_order(&self) -> Vec<GraphicsBackend> {
+        let mut order = vec![self.backend];
+        for backend in [
+            GraphicsBackend::Vulkan,
+            GraphicsBackend::Gl,
+            GraphicsBackend::Auto,
+        ] {
+            if backend != self.backend {
+                order.push(backend);
+            }
+        }
+        order
+    }
+    /// the first backend of `backend_order` with an adapter the settings can use,
+    /// the window is only created once so the backends are probed before it
+    pub fn usable_backend(&self) -> Option<GraphicsBackend> {
+        self.first_usable_backend(|backend| {
+            let instance = Instance::new(&InstanceDescriptor {
+                backends: backend.backends(),
+                ..Default::default()
+            });
+            instance
+                .enumerate_adapters(backend.backends())
+                .iter()
+                .map(|adapter| adapter.get_info().device_type)
+                .collect()
+        })
+    }
+    /// `device_types` gives the types of the adapters a backend finds
+    fn first_usable_backend(
+        &self,
+        mut device_types: impl FnMut(GraphicsBackend) -> Vec<DeviceType>,
+    ) -> Option<GraphicsBackend> {
+        self.backend_order().into_iter().find(|backend| {
+            let usable = device_types(*backend)
+                .into_iter()
+                .any(|device_type| adapter_rank(device_type, self.software_adapter).is_some());
+            if !usable {
+                warn!("graphics backend {} has no usable adapter", backend.name());
+            }
+            usable
+        })
+    }
+    /// the wgpu setup of eframe for one backend
+    /// the hardware adapters are preferred, a software one is taken when there is nothing else
+    pub fn wgpu_configuration(&self, backend: GraphicsBackend) -> WgpuConfiguration {
+        let software_adapter = self.software_adapter;
+        WgpuConfiguration {
+            wgpu_setup: WgpuSetup::CreateNew(WgpuSetupCreateNew {
+                instance_descriptor: InstanceDescriptor {
+                    backends: backend.backends(),
+                    ..Default::default()
+                },
+                native_adapter_selector: Some(Arc::new(
+                    move |adapters: &[Adapter], surface: Option<&Surface<'_>>| {
+                        let adapter = adapters
+                            .iter()
+                            .filter(|adapter| {
+                                surface.is_none_or(|surface| adapter.is_surface_supported(surface))
+                            })
+                            .filter_map(|adapter| {
+                                adapter_rank(adapter.get_info().device_type, software_adapter)
+                                    .map(|rank| (rank, adapter))
+                            })
+                            .min_by_key(|(rank, _)| *rank)
+                            .map(|(_, adapter)| adapter)
+                            .ok_or_else(|| {
+                                format!("no usable adapter among {} found", adapters.len())
+                            })?;
+                        let adapter_info = adapter.get_info();
+                        info!(
+                            "graphics adapter {} {:?} {:?}",
+                            adapter_info.name, adapter_info.backend, adapter_info.device_type
+                        );
+                        Ok(adapter.clone())
+                    },
+                )),
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings_with_args(args: &[&str]) -> GraphicsSettings {
+        let mut settings = GraphicsSettings::default();
+        settings.apply_args(args.iter().map(|arg| arg.to_string()));
+        settings
+    }
+
+    #[test]
+    fn backend_args() {
+        let default = GraphicsSettings::default();
+        assert_eq!(settings_with_args(&[]), default);
+        assert_eq!(
+            settings_with_args(&["--backend", "gl"]).backend,
+            GraphicsBackend::Gl
+        );
+        assert_eq!(
+            settings_with_args(&["--backend=auto"]).backend,
+            GraphicsBackend::Auto
+        );
+        assert_eq!(
+            settings_with_args(&["--backend", "gl", "--backend=VULKAN"]).backend,
+            GraphicsBackend::Vulkan
+        );
+        // the media file opened with the player is not a graphics arg
+        assert_eq!(
+            settings_with_args(&["movie.mkv", "--backend=gl"]).backend,
+            GraphicsBackend::Gl
+        );
+    }
+
+    #[test]
+    fn unknown_backend_keeps_the_saved_one() {
+        let default = GraphicsSettings::default();
+        assert_eq!(settings_with_args(&["--backend", "metal"]), default);
+        assert_eq!(settings_with_args(&["--backend=dx12"]), default);
+        assert_eq!(settings_with_args(&["--backendgl"]), default);
+        assert_eq!(settings_with_args(&["--backend"]), default);
+    }
+
+    #[test]
+    fn software_adapter_arg() {
+        assert!(!GraphicsSettings::default().software_adapter);
+        let settings = settings_with_args(&["--software-adapter"]);
+        assert!(settings.software_adapter);
+        assert_eq!(settings.backend, GraphicsSettings::default().backend);
+        let settings = settings_with_args(&["--backend", "metal", "--software-adapter"]);
+        assert!(settings.software_adapter);
+    }
+
+    #[test]
+    fn chosen_backend_is_tried_first() {
+        let order = |backend| {
+            GraphicsSettings {
+                backend,
+                software_adapter: false,
+            }
+            .backend_order()
+        };
+        assert_eq!(
+            order(GraphicsBackend::Vulkan),
+            [
+                GraphicsBackend::Vulkan,
+                GraphicsBackend::Gl,
+                GraphicsBackend::Auto
+            ]
+        );
+        assert_eq!(
+            order(GraphicsBackend::Gl),
+            [
+                GraphicsBackend::Gl,
+                GraphicsBackend::Vulkan,
+                GraphicsBackend::Auto
+            ]
+        );
+        assert_eq!(
+            order(GraphicsBackend::Auto),
+            [
+                GraphicsBackend::Auto,
+                GraphicsBackend::Vulkan,
+                GraphicsBackend::Gl
+            ]
+        );
+    }
+    #[test]
+    fn software_adapter_only_takes_cpu_adapters() {
+        assert_eq!(adapter_rank(DeviceType::DiscreteGpu, false), Some(0));
+        assert_eq!(adapter_rank(DeviceType::Cpu, false), Some(4));
+        assert_eq!(adapter_rank(DeviceType::IntegratedGpu, true), None);
+        assert_eq!(adapter_rank(DeviceType::Cpu, true), Some(4));
+    }
+
+    #[test]
+    fn first_backend_with_an_adapter_is_used() {
+        let settings = GraphicsSettings::default();
+        let mut probed = vec![];
+        // no vulkan driver, gl falls back to a software adapter
+        let backend = settings.first_usable_backend(|backend| {
+            probed.push(backend);
+            match backend {
+                GraphicsBackend::Vulkan => vec![],
+                GraphicsBackend::Gl => vec![DeviceType::Cpu],
+                GraphicsBackend::Auto => vec![DeviceType::DiscreteGpu],
+            }
+        });
+        assert_eq!(backend, Some(GraphicsBackend::Gl));
+        assert_eq!(probed, [GraphicsBackend::Vulkan, GraphicsBackend::Gl]);
+
+        let software = GraphicsSettings {
+            backend: GraphicsBackend::Vulkan,
+            software_adapter: true,
+        };
+        let backend = software.first_usable_backend(|backend| match backend {
+            GraphicsBackend::Vulkan => vec![DeviceType::DiscreteGpu],
+            GraphicsBackend::Gl => vec![DeviceType::IntegratedGpu],
+            GraphicsBackend::Auto => vec![DeviceType::DiscreteGpu, DeviceType::Cpu],
+        });
+        assert_eq!(backend, Some(GraphicsBackend::Auto));
+        assert_eq!(software.first_usable_backend(|_| vec![]), None);
+    }
+}
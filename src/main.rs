@@ -8,14 +8,10 @@
 use std::{
     error::Error,
     fmt::Display,
-    path::PathBuf,
+    path::{Path, PathBuf},
     sync::{Arc, LazyLock},
 };
 
-use eframe::{
-    egui_wgpu::{WgpuConfiguration, WgpuSetup, WgpuSetupCreateNew},
-    wgpu::{Backends, InstanceDescriptor},
-};
 use egui::{IconData, ImageSource, Vec2, include_image};
 
 use tracing::{Level, info, warn};
@@ -24,8 +20,15 @@ use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 mod ai_sub_title;
 mod appui;
 mod audio_play;
+mod buffer;
+mod clock;
 mod decode;
+mod graphics;
+mod player;
+mod player_state;
 mod present_data_manage;
+mod subtitle;
+mod yuv_render;
 
 const WINDOW_ICON: ImageSource = include_image!("../resources/play.ico");
 static CURRENT_EXE_PATH: LazyLock<PlayerResult<PathBuf>> = LazyLock::new(|| {
@@ -35,20 +38,97 @@ static CURRENT_EXE_PATH: LazyLock<PlayerResult<PathBuf>> = LazyLock::new(|| {
         Err(PlayerError::Internal("exe path get err".to_string()))
     }
 });
+/// what went wrong, grouped by what the user can do about it
 #[derive(Debug, Clone)]
 pub enum PlayerError {
+    /// a file could not be found or read
+    Io {
+        path: PathBuf,
+        source: Arc<std::io::Error>,
+    },
+    /// ffmpeg can not open the file as a media container
+    UnsupportedFormat {
+        path: PathBuf,
+        source: ffmpeg_the_third::Error,
+    },
+    /// the file has neither an audio nor a video stream
+    NoPlayableStream {
+        path: PathBuf,
+    },
+    /// the decoder of a stream could not be opened
+    Codec {
+        codec: String,
+        source: ffmpeg_the_third::Error,
+    },
+    /// the hardware decoding could not be set up
+    Hardware(String),
+    /// the audio or video filter graph could not be built
+    Filter(String),
+    /// the audio output device could not be opened
+    AudioOutput(Arc<dyn Error + Send + Sync>),
+    /// the speech recognition model is missing or broken
+    SubtitleModel(Arc<dyn Error + Send + Sync>),
+    /// the subtitle file can not be used
+    SubtitleFile {
+        path: PathBuf,
+        reason: String,
+    },
+    /// the stream index is not a track of the opened input
+    TrackNotFound(usize),
+    /// the command needs an opened input
+    NoInput,
     Internal(String),
 }
+impl PlayerError {
+    pub fn io(path: &Path, source: std::io::Error) -> Self {
+        Self::Io {
+            path: path.to_path_buf(),
+            source: Arc::new(source),
+        }
+    }
+    pub fn audio_output(source: impl Into<Box<dyn Error + Send + Sync>>) -> Self {
+        Self::AudioOutput(Arc::from(source.into()))
+    }
+    pub fn subtitle_model(source: impl Into<Box<dyn Error + Send + Sync>>) -> Self {
+        Self::SubtitleModel(Arc::from(source.into()))
+    }
+}
 impl Display for PlayerError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
+            Self::Io { path, source } => write!(f, "io error on {}: {}", path.display(), source)?,
+            Self::UnsupportedFormat { path, source } => {
+                write!(f, "unsupported format {}: {}", path.display(), source)?
+            }
+            Self::NoPlayableStream { path } => {
+                write!(f, "no audio or video stream in {}", path.display())?
+            }
+            Self::Codec { codec, source } => write!(f, "codec {} error: {}", codec, source)?,
+            Self::Hardware(s) => write!(f, "hardware decoding error: {}", s)?,
+            Self::Filter(s) => write!(f, "filter error: {}", s)?,
+            Self::AudioOutput(source) => write!(f, "audio output error: {}", source)?,
+            Self::SubtitleModel(source) => write!(f, "subtitle model error: {}", source)?,
+            Self::SubtitleFile { path, reason } => {
+                write!(f, "subtitle file {}: {}", path.display(), reason)?
+            }
+            Self::TrackNotFound(stream_index) => write!(f, "track #{} not found", stream_index)?,
+            Self::NoInput => write!(f, "no input opened")?,
             Self::Internal(s) => write!(f, "error: {}", s)?,
         }
 
         Ok(())
     }
 }
-impl Error for PlayerError {}
+impl Error for PlayerError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Self::Io { source, .. } => Some(source.as_ref()),
+            Self::UnsupportedFormat { source, .. } | Self::Codec { source, .. } => Some(source),
+            Self::AudioOutput(source) | Self::SubtitleModel(source) => Some(source.as_ref()),
+            _ => None,
+        }
+    }
+}
 pub type PlayerResult<T> = std::result::Result<T, PlayerError>;
 
 /// main fun init log, init main ui type Appui
@@ -68,45 +148,47 @@ fn main() {
     let span = tracing::span!(Level::INFO, "main");
     let _main_entered = span.enter();
     info!("enter main span");
-    if let Ok(tiny_app_ui) = appui::AppUi::new() {
-        let mut options = eframe::NativeOptions {
-            renderer: eframe::Renderer::Wgpu,
-            wgpu_options: WgpuConfiguration {
-                wgpu_setup: WgpuSetup::CreateNew(WgpuSetupCreateNew {
-                    instance_descriptor: InstanceDescriptor {
-                        backends: Backends::VULKAN,
-                        ..Default::default()
-                    },
-                    ..Default::default()
-                }),
+    let mut graphics_settings = graphics::GraphicsSettings::load();
+    graphics_settings.apply_args(std::env::args().skip(1));
+    // winit can't create a second event loop, so the backend is chosen before the window
+    let backend = graphics_settings
+        .usable_backend()
+        .unwrap_or(graphics_settings.backend);
+    match appui::AppUi::new() {
+        Ok(mut tiny_app_ui) => {
+            let mut options = eframe::NativeOptions {
+                renderer: eframe::Renderer::Wgpu,
+                wgpu_options: graphics_settings.wgpu_configuration(backend),
                 ..Default::default()
-            },
-            ..Default::default()
-        };
-        if let ImageSource::Bytes { bytes, .. } = WINDOW_ICON {
-            if let Ok(img) = image::load_from_memory(&bytes) {
-                options.viewport.icon = Some(Arc::new(IconData {
-                    width: img.width(),
-                    height: img.height(),
-                    rgba: img.as_bytes().to_vec(),
-                }));
+            };
+            if let ImageSource::Bytes { bytes, .. } = WINDOW_ICON {
+                if let Ok(img) = image::load_from_memory(&bytes) {
+                    options.viewport.icon = Some(Arc::new(IconData {
+                        width: img.width(),
+                        height: img.height(),
+                        rgba: img.as_bytes().to_vec(),
+                    }));
+                }
             }
-        }
-        options.centered = true;
-        options.viewport.inner_size = Some(Vec2::new(900.0, 700.0));
+            options.centered = true;
+            options.viewport.inner_size = Some(Vec2::new(900.0, 700.0));
 
-        if let Err(e) = eframe::run_native(
-            "tiny player",
-            options,
-            Box::new(|cc| {
-                egui_extras::install_image_loaders(&cc.egui_ctx);
-                tiny_app_ui.replace_fonts(&cc.egui_ctx);
-                Ok(Box::new(tiny_app_ui))
-            }),
-        ) {
-            warn!("eframe start error {}", e.to_string());
+            info!("start with graphics backend {}", backend.name());
+            if let Err(e) = eframe::run_native(
+                "tiny player",
+                options,
+                Box::new(|cc| {
+                    egui_extras::install_image_loaders(&cc.egui_ctx);
+                    tiny_app_ui.replace_fonts(&cc.egui_ctx);
+                    tiny_app_ui.install_video_renderer(cc);
+                    Ok(Box::new(tiny_app_ui))
+                }),
+            ) {
+                warn!("eframe start error {}", e.to_string());
+            }
+        }
+        Err(e) => {
+            warn!("appui construct err {}", e.to_string());
         }
-    } else if let Err(e) = appui::AppUi::new() {
-        warn!("appui construct err {}", e.to_string());
     }
 }
@@ -0,0 +1,1145 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::{
+        Arc,
+        mpsc::{RecvTimeoutError, SyncSender},
+    },
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
+
+use ffmpeg_the_third::frame::Video;
+use keepawake::KeepAwake;
+use tokio::{
+    runtime::{Handle, Runtime},
+    sync::{
+        Notify, RwLock, broadcast, mpsc,
+        watch::{self, Receiver, Sender},
+    },
+};
+use tracing::{info, warn};
+
+use crate::{
+    PlayerError, PlayerResult,
+    ai_sub_title::{SubtitleWorker, UsedModel},
+    audio_play::AudioPlayer,
+    buffer::{BufferHealth, BufferLimits},
+    clock::{ClockSource, FrameStats, PlaybackClock},
+    decode::{
+        ColorMatrixOverride, ColorRangeOverride, DecodeStats, DecoderThreading, HardwarePolicy,
+        MainStream, SeekMode, StreamTrack, TinyDecoder, VideoFilterChain, VideoRenderer,
+    },
+    player_state::PlayerState,
+    present_data_manage::{DataManageContextBuilder, PresentDataManager},
+    subtitle::SubtitleCues,
+};
+
+/// the events are dropped for the subscribers lagging more than this
+const EVENT_CHANNEL_CAPACITY: usize = 64;
+/// the position is published when it moved this much since the last event
+const POSITION_EVENT_INTERVAL_MS: i64 = 250;
+/// the buffering ends once the main stream packets cover this much
+const BUFFERING_RESUME_SECONDS: f64 = 1.0;
+/// the engine drives the playback and publishes a snapshot at least this often
+const ENGINE_TICK_MS: u64 = 10;
+
+/// what the player reports to its front-ends
+#[derive(Debug, Clone)]
+pub enum PlayerEvent {
+    StateChanged(PlayerState),
+    /// a new input is opened, the playback is paused at its start
+    Opened(PathBuf),
+    /// the play position in ms from the media start
+    Position(i64),
+    /// the tracks of the opened input
+    TracksChanged {
+        audio_tracks: Vec<StreamTrack>,
+        subtitle_tracks: Vec<StreamTrack>,
+    },
+    AudioTrackChanged(usize),
+    SubtitleTrackChanged(Option<usize>),
+    VolumeChanged(f32),
+    /// a command failed, the player keeps its state unless it is `PlayerState::Error`
+    Error(PlayerError),
+}
+
+/// what the front-ends show, published by the engine after every command and tick
+/// the events are sent after the snapshot which already contains their changes
+#[derive(Debug, Clone)]
+pub struct PlayerSnapshot {
+    pub state: PlayerState,
+    /// false when the playback is asked to go on, whatever the state is
+    pub paused: bool,
+    /// the play position in the main stream time base
+    pub timestamp: i64,
+    pub end_timestamp: i64,
+    /// the play position in ms from the media start
+    pub position_ms: i64,
+    /// the start time of the input, subtitle files beside the video are relative to it
+    pub start_time_ms: i64,
+    pub end_time_text: String,
+    pub volume: f32,
+    pub speed: f64,
+    /// the model generating the subtitle from the audio
+    pub used_model: UsedModel,
+    pub audio_tracks: Vec<StreamTrack>,
+    pub audio_stream_index: usize,
+    pub subtitle_tracks: Vec<StreamTrack>,
+    pub subtitle_stream_index: Option<usize>,
+    /// the cues of the embedded subtitle, read them with `try_read` only
+    pub subtitle_cues: Arc<RwLock<SubtitleCues>>,
+    /// the attached picture of the input, found by the demux task after the input is opened
+    pub cover_pic: Option<Arc<Vec<u8>>>,
+    pub color_matrix: ColorMatrixOverride,
+    pub color_range: ColorRangeOverride,
+    pub hardware_policy: HardwarePolicy,
+    pub video_renderer: VideoRenderer,
+    pub video_decode_path: String,
+    pub video_codec_name: String,
+    pub audio_codec_name: String,
+    pub video_frame_rate: f64,
+    pub video_frame_rect: [u32; 2],
+    pub decode_stats: DecodeStats,
+    pub buffer_health: BufferHealth,
+    pub clock_source: ClockSource,
+    pub av_offset_ms: Option<f64>,
+    pub audio_latency_ms: f64,
+    pub frame_stats: FrameStats,
+}
+
+/// what the front-ends ask the engine to do, run in the order they are sent
+enum PlayerCommand {
+    Open(PathBuf),
+    TogglePause,
+    Seek(i64, SeekMode),
+    SetVolume(f32),
+    SetAudioTrack(usize),
+    SetSubtitleTrack(Option<usize>),
+    SetSpeed(f64),
+    SetVideoFilterChain(VideoFilterChain),
+    SetColorOverride(ColorMatrixOverride, ColorRangeOverride),
+    SetVideoRenderer(VideoRenderer),
+    SetHardwarePolicy(HardwarePolicy),
+    SetDecoderThreading(DecoderThreading, DecoderThreading),
+    SetBufferLimits(BufferLimits),
+    SetClockSource(ClockSource),
+    SetUsedModel(UsedModel),
+    StepFrameForward,
+    StepFrameBackward,
+    /// stop the engine thread, sent when the player is dropped
+    Shutdown,
+}
+
+/// the front-end side of the playback engine, it never waits on the decoder
+/// the commands are run by the engine thread, their failures come back as `PlayerEvent::Error`
+pub struct Player {
+    async_rt: Runtime,
+    command_sender: std::sync::mpsc::Sender<PlayerCommand>,
+    engine_thread: Option<JoinHandle<()>>,
+    event_sender: broadcast::Sender<PlayerEvent>,
+    snapshot_receiver: Receiver<PlayerSnapshot>,
+    video_frame_receiver: Receiver<Arc<Video>>,
+    subtitle_text_receiver: mpsc::Receiver<String>,
+}
+impl Player {
+    /// start the engine thread, return once the decoder, the audio output and the
+    /// subtitle model are ready
+    pub fn new() -> PlayerResult<Self> {
+        let async_rt = tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()
+            .map_err(|e| PlayerError::Internal(e.to_string()))?;
+        let rt = async_rt.handle().clone();
+        let (event_sender, _) = broadcast::channel(EVENT_CHANNEL_CAPACITY);
+        let (video_frame_sender, video_frame_receiver) = watch::channel(Arc::new(Video::empty()));
+        let subtitle_channel = mpsc::channel(10);
+        let (command_sender, command_receiver) = std::sync::mpsc::channel();
+        let (init_sender, init_receiver) = std::sync::mpsc::sync_channel(1);
+
+        let engine_event_sender = event_sender.clone();
+        let engine_thread = std::thread::Builder::new()
+            .name("player engine".to_string())
+            .spawn(move || {
+                // the audio output can not leave the thread it is opened on
+                let engine = PlayerEngine::new(
+                    rt,
+                    engine_event_sender,
+                    Arc::new(video_frame_sender),
+                    subtitle_channel.0,
+                );
+                PlayerEngine::start(engine, init_sender, command_receiver);
+            })
+            .map_err(|e| PlayerError::Internal(e.to_string()))?;
+        let snapshot_receiver = init_receiver
+            .recv()
+            .map_err(|e| PlayerError::Internal(e.to_string()))??;
+
+        Ok(Self {
+            async_rt,
+            command_sender,
+            engine_thread: Some(engine_thread),
+            event_sender,
+            snapshot_receiver,
+            video_frame_receiver,
+            subtitle_text_receiver: subtitle_channel.1,
+        })
+    }
+    /// receive the events sent from now on
+    pub fn subscribe(&self) -> broadcast::Receiver<PlayerEvent> {
+        self.event_sender.subscribe()
+    }
+    /// the runtime the decode and present tasks run on
+    pub fn runtime_handle(&self) -> Handle {
+        self.async_rt.handle().clone()
+    }
+    /// the last snapshot published by the engine
+    pub fn snapshot(&self) -> PlayerSnapshot {
+        self.snapshot_receiver.borrow().clone()
+    }
+    /// the last frame shown, converted to rgba
+    pub fn current_video_frame(&self) -> Arc<Video> {
+        self.video_frame_receiver.borrow().clone()
+    }
+    /// text generated by the subtitle model since the last call
+    pub fn try_recv_generated_subtitle(&mut self) -> Option<String> {
+        self.subtitle_text_receiver.try_recv().ok()
+    }
+    fn send_command(&self, command: PlayerCommand) {
+        if self.command_sender.send(command).is_err() {
+            warn!("player engine is stopped");
+        }
+    }
+
+    /// open a media file, the playback is paused at its start
+    pub fn open(&self, path: &Path) {
+        self.send_command(PlayerCommand::Open(path.to_path_buf()));
+    }
+    pub fn toggle_pause(&self) {
+        self.send_command(PlayerCommand::TogglePause);
+    }
+    /// jump to a main stream timestamp, only the last of the queued seeks is run
+    pub fn seek(&self, timestamp: i64, seek_mode: SeekMode) {
+        self.send_command(PlayerCommand::Seek(timestamp, seek_mode));
+    }
+    pub fn set_volume(&self, volume: f32) {
+        self.send_command(PlayerCommand::SetVolume(volume));
+    }
+    /// switch the audio track and keep playing from the current position
+    pub fn set_audio_track(&self, stream_index: usize) {
+        self.send_command(PlayerCommand::SetAudioTrack(stream_index));
+    }
+    /// `None` turns the embedded subtitle off
+    pub fn set_subtitle_track(&self, stream_index: Option<usize>) {
+        self.send_command(PlayerCommand::SetSubtitleTrack(stream_index));
+    }
+    pub fn set_speed(&self, speed: f64) {
+        self.send_command(PlayerCommand::SetSpeed(speed));
+    }
+    pub fn set_video_filter_chain(&self, chain: VideoFilterChain) {
+        self.send_command(PlayerCommand::SetVideoFilterChain(chain));
+    }
+    pub fn set_color_override(&self, matrix: ColorMatrixOverride, range: ColorRangeOverride) {
+        self.send_command(PlayerCommand::SetColorOverride(matrix, range));
+    }
+    /// the ui tells which renderer draws the next frames
+    pub fn set_video_renderer(&self, renderer: VideoRenderer) {
+        self.send_command(PlayerCommand::SetVideoRenderer(renderer));
+    }
+    /// reopen the video decoder with the new hardware policy and keep playing from the current position
+    pub fn set_hardware_policy(&self, policy: HardwarePolicy) {
+        self.send_command(PlayerCommand::SetHardwarePolicy(policy));
+    }
+    /// reopen the decoders with the new threading and keep playing from the current position
+    pub fn set_decoder_threading(
+        &self,
+        video_threading: DecoderThreading,
+        audio_threading: DecoderThreading,
+    ) {
+        self.send_command(PlayerCommand::SetDecoderThreading(
+            video_threading,
+            audio_threading,
+        ));
+    }
+    pub fn set_buffer_limits(&self, limits: BufferLimits) {
+        self.send_command(PlayerCommand::SetBufferLimits(limits));
+    }
+    pub fn set_clock_source(&self, source: ClockSource) {
+        self.send_command(PlayerCommand::SetClockSource(source));
+    }
+    /// choose the model generating the subtitle from the audio
+    pub fn set_used_model(&self, used_model: UsedModel) {
+        self.send_command(PlayerCommand::SetUsedModel(used_model));
+    }
+    /// show the next frame while paused
+    pub fn step_frame_forward(&self) {
+        self.send_command(PlayerCommand::StepFrameForward);
+    }
+    /// show the previous frame while paused, it is shown once it is decoded again
+    pub fn step_frame_backward(&self) {
+        self.send_command(PlayerCommand::StepFrameBackward);
+    }
+}
+impl Drop for Player {
+    /// the engine is stopped before the runtime its tasks run on
+    fn drop(&mut self) {
+        self.send_command(PlayerCommand::Shutdown);
+        if let Some(engine_thread) = self.engine_thread.take() {
+            if engine_thread.join().is_err() {
+                warn!("join player engine thread err");
+            }
+        }
+    }
+}
+
+/// the playback engine, it owns the decoder, the present task and the audio output
+/// and runs on its own thread so waiting on the decoder never stops a front-end
+struct PlayerEngine {
+    async_rt: Handle,
+    tiny_decoder: Arc<RwLock<TinyDecoder>>,
+    audio_player: AudioPlayer,
+    _present_data_manager: PresentDataManager,
+    used_model: Arc<RwLock<UsedModel>>,
+    video_frame_sender: Arc<Sender<Arc<Video>>>,
+    main_stream_current_timestamp: Arc<RwLock<i64>>,
+    playback_clock: Arc<RwLock<PlaybackClock>>,
+    pause_flag: (Sender<bool>, Receiver<bool>),
+    playback_finished_receiver: Receiver<bool>,
+    data_thread_notify: Arc<Notify>,
+    event_sender: broadcast::Sender<PlayerEvent>,
+    /// sent after the next snapshot is published
+    pending_events: Vec<PlayerEvent>,
+    state: PlayerState,
+    last_position_ms: Option<i64>,
+    cover_pic: Option<Arc<Vec<u8>>>,
+    frame_step_pending_flag: bool,
+    stepped_while_paused_flag: bool,
+    /// held while playing
+    keep_awake: Option<KeepAwake>,
+}
+impl PlayerEngine {
+    fn new(
+        rt: Handle,
+        event_sender: broadcast::Sender<PlayerEvent>,
+        video_frame_sender: Arc<Sender<Arc<Video>>>,
+        subtitle_sender: mpsc::Sender<String>,
+    ) -> PlayerResult<Self> {
+        let tiny_decoder = TinyDecoder::new()?;
+        let tiny_decoder = Arc::new(RwLock::new(tiny_decoder));
+        let used_model = Arc::new(RwLock::new(UsedModel::Empty));
+        let subtitle_worker = SubtitleWorker::new(subtitle_sender, event_sender.clone())?;
+        let audio_player = AudioPlayer::new()?;
+        let main_stream_current_timestamp = Arc::new(RwLock::new(0));
+        let pause_flag = watch::channel(true);
+        let (playback_finished_sender, playback_finished_receiver) = watch::channel(false);
+        let playback_clock = Arc::new(RwLock::new(PlaybackClock::default()));
+
+        let data_thread_notify = Arc::new(Notify::new());
+        let data_manage_context = DataManageContextBuilder::default()
+            .data_thread_notify(data_thread_notify.clone())
+            .tiny_decoder(tiny_decoder.clone())
+            .used_model(used_model.clone())
+            .subtitle_worker(subtitle_worker)
+            .video_frame_sender(video_frame_sender.clone())
+            .audio_sink(audio_player.sink())
+            .main_stream_current_timestamp(main_stream_current_timestamp.clone())
+            .playback_clock(playback_clock.clone())
+            .pause_flag_receiver(pause_flag.1.clone())
+            .playback_finished_sender(Arc::new(playback_finished_sender))
+            .runtime_handle(rt.clone())
+            .build()
+            .map_err(|e| PlayerError::Internal(e.to_string()))?;
+        let present_data_manager = PresentDataManager::new(data_manage_context);
+
+        Ok(Self {
+            async_rt: rt,
+            tiny_decoder,
+            audio_player,
+            _present_data_manager: present_data_manager,
+            used_model,
+            video_frame_sender,
+            main_stream_current_timestamp,
+            playback_clock,
+            pause_flag,
+            playback_finished_receiver,
+            data_thread_notify,
+            event_sender,
+            pending_events: vec![],
+            state: PlayerState::Idle,
+            last_position_ms: None,
+            cover_pic: None,
+            frame_step_pending_flag: false,
+            stepped_while_paused_flag: false,
+            keep_awake: None,
+        })
+    }
+    /// give the first snapshot or the construct error to `Player::new`, then run the commands
+    fn start(
+        engine: PlayerResult<Self>,
+        init_sender: SyncSender<PlayerResult<Receiver<PlayerSnapshot>>>,
+        command_receiver: std::sync::mpsc::Receiver<PlayerCommand>,
+    ) {
+        match engine {
+            Ok(engine) => {
+                let (snapshot_sender, snapshot_receiver) = watch::channel(engine.snapshot());
+                if init_sender.send(Ok(snapshot_receiver)).is_ok() {
+                    engine.run(command_receiver, snapshot_sender);
+                }
+            }
+            Err(e) => {
+                if init_sender.send(Err(e)).is_err() {
+                    warn!("player construct result lost");
+                }
+            }
+        }
+    }
+    /// run the commands and drive the playback until the player is dropped
+    fn run(
+        mut self,
+        command_receiver: std::sync::mpsc::Receiver<PlayerCommand>,
+        snapshot_sender: Sender<PlayerSnapshot>,
+    ) {
+        loop {
+            let mut commands = vec![];
+            match command_receiver.recv_timeout(Duration::from_millis(ENGINE_TICK_MS)) {
+                Ok(command) => commands.push(command),
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+            commands.extend(command_receiver.try_iter());
+            // the seeks sent while scrubbing are replaced by the last one
+            let last_seek = commands
+                .iter()
+                .rposition(|command| matches!(command, PlayerCommand::Seek(..)));
+            for (i, command) in commands.into_iter().enumerate() {
+                if let PlayerCommand::Shutdown = command {
+                    info!("player engine stopped");
+                    return;
+                }
+                if matches!(command, PlayerCommand::Seek(..)) && Some(i) != last_seek {
+                    continue;
+                }
+                self.handle_command(command);
+            }
+            self.tick();
+            snapshot_sender.send_replace(self.snapshot());
+            for event in self.pending_events.drain(..) {
+                // nobody listening is not an error
+                let _ = self.event_sender.send(event);
+            }
+        }
+    }
+    fn handle_command(&mut self, command: PlayerCommand) {
+        let res = match command {
+            PlayerCommand::Open(path) => self.open(&path),
+            PlayerCommand::TogglePause => {
+                self.toggle_pause();
+                Ok(())
+            }
+            PlayerCommand::Seek(timestamp, seek_mode) => {
+                self.seek(timestamp, seek_mode);
+                Ok(())
+            }
+            PlayerCommand::SetVolume(volume) => {
+                self.set_volume(volume);
+                Ok(())
+            }
+            PlayerCommand::SetAudioTrack(stream_index) => self.set_audio_track(stream_index),
+            PlayerCommand::SetSubtitleTrack(stream_index) => self.set_subtitle_track(stream_index),
+            PlayerCommand::SetSpeed(speed) => self.set_speed(speed),
+            PlayerCommand::SetVideoFilterChain(chain) => self.set_video_filter_chain(chain),
+            PlayerCommand::SetColorOverride(matrix, range) => {
+                self.set_color_override(matrix, range);
+                Ok(())
+            }
+            PlayerCommand::SetVideoRenderer(renderer) => {
+                self.set_video_renderer(renderer);
+                Ok(())
+            }
+            PlayerCommand::SetHardwarePolicy(policy) => self.set_hardware_policy(policy),
+            PlayerCommand::SetDecoderThreading(video_threading, audio_threading) => {
+                self.set_decoder_threading(video_threading, audio_threading)
+            }
+            PlayerCommand::SetBufferLimits(limits) => {
+                self.set_buffer_limits(limits);
+                Ok(())
+            }
+            PlayerCommand::SetClockSource(source) => {
+                self.set_clock_source(source);
+                Ok(())
+            }
+            PlayerCommand::SetUsedModel(used_model) => {
+                *self.async_rt.block_on(self.used_model.write()) = used_model;
+                Ok(())
+            }
+            PlayerCommand::StepFrameForward => {
+                self.step_frame_forward();
+                Ok(())
+            }
+            PlayerCommand::StepFrameBackward => {
+                self.step_frame_backward();
+                Ok(())
+            }
+            PlayerCommand::Shutdown => Ok(()),
+        };
+        if let Err(e) = res {
+            warn!("player command err {}", e);
+            self.send_event(PlayerEvent::Error(e));
+        }
+    }
+    fn send_event(&mut self, event: PlayerEvent) {
+        self.pending_events.push(event);
+    }
+    /// move to the next state if the transition is allowed
+    fn change_state(&mut self, next: PlayerState) -> bool {
+        if next == self.state {
+            return true;
+        }
+        if !self.state.can_change_to(next) {
+            warn!("invalid player state change {:?} -> {:?}", self.state, next);
+            return false;
+        }
+        info!("player state {:?} -> {:?}", self.state, next);
+        self.state = next;
+        self.update_keep_awake();
+        self.send_event(PlayerEvent::StateChanged(next));
+        true
+    }
+    /// the display stays on while playing, seeking and buffering keep what playing took
+    fn update_keep_awake(&mut self) {
+        match self.state {
+            PlayerState::Playing => {
+                if self.keep_awake.is_none() {
+                    match keepawake::Builder::default()
+                        .display(true)
+                        .idle(true)
+                        .app_name("tiny_player")
+                        .reason("video play")
+                        .create()
+                    {
+                        Ok(keep_awake) => self.keep_awake = Some(keep_awake),
+                        Err(e) => warn!("keep awake err: {}", e),
+                    }
+                }
+            }
+            PlayerState::Seeking | PlayerState::Buffering => {}
+            PlayerState::Idle
+            | PlayerState::Opening
+            | PlayerState::Paused
+            | PlayerState::Ended
+            | PlayerState::Error => self.keep_awake = None,
+        }
+    }
+    fn is_paused(&self) -> bool {
+        *self.pause_flag.1.borrow()
+    }
+    fn snapshot(&self) -> PlayerSnapshot {
+        let tiny_decoder = self.async_rt.block_on(self.tiny_decoder.read());
+        let timestamp = *self
+            .async_rt
+            .block_on(self.main_stream_current_timestamp.read());
+        let clock = self.async_rt.block_on(self.playback_clock.read());
+        PlayerSnapshot {
+            state: self.state,
+            paused: self.is_paused(),
+            timestamp,
+            end_timestamp: tiny_decoder.end_ts(),
+            position_ms: tiny_decoder.main_stream_ts_to_ms(timestamp)
+                - tiny_decoder.start_time_ms(),
+            start_time_ms: tiny_decoder.start_time_ms(),
+            end_time_text: tiny_decoder.end_time_formatted_string().clone(),
+            volume: *self.audio_player.current_volumn(),
+            speed: tiny_decoder.playback_speed(),
+            used_model: self.async_rt.block_on(self.used_model.read()).clone(),
+            audio_tracks: tiny_decoder.audio_tracks().clone(),
+            audio_stream_index: tiny_decoder.audio_stream_index(),
+            subtitle_tracks: tiny_decoder.subtitle_tracks().clone(),
+            subtitle_stream_index: tiny_decoder.subtitle_stream_index(),
+            subtitle_cues: tiny_decoder.subtitle_cues(),
+            cover_pic: self.cover_pic.clone(),
+            color_matrix: tiny_decoder.color_matrix_override(),
+            color_range: tiny_decoder.color_range_override(),
+            hardware_policy: tiny_decoder.hardware_policy(),
+            video_renderer: tiny_decoder.video_renderer(),
+            video_decode_path: tiny_decoder.video_decode_path(),
+            video_codec_name: tiny_decoder.video_codec_name().clone(),
+            audio_codec_name: tiny_decoder.audio_codec_name().clone(),
+            video_frame_rate: tiny_decoder.video_frame_rate(),
+            video_frame_rect: *tiny_decoder.video_frame_rect(),
+            decode_stats: self.async_rt.block_on(tiny_decoder.decode_stats()),
+            buffer_health: tiny_decoder.buffer_health(),
+            clock_source: clock.source(),
+            av_offset_ms: clock.av_offset_ms(),
+            audio_latency_ms: clock.audio_latency_ms(),
+            frame_stats: clock.frame_stats(),
+        }
+    }
+
+    fn open(&mut self, path: &Path) -> PlayerResult<()> {
+        if !self.change_state(PlayerState::Opening) {
+            return Err(PlayerError::Internal(
+                "an input is being opened".to_string(),
+            ));
+        }
+        let res = self.open_input(path);
+        if res.is_err() {
+            self.change_state(PlayerState::Error);
+        }
+        res
+    }
+    fn open_input(&mut self, path: &Path) -> PlayerResult<()> {
+        let tracks = {
+            let mut tiny_decoder = self.async_rt.block_on(self.tiny_decoder.write());
+            if self.pause_flag.0.send(true).is_err() {
+                warn!("change pause flag err");
+                return Err(PlayerError::Internal("change pause flag err".to_string()));
+            }
+            if let Err(e) = self
+                .async_rt
+                .block_on(tiny_decoder.set_file_path_and_init_par(path))
+            {
+                warn!("reset file path error!");
+                return Err(e);
+            }
+            (
+                tiny_decoder.audio_tracks().clone(),
+                tiny_decoder.subtitle_tracks().clone(),
+            )
+        };
+        self.audio_player.pause();
+        self.audio_player.source_queue_skip_to_end();
+        self.frame_step_pending_flag = false;
+        self.stepped_while_paused_flag = false;
+        self.last_position_ms = None;
+        self.cover_pic = None;
+        self.async_rt
+            .block_on(self.playback_clock.write())
+            .reset_frame_stats();
+        *self
+            .async_rt
+            .block_on(self.main_stream_current_timestamp.write()) = 0;
+        self.video_frame_sender
+            .send_replace(Arc::new(Video::empty()));
+        info!("input opened {:?}", path);
+        self.change_state(PlayerState::Paused);
+        self.send_event(PlayerEvent::Opened(path.to_path_buf()));
+        let (audio_tracks, subtitle_tracks) = tracks;
+        self.send_event(PlayerEvent::TracksChanged {
+            audio_tracks,
+            subtitle_tracks,
+        });
+        Ok(())
+    }
+    fn toggle_pause(&mut self) {
+        let paused = self.is_paused();
+        self.set_paused(!paused);
+    }
+    fn set_paused(&mut self, paused: bool) {
+        // the playback is already stopped at the end
+        if !self.state.has_input() || (paused && self.state == PlayerState::Ended) {
+            return;
+        }
+        if !paused && self.state == PlayerState::Ended {
+            // playing again after the end starts from the beginning
+            let start_ts = {
+                let tiny_decoder = self.async_rt.block_on(self.tiny_decoder.read());
+                tiny_decoder.ms_to_main_stream_ts(tiny_decoder.start_time_ms())
+            };
+            self.seek(start_ts, SeekMode::Accurate);
+        }
+        if paused != self.is_paused() {
+            if self.pause_flag.0.send(paused).is_err() {
+                warn!("change pause flag err");
+                return;
+            }
+            if paused {
+                self.audio_player.pause();
+            } else {
+                if self.stepped_while_paused_flag {
+                    // the audio queue was left at the position before frame stepping
+                    self.stepped_while_paused_flag = false;
+                    self.frame_step_pending_flag = false;
+                    let timestamp = *self
+                        .async_rt
+                        .block_on(self.main_stream_current_timestamp.read());
+                    let tiny_decoder = self.async_rt.block_on(self.tiny_decoder.read());
+                    self.async_rt.block_on(
+                        tiny_decoder.seek_timestamp_to_decode(timestamp, SeekMode::Accurate),
+                    );
+                    self.audio_player.source_queue_skip_to_end();
+                }
+                self.audio_player.play();
+            }
+        }
+        // a seek ends in the state asked for meanwhile, see `update_state`
+        if self.state != PlayerState::Seeking {
+            self.change_state(if paused {
+                PlayerState::Paused
+            } else {
+                PlayerState::Playing
+            });
+        }
+    }
+    fn seek(&mut self, timestamp: i64, seek_mode: SeekMode) {
+        if !self.state.has_input() || !self.change_state(PlayerState::Seeking) {
+            return;
+        }
+        {
+            let tiny_decoder = self.async_rt.block_on(self.tiny_decoder.read());
+            self.async_rt
+                .block_on(tiny_decoder.seek_timestamp_to_decode(timestamp, seek_mode));
+        }
+        *self
+            .async_rt
+            .block_on(self.main_stream_current_timestamp.write()) = timestamp;
+        self.last_position_ms = None;
+        self.restart_output();
+    }
+    /// the decode thread can't transfer the hardware frames, the decoder is reopened
+    /// in software mode and the playback seeks back to the first failed frame
+    fn fall_back_to_software_decoding(&mut self, timestamp: i64) {
+        let res = {
+            let mut tiny_decoder = self.async_rt.block_on(self.tiny_decoder.write());
+            self.async_rt
+                .block_on(tiny_decoder.fall_back_to_software_decoding())
+        };
+        if let Err(e) = res {
+            warn!("fallback to software decoding error: {}", e);
+            self.send_event(PlayerEvent::Error(e));
+        }
+        self.seek(timestamp, SeekMode::Accurate);
+    }
+    /// the queued audio and the shown frame are of the old position,
+    /// the present task fills them again from the new one
+    fn restart_output(&mut self) {
+        self.audio_player.source_queue_skip_to_end();
+        if !self.is_paused() {
+            self.audio_player.play();
+        }
+        self.video_frame_sender
+            .send_replace(Arc::new(Video::empty()));
+    }
+    fn set_volume(&mut self, volume: f32) {
+        self.audio_player.change_volumn(volume);
+        self.send_event(PlayerEvent::VolumeChanged(volume));
+    }
+    fn set_audio_track(&mut self, stream_index: usize) -> PlayerResult<()> {
+        {
+            let mut tiny_decoder = self.async_rt.block_on(self.tiny_decoder.write());
+            let mut timestamp = self
+                .async_rt
+                .block_on(self.main_stream_current_timestamp.write());
+            *timestamp = self
+                .async_rt
+                .block_on(tiny_decoder.switch_audio_track(stream_index, *timestamp))?;
+        }
+        self.restart_output();
+        self.send_event(PlayerEvent::AudioTrackChanged(stream_index));
+        Ok(())
+    }
+    fn set_subtitle_track(&mut self, stream_index: Option<usize>) -> PlayerResult<()> {
+        {
+            let mut tiny_decoder = self.async_rt.block_on(self.tiny_decoder.write());
+            self.async_rt
+                .block_on(tiny_decoder.switch_subtitle_track(stream_index))?;
+        }
+        self.send_event(PlayerEvent::SubtitleTrackChanged(stream_index));
+        Ok(())
+    }
+    fn set_speed(&mut self, speed: f64) -> PlayerResult<()> {
+        let mut tiny_decoder = self.async_rt.block_on(self.tiny_decoder.write());
+        tiny_decoder.set_playback_speed(speed)
+    }
+    fn set_video_filter_chain(&self, chain: VideoFilterChain) -> PlayerResult<()> {
+        let mut tiny_decoder = self.async_rt.block_on(self.tiny_decoder.write());
+        tiny_decoder.set_video_filter_chain(chain)
+    }
+    fn set_color_override(&self, matrix: ColorMatrixOverride, range: ColorRangeOverride) {
+        let mut tiny_decoder = self.async_rt.block_on(self.tiny_decoder.write());
+        tiny_decoder.set_color_override(matrix, range);
+    }
+    fn set_video_renderer(&self, renderer: VideoRenderer) {
+        let mut tiny_decoder = self.async_rt.block_on(self.tiny_decoder.write());
+        tiny_decoder.set_video_renderer(renderer);
+    }
+    fn set_hardware_policy(&mut self, policy: HardwarePolicy) -> PlayerResult<()> {
+        {
+            let mut tiny_decoder = self.async_rt.block_on(self.tiny_decoder.write());
+            let timestamp = self
+                .async_rt
+                .block_on(self.main_stream_current_timestamp.read());
+            self.async_rt
+                .block_on(tiny_decoder.set_hardware_policy(policy, *timestamp))?;
+        }
+        self.restart_output();
+        Ok(())
+    }
+    fn set_decoder_threading(
+        &mut self,
+        video_threading: DecoderThreading,
+        audio_threading: DecoderThreading,
+    ) -> PlayerResult<()> {
+        {
+            let mut tiny_decoder = self.async_rt.block_on(self.tiny_decoder.write());
+            let timestamp = self
+                .async_rt
+                .block_on(self.main_stream_current_timestamp.read());
+            self.async_rt.block_on(tiny_decoder.set_decoder_threading(
+                video_threading,
+                audio_threading,
+                *timestamp,
+            ))?;
+        }
+        self.restart_output();
+        Ok(())
+    }
+    fn set_buffer_limits(&self, limits: BufferLimits) {
+        let tiny_decoder = self.async_rt.block_on(self.tiny_decoder.read());
+        tiny_decoder.set_buffer_limits(limits);
+    }
+    fn set_clock_source(&self, source: ClockSource) {
+        self.async_rt
+            .block_on(self.playback_clock.write())
+            .set_source(source, Instant::now());
+    }
+
+    fn step_frame_forward(&mut self) {
+        if self.can_step_frame() {
+            self.frame_step_pending_flag = true;
+            self.stepped_while_paused_flag = true;
+        }
+    }
+    fn step_frame_backward(&mut self) {
+        if !self.can_step_frame() {
+            return;
+        }
+        let current_pts = self.video_frame_sender.borrow().pts();
+        if let Some(pts) = current_pts {
+            let tiny_decoder = self.async_rt.block_on(self.tiny_decoder.read());
+            self.async_rt
+                .block_on(tiny_decoder.seek_to_previous_video_frame(pts));
+            self.frame_step_pending_flag = true;
+            self.stepped_while_paused_flag = true;
+        }
+    }
+    fn can_step_frame(&self) -> bool {
+        self.state == PlayerState::Paused
+            && self
+                .async_rt
+                .block_on(self.tiny_decoder.read())
+                .video_frame_rect()[0]
+                != 0
+    }
+    /// drive the playback, called by the engine loop every tick
+    /// the present task checks the clocks and pulls the frames when it is notified
+    fn tick(&mut self) {
+        if !self.state.has_input() {
+            return;
+        }
+        let hardware_fallback_ts = self
+            .async_rt
+            .block_on(self.tiny_decoder.read())
+            .take_hardware_fallback_ts();
+        if let Some(timestamp) = hardware_fallback_ts {
+            self.fall_back_to_software_decoding(timestamp);
+        }
+        if self.is_paused() {
+            self.pull_stepped_frame();
+        } else {
+            self.data_thread_notify.notify_one();
+        }
+        self.update_state();
+        self.publish_position();
+        self.find_cover_pic();
+    }
+    /// follow the decoder to end the seeks, detect the buffering and the end of the playback
+    fn update_state(&mut self) {
+        let paused = self.is_paused();
+        let (main_packets, main_frames, demux_ended, main_ended) = {
+            let tiny_decoder = self.async_rt.block_on(self.tiny_decoder.read());
+            let health = tiny_decoder.buffer_health();
+            let (main_packets, main_frames) = if let MainStream::Audio = tiny_decoder.main_stream()
+            {
+                (health.audio_packets, health.audio_frames)
+            } else {
+                (health.video_packets, health.video_frames)
+            };
+            (
+                main_packets,
+                main_frames,
+                tiny_decoder.is_demux_ended(),
+                tiny_decoder.is_main_stream_ended(),
+            )
+        };
+        let resumed_state = if paused {
+            PlayerState::Paused
+        } else {
+            PlayerState::Playing
+        };
+        match self.state {
+            PlayerState::Seeking => {
+                if main_frames.count > 0 || main_ended {
+                    self.change_state(resumed_state);
+                }
+            }
+            PlayerState::Playing => {
+                // the decoder is waiting for the input, not only slower than the playback
+                if main_frames.count == 0 && main_packets.count == 0 && !demux_ended {
+                    self.change_state(PlayerState::Buffering);
+                }
+            }
+            PlayerState::Buffering => {
+                if main_packets.seconds >= BUFFERING_RESUME_SECONDS || demux_ended {
+                    self.change_state(resumed_state);
+                }
+            }
+            _ => {}
+        }
+        if !paused && Self::check_play_is_at_endtail(&mut self.playback_finished_receiver) {
+            if self.pause_flag.0.send(true).is_err() {
+                warn!("change pause flag err");
+            }
+            self.audio_player.pause();
+            self.change_state(PlayerState::Ended);
+        }
+    }
+    /// send the position when it moved enough since the last one
+    fn publish_position(&mut self) {
+        let position_ms = {
+            let tiny_decoder = self.async_rt.block_on(self.tiny_decoder.read());
+            let timestamp = *self
+                .async_rt
+                .block_on(self.main_stream_current_timestamp.read());
+            tiny_decoder.main_stream_ts_to_ms(timestamp) - tiny_decoder.start_time_ms()
+        };
+        if self
+            .last_position_ms
+            .is_none_or(|last_ms| (position_ms - last_ms).abs() >= POSITION_EVENT_INTERVAL_MS)
+        {
+            self.last_position_ms = Some(position_ms);
+            self.send_event(PlayerEvent::Position(position_ms));
+        }
+    }
+    /// the attached picture is read by the demux task after the input is opened
+    fn find_cover_pic(&mut self) {
+        if self.cover_pic.is_some() {
+            return;
+        }
+        let cover_pic_data = self
+            .async_rt
+            .block_on(self.tiny_decoder.read())
+            .cover_pic_data();
+        if let Some(data) = &*self.async_rt.block_on(cover_pic_data.read()) {
+            self.cover_pic = Some(Arc::new(data.clone()));
+        }
+    }
+    /// the stepped frame is pulled in the following ticks once it is decoded
+    fn pull_stepped_frame(&mut self) {
+        if !self.frame_step_pending_flag {
+            return;
+        }
+        let mut tiny_decoder = self.async_rt.block_on(self.tiny_decoder.write());
+        if let Some(play_frame) = self
+            .async_rt
+            .block_on(tiny_decoder.pull_one_video_play_frame())
+        {
+            // the present task may take the decoder while the frame is converted
+            drop(tiny_decoder);
+            self.frame_step_pending_flag = false;
+            if let Some(frame) = self.async_rt.block_on(play_frame.wait()) {
+                if let Some(pts) = frame.pts() {
+                    let main_ts = self
+                        .async_rt
+                        .block_on(self.tiny_decoder.read())
+                        .video_pts_to_main_ts(pts);
+                    *self
+                        .async_rt
+                        .block_on(self.main_stream_current_timestamp.write()) = main_ts;
+                }
+                self.video_frame_sender.send_replace(Arc::new(frame));
+            }
+        } else if tiny_decoder.is_video_ended() {
+            // stepped past the last frame, playing again starts from the beginning
+            drop(tiny_decoder);
+            self.frame_step_pending_flag = false;
+            self.stepped_while_paused_flag = false;
+            self.change_state(PlayerState::Ended);
+        }
+    }
+    /// true once each time the present task reports the main stream is played to the end
+    fn check_play_is_at_endtail(receiver: &mut Receiver<bool>) -> bool {
+        if let Ok(true) = receiver.has_changed() {
+            if *receiver.borrow_and_update() {
+                return true;
+            }
+        }
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::process::Command;
+
+    use tokio::sync::broadcast::error::TryRecvError;
+
+    use super::*;
+
+    /// how long the engine may take to reach a state
+    const WAIT_TIMEOUT: Duration = Duration::from_secs(10);
+
+    /// a two seconds clip with a video and an audio stream, made by the ffmpeg cli
+    fn generate_clip() -> PlayerResult<PathBuf> {
+        let path = std::env::temp_dir().join(format!(
+            "tiny_player_engine_test_{}.mkv",
+            std::process::id()
+        ));
+        let status = Command::new("ffmpeg")
+            .args([
+                "-y",
+                "-loglevel",
+                "error",
+                "-f",
+                "lavfi",
+                "-i",
+                "testsrc=size=160x120:rate=25:duration=2",
+                "-f",
+                "lavfi",
+                "-i",
+                "sine=frequency=440:sample_rate=48000:duration=2",
+                "-c:v",
+                "mpeg4",
+                "-g",
+                "10",
+                "-c:a",
+                "mp2",
+                "-shortest",
+            ])
+            .arg(&path)
+            .status()
+            .map_err(|e| PlayerError::Internal(format!("run the ffmpeg cli err: {}", e)))?;
+        if !status.success() {
+            return Err(PlayerError::Internal(format!(
+                "the ffmpeg cli can't generate the test clip: {}",
+                status
+            )));
+        }
+        Ok(path)
+    }
+
+    /// read the events until one is accepted, the skipped ones are kept in `seen`
+    fn wait_for_event(
+        events: &mut broadcast::Receiver<PlayerEvent>,
+        seen: &mut Vec<PlayerEvent>,
+        mut accept: impl FnMut(&PlayerEvent) -> bool,
+    ) -> bool {
+        let deadline = Instant::now() + WAIT_TIMEOUT;
+        while Instant::now() < deadline {
+            match events.try_recv() {
+                Ok(event) => {
+                    let accepted = accept(&event);
+                    seen.push(event);
+                    if accepted {
+                        return true;
+                    }
+                }
+                Err(TryRecvError::Empty) => std::thread::sleep(Duration::from_millis(5)),
+                Err(TryRecvError::Lagged(_)) => {}
+                Err(TryRecvError::Closed) => return false,
+            }
+        }
+        false
+    }
+
+    fn wait_for_state(
+        events: &mut broadcast::Receiver<PlayerEvent>,
+        seen: &mut Vec<PlayerEvent>,
+        state: PlayerState,
+    ) -> bool {
+        wait_for_event(
+            events,
+            seen,
+            |event| matches!(event, PlayerEvent::StateChanged(changed) if *changed == state),
+        )
+    }
+
+    #[test]
+    #[ignore = "needs the ffmpeg cli and an audio output device"]
+    fn open_play_seek_pause_and_end() -> PlayerResult<()> {
+        let clip = generate_clip()?;
+        let player = Player::new()?;
+        let mut events = player.subscribe();
+        let mut seen = vec![];
+
+        // open: paused at the start with the tracks of the clip
+        player.open(&clip);
+        assert!(wait_for_event(&mut events, &mut seen, |event| {
+            matches!(event, PlayerEvent::Opened(path) if *path == clip)
+        }));
+        assert!(matches!(
+            seen.as_slice(),
+            [
+                ..,
+                PlayerEvent::StateChanged(PlayerState::Opening),
+                PlayerEvent::StateChanged(PlayerState::Paused),
+                PlayerEvent::Opened(_)
+            ]
+        ));
+        assert!(wait_for_event(&mut events, &mut seen, |event| {
+            matches!(event, PlayerEvent::TracksChanged { audio_tracks, .. } if audio_tracks.len() == 1)
+        }));
+        let opened = player.snapshot();
+        assert_eq!(opened.state, PlayerState::Paused);
+        assert!(opened.paused);
+        assert!(opened.end_timestamp > 0);
+        assert_eq!(opened.video_frame_rect, [160, 120]);
+
+        // play: the position moves on
+        player.toggle_pause();
+        assert!(wait_for_state(&mut events, &mut seen, PlayerState::Playing));
+        assert!(!player.snapshot().paused);
+        let mut played_ms = 0;
+        assert!(wait_for_event(&mut events, &mut seen, |event| {
+            if let PlayerEvent::Position(position_ms) = event {
+                played_ms = *position_ms;
+                return *position_ms > opened.position_ms;
+            }
+            false
+        }));
+
+        // seek: the playback goes on from the target
+        let before_seek = player.snapshot();
+        let seek_ts = before_seek.end_timestamp / 2;
+        player.seek(seek_ts, SeekMode::Accurate);
+        assert!(wait_for_state(&mut events, &mut seen, PlayerState::Seeking));
+        assert!(wait_for_state(&mut events, &mut seen, PlayerState::Playing));
+        let after_seek = player.snapshot();
+        assert!(after_seek.timestamp > before_seek.timestamp);
+        assert!(after_seek.timestamp <= after_seek.end_timestamp);
+        assert!(after_seek.position_ms > played_ms);
+
+        // pause: the position stays
+        player.toggle_pause();
+        assert!(wait_for_state(&mut events, &mut seen, PlayerState::Paused));
+        let paused = player.snapshot();
+        assert!(paused.paused);
+        std::thread::sleep(Duration::from_millis(200));
+        assert_eq!(player.snapshot().timestamp, paused.timestamp);
+
+        // end: playing the rest stops at the end
+        player.toggle_pause();
+        assert!(wait_for_state(&mut events, &mut seen, PlayerState::Playing));
+        assert!(wait_for_state(&mut events, &mut seen, PlayerState::Ended));
+        let ended = player.snapshot();
+        assert_eq!(ended.state, PlayerState::Ended);
+        assert!(ended.paused);
+        assert!(
+            !seen
+                .iter()
+                .any(|event| matches!(event, PlayerEvent::Error(_)))
+        );
+
+        drop(player);
+        let _ = std::fs::remove_file(&clip);
+        Ok(())
+    }
+}
@@ -0,0 +1,95 @@
+/// the state of the playback, changed only by the `Player`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PlayerState {
+    /// no input opened yet
+    Idle,
+    /// the input is being opened and probed
+    Opening,
+    Playing,
+    Paused,
+    /// waiting for the first frames after a seek
+    Seeking,
+    /// the main stream frames ran out while playing, waiting for the decoder
+    Buffering,
+    /// the main stream is played to the end
+    Ended,
+    /// the input could not be opened, a new one has to be chosen
+    Error,
+}
+impl PlayerState {
+    /// the transitions the player is allowed to make
+    pub fn can_change_to(self, next: PlayerState) -> bool {
+        use PlayerState::*;
+        match (self, next) {
+            // a second input can't be opened while the first one is probed
+            (Opening, Opening) => false,
+            // any other input may be replaced, and opening may fail whatever the state was
+            (_, Opening) => true,
+            (Opening, Paused | Error) => true,
+            (Opening, _) => false,
+            (Idle | Error, _) => false,
+            (Playing, Paused | Seeking | Buffering | Ended) => true,
+            // stepping the frames while paused may reach the end
+            (Paused, Playing | Seeking | Ended) => true,
+            (Seeking, Playing | Paused | Seeking | Ended) => true,
+            (Buffering, Playing | Paused | Seeking | Ended) => true,
+            (Ended, Playing | Paused | Seeking) => true,
+            _ => false,
+        }
+    }
+    /// an input is opened and can be played
+    pub fn has_input(self) -> bool {
+        !matches!(
+            self,
+            PlayerState::Idle | PlayerState::Opening | PlayerState::Error
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::PlayerState::{self, *};
+
+    const ALL: [PlayerState; 8] = [
+        Idle, Opening, Playing, Paused, Seeking, Buffering, Ended, Error,
+    ];
+
+    /// the states each state may change to, the others are refused
+    const ALLOWED: [(PlayerState, &[PlayerState]); 8] = [
+        (Idle, &[Opening]),
+        (Opening, &[Paused, Error]),
+        (Playing, &[Opening, Paused, Seeking, Buffering, Ended]),
+        (Paused, &[Opening, Playing, Seeking, Ended]),
+        (Seeking, &[Opening, Playing, Paused, Seeking, Ended]),
+        (Buffering, &[Opening, Playing, Paused, Seeking, Ended]),
+        (Ended, &[Opening, Playing, Paused, Seeking]),
+        (Error, &[Opening]),
+    ];
+
+    #[test]
+    fn transitions() {
+        for (from, allowed) in ALLOWED {
+            for to in ALL {
+                assert_eq!(
+                    from.can_change_to(to),
+                    allowed.contains(&to),
+                    "{:?} -> {:?}",
+                    from,
+                    to
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn has_input() {
+        for state in ALL {
+            assert_eq!(
+                state.has_input(),
+                !matches!(state, Idle | Opening | Error),
+                "{:?}",
+                state
+            );
+        }
+    }
+}
@@ -1,23 +1,27 @@
-use std::{
-    sync::Arc,
-    time::{Duration, Instant},
-};
+use std::{sync::Arc, time::Instant};
 
 use derive_builder::Builder;
-use ffmpeg_the_third::frame::Video;
+use ffmpeg_the_third::frame::{Audio, Video};
 use rodio::Sink;
 use tokio::{
     runtime::Handle,
-    sync::{Notify, RwLock},
+    sync::{Notify, RwLock, watch},
     task::JoinHandle,
 };
+use tracing::info;
 
 use crate::{
-    ai_sub_title::{AISubTitle, UsedModel},
+    ai_sub_title::{SubtitleWorker, UsedModel},
     audio_play::AudioPlayer,
-    decode::{MainStream, TinyDecoder},
+    clock::{AudioSync, PlaybackClock, VideoSync},
+    decode::{MainStream, TinyDecoder, VideoPlayFrame},
 };
 
+/// the sink is filled up to this many frames, more only adds latency
+const AUDIO_SINK_QUEUE_LEN: usize = 10;
+/// the video decoder skips the non-reference frames when the video is this late
+const HEAVY_LAG_MS: f64 = 500.0;
+
 pub struct PresentDataManager {
     _data_thread_handle: JoinHandle<()>,
 }
@@ -30,157 +34,247 @@ impl PresentDataManager {
                 .spawn(PresentDataManager::play_task(data_manage_context)),
         }
     }
-    async fn play_task(data_manage_context: DataManageContext) {
-        let mut change_instant = Instant::now();
+    async fn play_task(mut data_manage_context: DataManageContext) {
+        let mut seek_serial = 0;
+        let mut pending_audio_frame = None;
+        let mut shown_frame = None;
         loop {
-            /*
-            add audio frame data to the audio player
-             */
+            let mut pulled_frame = None;
+            let mut played_audio_frames = vec![];
             {
-                let mut audio_cur_ts = None;
+                let now = Instant::now();
                 let mut tiny_decoder = data_manage_context.tiny_decoder.write().await;
-                if let MainStream::Audio = tiny_decoder.main_stream() {
-                    if let Some(audio_frame) = tiny_decoder.pull_one_audio_play_frame().await {
-                        if let Some(pts) = audio_frame.pts() {
-                            audio_cur_ts = Some(pts);
-                            AudioPlayer::play_raw_data_from_audio_frame(
-                                &data_manage_context.audio_sink,
-                                audio_frame.clone(),
-                            )
+                let mut clock = data_manage_context.playback_clock.write().await;
+                let paused = *data_manage_context.pause_flag_receiver.borrow_and_update();
+                clock.set_paused(paused, now);
+                clock.set_speed(tiny_decoder.playback_speed(), now);
+                if tiny_decoder.seek_serial() != seek_serial {
+                    // the frames pulled from now on start a new timeline
+                    seek_serial = tiny_decoder.seek_serial();
+                    clock.reset();
+                    pending_audio_frame = None;
+                    shown_frame = None;
+                }
+                clock.update_audio_output(
+                    data_manage_context.audio_sink.len(),
+                    data_manage_context.audio_sink.get_pos(),
+                    now,
+                );
+                let has_audio = matches!(tiny_decoder.main_stream(), MainStream::Audio);
+                clock.set_has_audio(has_audio);
+                if !paused {
+                    if has_audio {
+                        PresentDataManager::present_audio(
+                            &data_manage_context,
+                            &mut tiny_decoder,
+                            &mut clock,
+                            &mut pending_audio_frame,
+                            &mut played_audio_frames,
+                            now,
+                        )
+                        .await;
+                    }
+                    pulled_frame = PresentDataManager::present_video(
+                        &mut tiny_decoder,
+                        &mut clock,
+                        &mut shown_frame,
+                        now,
+                    )
+                    .await;
+                    if let Some(master_ms) = clock.master_ms(now) {
+                        let mut main_ts = data_manage_context
+                            .main_stream_current_timestamp
+                            .write()
                             .await;
-                            let used_model = data_manage_context.used_model.read().await;
-                            let used_model_ref = &*used_model;
-                            if UsedModel::Empty != *used_model_ref {
-                                let mut ai_subtitle = data_manage_context.ai_subtitle.write().await;
-                                let used_model = used_model_ref.clone();
-                                ai_subtitle.push_frame_data(audio_frame, used_model).await;
-                            }
-                        }
+                        *main_ts = tiny_decoder.ms_to_main_stream_ts(master_ms as i64);
                     }
                 }
-                if PresentDataManager::should_video_catch_audio(
-                    &tiny_decoder,
-                    data_manage_context.main_stream_current_timestamp.clone(),
-                    data_manage_context.current_video_frame.clone(),
-                )
-                .await
-                {
-                    let ins_now = Instant::now();
-                    if let Some(frame) = tiny_decoder.pull_one_video_play_frame().await {
-                        let mut cur_frame = data_manage_context.current_video_frame.write().await;
-                        let main_stream = tiny_decoder.main_stream();
-                        if let MainStream::Video = main_stream {
-                            if ins_now - change_instant > Duration::from_millis(0) {
-                                if let Some(f_pts) = frame.pts() {
-                                    if let Some(cur_pts) = cur_frame.pts() {
-                                        let time_base = tiny_decoder.video_time_base();
-                                        if f_pts > 0
-                                            && cur_pts > 0
-                                            && ((f_pts - cur_pts)
-                                                * 1000
-                                                * (*time_base).numerator() as i64
-                                                / (*time_base).denominator() as i64)
-                                                < 1000
-                                        {
-                                            if let Some(ins) =
-                                                change_instant.checked_add(Duration::from_millis(
-                                                    ((f_pts - cur_pts)
-                                                        * 1000
-                                                        * (*time_base).numerator() as i64
-                                                        / (*time_base).denominator() as i64)
-                                                        as u64,
-                                                ))
-                                            {
-                                                change_instant = ins;
-                                            }
-                                        } else {
-                                            change_instant = ins_now;
-                                        }
-                                    }
-                                }
+                // the last audio is played out of the sink,
+                // the last frame is shown for its duration
+                let finished = tiny_decoder.is_main_stream_ended()
+                    && pulled_frame.is_none()
+                    && if has_audio {
+                        data_manage_context.audio_sink.empty()
+                    } else {
+                        clock.master_ms(now).is_none_or(|master_ms| {
+                            shown_frame.is_none_or(|shown| master_ms >= shown.end_ms)
+                        })
+                    };
+                data_manage_context
+                    .playback_finished_sender
+                    .send_if_modified(|playback_finished| {
+                        if *playback_finished != finished {
+                            if finished {
+                                info!("playback finished");
                             }
-                        } else if let MainStream::Audio = main_stream {
-                            change_instant = ins_now;
+                            *playback_finished = finished;
+                            true
+                        } else {
+                            false
                         }
-                        *cur_frame = frame;
+                    });
+            }
+            // the subtitle thread gets the played audio after the locks are released
+            if !played_audio_frames.is_empty() {
+                let used_model = data_manage_context.used_model.read().await.clone();
+                if UsedModel::Empty != used_model {
+                    for audio_frame in played_audio_frames {
+                        data_manage_context
+                            .subtitle_worker
+                            .push_frame(audio_frame, used_model.clone());
                     }
                 }
-                PresentDataManager::update_current_timestamp(
-                    data_manage_context.main_stream_current_timestamp.clone(),
-                    audio_cur_ts,
-                    &tiny_decoder,
-                    data_manage_context.current_video_frame.clone(),
-                )
-                .await;
             }
-            data_manage_context.data_thread_notify.notified().await;
+            if let Some((pts_ms, frame_duration_ms, play_frame)) = pulled_frame {
+                // the conversion is waited for without the decoder lock,
+                // the engine and the ui are not blocked by it
+                if let Some(frame) = play_frame.wait().await {
+                    // a seek meanwhile makes the frame one of the old position
+                    if data_manage_context.tiny_decoder.read().await.seek_serial() == seek_serial {
+                        data_manage_context
+                            .playback_clock
+                            .write()
+                            .await
+                            .video_frame_shown(pts_ms, frame_duration_ms, Instant::now());
+                        data_manage_context
+                            .video_frame_sender
+                            .send_replace(Arc::new(frame));
+                        shown_frame = Some(ShownFrame {
+                            end_ms: pts_ms + frame_duration_ms,
+                            repeated_until_ms: pts_ms + frame_duration_ms,
+                        });
+                    }
+                }
+            }
+            // a pause change is taken at once so the clocks stop with the sink
+            tokio::select! {
+                _ = data_manage_context.data_thread_notify.notified() => {}
+                Ok(()) = data_manage_context.pause_flag_receiver.changed() => {}
+            }
         }
     }
-    async fn update_current_timestamp(
-        main_stream_current_timestamp: Arc<RwLock<i64>>,
-        audio_pts: Option<i64>,
-        tiny_decoder: &TinyDecoder,
-        current_video_frame: Arc<RwLock<Video>>,
+    /// append audio frames to the sink until it holds enough
+    /// when audio is not the master clock, the frames out of the sync window
+    /// are dropped or kept for a later run, the played ones are kept for the subtitle
+    async fn present_audio(
+        data_manage_context: &DataManageContext,
+        tiny_decoder: &mut TinyDecoder,
+        clock: &mut PlaybackClock,
+        pending_audio_frame: &mut Option<Audio>,
+        played_audio_frames: &mut Vec<Audio>,
+        now: Instant,
     ) {
-        /*
-        add audio frame data to the audio player
-         */
-        let main_stream = tiny_decoder.main_stream();
-        let mut main_ts = main_stream_current_timestamp.write().await;
-        if let MainStream::Audio = main_stream {
-            if let Some(pts) = audio_pts {
-                *main_ts = pts;
-            }
-        } else if let MainStream::Video = main_stream {
-            let cur_video_frame = current_video_frame.read().await;
-
-            if let Some(pts) = cur_video_frame.pts() {
-                if pts > 0 {
-                    *main_ts = pts;
+        while data_manage_context.audio_sink.len() < AUDIO_SINK_QUEUE_LEN {
+            let audio_frame = if let Some(audio_frame) = pending_audio_frame.take() {
+                audio_frame
+            } else if let Some(audio_frame) = tiny_decoder.pull_one_audio_play_frame() {
+                audio_frame
+            } else {
+                break;
+            };
+            if let Some(pts) = audio_frame.pts() {
+                let pts_ms = tiny_decoder.audio_ts_to_ms(pts);
+                let duration_ms =
+                    audio_frame.samples() as f64 * 1000.0 / audio_frame.rate().max(1) as f64;
+                match clock.sync_audio(pts_ms, duration_ms, now) {
+                    AudioSync::Wait => {
+                        *pending_audio_frame = Some(audio_frame);
+                        break;
+                    }
+                    AudioSync::Drop => continue,
+                    AudioSync::Play => {}
                 }
+                AudioPlayer::play_raw_data_from_audio_frame(
+                    &data_manage_context.audio_sink,
+                    audio_frame.clone(),
+                )
+                .await;
+                clock.audio_frame_queued(pts_ms, duration_ms);
+                played_audio_frames.push(audio_frame);
             }
         }
     }
-    /// if video time-audio time is too high(more than 1 second),default return true
-    async fn should_video_catch_audio(
-        tiny_decoder: &TinyDecoder,
-        main_stream_current_timestamp: Arc<RwLock<i64>>,
-        current_video_frame: Arc<RwLock<Video>>,
-    ) -> bool {
-        if let MainStream::Video = tiny_decoder.main_stream() {
-            return true;
+    /// pull the video frame whose time is reached by the master clock, with its pts
+    /// and duration in ms, it is shown by the caller once converted
+    /// the current frame is repeated while the next one is early, late frames are
+    /// dropped without converting them while the one after them is due too
+    async fn present_video(
+        tiny_decoder: &mut TinyDecoder,
+        clock: &mut PlaybackClock,
+        shown_frame: &mut Option<ShownFrame>,
+        now: Instant,
+    ) -> Option<(f64, f64, VideoPlayFrame)> {
+        let frame_duration_ms = tiny_decoder.video_frame_duration_ms();
+        let mut pulled_frame = None;
+        while let Some(pts) = tiny_decoder.queued_video_frame_pts(0) {
+            let pts_ms = tiny_decoder.video_ts_to_ms(pts);
+            match clock.sync_video(pts_ms, frame_duration_ms, now) {
+                VideoSync::Wait => break,
+                VideoSync::Show => {}
+                VideoSync::Late => {
+                    if let Some(following_pts) = tiny_decoder.queued_video_frame_pts(1) {
+                        let following_ms = tiny_decoder.video_ts_to_ms(following_pts);
+                        if clock
+                            .master_ms(now)
+                            .is_some_and(|master_ms| following_ms <= master_ms)
+                        {
+                            tiny_decoder.drop_one_video_frame();
+                            clock.record_dropped_frame();
+                            continue;
+                        }
+                    }
+                }
+            }
+            if let Some(play_frame) = tiny_decoder.pull_one_video_play_frame().await {
+                pulled_frame = Some((pts_ms, frame_duration_ms, play_frame));
+            }
+            break;
         }
-        let current_video_frame = current_video_frame.read().await;
-
-        let timestamp = main_stream_current_timestamp.read().await;
-        {
-            let video_time_base = tiny_decoder.video_time_base();
-            let audio_time_base = tiny_decoder.audio_time_base();
-            if let Some(f_ts) = current_video_frame.pts() {
-                let v_time = f_ts * 1000 * video_time_base.numerator() as i64
-                    / video_time_base.denominator() as i64;
-                let a_time = *timestamp * 1000 * audio_time_base.numerator() as i64
-                    / audio_time_base.denominator() as i64;
-                let time_dur = a_time - v_time;
-                if time_dur > 100 {
-                    return true;
+        if let (None, Some(shown)) = (&pulled_frame, shown_frame.as_mut()) {
+            // the next frame is not ready when the shown one ends
+            if let Some(master_ms) = clock.master_ms(now) {
+                if master_ms >= shown.repeated_until_ms && !tiny_decoder.is_video_ended() {
+                    clock.record_duplicated_frame();
+                    shown.repeated_until_ms += frame_duration_ms.max(1.0);
                 }
-            } else {
-                return true;
             }
         }
-
-        false
+        if let (Some(shown), Some(master_ms)) = (*shown_frame, clock.master_ms(now)) {
+            // dropping the converted frames is not enough when the decoder is too slow
+            let lag_ms = master_ms - shown.end_ms;
+            let skip_non_ref = if lag_ms > HEAVY_LAG_MS {
+                true
+            } else if lag_ms < 0.0 {
+                false
+            } else {
+                clock.frame_stats().skipping_non_ref
+            };
+            tiny_decoder.set_video_skip_non_ref(skip_non_ref);
+            clock.set_skipping_non_ref(skip_non_ref);
+        }
+        pulled_frame
     }
 }
+/// the end of the video frame being shown
+#[derive(Debug, Clone, Copy)]
+struct ShownFrame {
+    end_ms: f64,
+    /// the frame counted as duplicated until this time
+    repeated_until_ms: f64,
+}
 #[derive(Builder)]
 pub struct DataManageContext {
     data_thread_notify: Arc<Notify>,
     tiny_decoder: Arc<RwLock<TinyDecoder>>,
     used_model: Arc<RwLock<UsedModel>>,
-    ai_subtitle: Arc<RwLock<AISubTitle>>,
-    current_video_frame: Arc<RwLock<Video>>,
+    subtitle_worker: SubtitleWorker,
+    /// the frame being shown, read by the front-ends
+    video_frame_sender: Arc<watch::Sender<Arc<Video>>>,
     audio_sink: Arc<Sink>,
     main_stream_current_timestamp: Arc<RwLock<i64>>,
+    playback_clock: Arc<RwLock<PlaybackClock>>,
+    pause_flag_receiver: watch::Receiver<bool>,
+    /// set to true when the main stream is played to the end
+    playback_finished_sender: Arc<watch::Sender<bool>>,
     runtime_handle: Handle,
 }
@@ -0,0 +1,519 @@
+use std::path::Path;
+
+use ffmpeg_the_third::{codec::subtitle::Rect, ffi::avsubtitle_free};
+
+use crate::{PlayerError, PlayerResult};
+
+/// one piece of subtitle, shown while the main stream clock
+/// is between `start_ms` and `end_ms`
+#[derive(Debug, Clone)]
+pub struct SubtitleCue {
+    pub start_ms: i64,
+    pub end_ms: i64,
+    pub content: SubtitleContent,
+}
+/// text subtitle (srt, ass...) or picture subtitle (pgs, dvd...)
+#[derive(Debug, Clone, PartialEq)]
+pub enum SubtitleContent {
+    Text(String),
+    Bitmap(SubtitleBitmap),
+}
+/// rgba picture of a bitmap subtitle, `x` `y` are relative to `canvas`
+/// which normally is the video frame size
+#[derive(Debug, Clone, PartialEq)]
+pub struct SubtitleBitmap {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+    pub canvas: [u32; 2],
+    pub rgba: Vec<u8>,
+}
+
+/// the demux runs a little ahead of the play, the cues ended this long
+/// before will not be shown again
+const KEPT_ENDED_CUE_MS: i64 = 60_000;
+/// the timed cues of the selected subtitle track, ordered by start time
+#[derive(Debug, Default)]
+pub struct SubtitleCues {
+    cues: Vec<SubtitleCue>,
+}
+impl SubtitleCues {
+    /// insert a cue in start time order
+    /// a cue without end time is closed by the next cue
+    /// the cues decoded again after a seek inside the kept ones are skipped,
+    /// a cue is the same if its times and its content with the position match
+    pub fn push(&mut self, cue: SubtitleCue) {
+        let idx = self.cues.partition_point(|c| c.start_ms <= cue.start_ms);
+        if self.cues[..idx]
+            .iter()
+            .rev()
+            .take_while(|c| c.start_ms == cue.start_ms)
+            .any(|c| {
+                // a cue without end time decoded again is the one its next cue closed
+                c.content == cue.content && (c.end_ms == cue.end_ms || cue.end_ms == i64::MAX)
+            })
+        {
+            return;
+        }
+        if idx > 0 {
+            if let Some(previous) = self.cues.get_mut(idx - 1) {
+                if previous.end_ms == i64::MAX && previous.start_ms < cue.start_ms {
+                    previous.end_ms = cue.start_ms;
+                }
+            }
+        }
+        self.cues.insert(idx, cue);
+    }
+    /// close the cue without end time, used by picture subtitles which
+    /// send an empty subtitle to clear the screen
+    pub fn close_open_cues(&mut self, end_ms: i64) {
+        for cue in &mut self.cues {
+            if cue.end_ms == i64::MAX && cue.start_ms <= end_ms {
+                cue.end_ms = end_ms;
+            }
+        }
+    }
+    /// cues that should be shown at `play_ms`
+    pub fn active_cues(&self, play_ms: i64) -> impl Iterator<Item = &SubtitleCue> {
+        let end = self.cues.partition_point(|c| c.start_ms <= play_ms);
+        self.cues[..end].iter().filter(move |c| c.end_ms > play_ms)
+    }
+    /// drop the cues that ended long before `play_ms` to bound the memory
+    pub fn remove_ended_before(&mut self, play_ms: i64) {
+        self.cues
+            .retain(|c| c.end_ms >= play_ms.saturating_sub(KEPT_ENDED_CUE_MS));
+    }
+    /// true if `play_ms` is between the start of the first cue and the end of the last one
+    pub fn spans(&self, play_ms: i64) -> bool {
+        self.cues.first().is_some_and(|c| c.start_ms <= play_ms)
+            && self.cues.iter().any(|c| c.end_ms >= play_ms)
+    }
+    pub fn clear(&mut self) {
+        self.cues.clear();
+    }
+}
+
+/// convert one decoded subtitle to cues and free it
+/// `fallback_pts_ms` is used when the decoder doesn't give a pts
+pub fn cues_from_decoded_subtitle(
+    subtitle: &mut ffmpeg_the_third::codec::subtitle::Subtitle,
+    fallback_pts_ms: i64,
+    canvas: [u32; 2],
+) -> Vec<SubtitleCue> {
+    // subtitle pts unit is AV_TIME_BASE(us), display times are ms relative to the pts
+    let pts_ms = subtitle
+        .pts()
+        .map(|pts| pts / 1000)
+        .unwrap_or(fallback_pts_ms);
+    let start_ms = pts_ms + subtitle.start() as i64;
+    let end_ms = if subtitle.end() == u32::MAX || subtitle.end() <= subtitle.start() {
+        i64::MAX
+    } else {
+        pts_ms + subtitle.end() as i64
+    };
+    let mut cues = vec![];
+    for rect in subtitle.rects() {
+        let content = match rect {
+            Rect::Text(text) => Some(SubtitleContent::Text(text.get().trim().to_string())),
+            Rect::Ass(ass) => Some(SubtitleContent::Text(ass_dialogue_text(ass.get()))),
+            Rect::Bitmap(bitmap) => unsafe {
+                let rect = bitmap.as_ptr();
+                let width = (*rect).w.max(0) as usize;
+                let height = (*rect).h.max(0) as usize;
+                let data = (*rect).data[0];
+                let palette = (*rect).data[1] as *const u32;
+                if data.is_null() || palette.is_null() {
+                    None
+                } else {
+                    let linesize = (*rect).linesize[0] as usize;
+                    let mut rgba = Vec::with_capacity(width * height * 4);
+                    for y in 0..height {
+                        for x in 0..width {
+                            let color_idx = *data.add(y * linesize + x) as usize;
+                            // palette entry is 0xAARRGGBB
+                            let argb = *palette.add(color_idx);
+                            rgba.push((argb >> 16) as u8);
+                            rgba.push((argb >> 8) as u8);
+                            rgba.push(argb as u8);
+                            rgba.push((argb >> 24) as u8);
+                        }
+                    }
+                    Some(SubtitleContent::Bitmap(SubtitleBitmap {
+                        x: (*rect).x.max(0) as u32,
+                        y: (*rect).y.max(0) as u32,
+                        width: width as u32,
+                        height: height as u32,
+                        canvas,
+                        rgba,
+                    }))
+                }
+            },
+            Rect::None(_) => None,
+        };
+        if let Some(content) = content {
+            cues.push(SubtitleCue {
+                start_ms,
+                end_ms,
+                content,
+            });
+        }
+    }
+    unsafe {
+        avsubtitle_free(subtitle.as_mut_ptr());
+    }
+    cues
+}
+
+/// the ass event given by ffmpeg is
+/// `ReadOrder,Layer,Style,Name,MarginL,MarginR,MarginV,Effect,Text`
+/// keep only the text part and clean the tags
+fn ass_dialogue_text(event: &str) -> String {
+    let text = event.splitn(9, ',').nth(8).unwrap_or(event);
+    strip_ass_tags(text)
+}
+
+/// remove the `{...}` override tags and convert the ass line breaks
+fn strip_ass_tags(text: &str) -> String {
+    let mut res = String::with_capacity(text.len());
+    let mut in_tag = false;
+    for c in text.chars() {
+        match c {
+            '{' => in_tag = true,
+            '}' if in_tag => in_tag = false,
+            _ if !in_tag => res.push(c),
+            _ => {}
+        }
+    }
+    res.replace("\\N", "\n")
+        .replace("\\n", "\n")
+        .replace("\\h", " ")
+        .trim()
+        .to_string()
+}
+
+/// read a subtitle file beside the video, the format is chosen by the extension
+/// the cue times are relative to the start of the media
+pub fn load_subtitle_file(path: &Path) -> PlayerResult<SubtitleCues> {
+    let data = std::fs::read(path).map_err(|e| PlayerError::io(path, e))?;
+    let content = String::from_utf8_lossy(&data);
+    let content = content.trim_start_matches('\u{feff}').replace("\r\n", "\n");
+    let extension = path
+        .extension()
+        .and_then(|e| e.to_str())
+        .map(|e| e.to_lowercase())
+        .unwrap_or_default();
+    let cues = match extension.as_str() {
+        "srt" | "vtt" => parse_srt_or_vtt(&content),
+        "ass" | "ssa" => parse_ass(&content),
+        _ => {
+            return Err(PlayerError::SubtitleFile {
+                path: path.to_path_buf(),
+                reason: "only srt, vtt and ass files are supported".to_string(),
+            });
+        }
+    };
+    if cues.is_empty() {
+        return Err(PlayerError::SubtitleFile {
+            path: path.to_path_buf(),
+            reason: "no subtitle cue found".to_string(),
+        });
+    }
+    let mut subtitle_cues = SubtitleCues::default();
+    for cue in cues {
+        subtitle_cues.push(cue);
+    }
+    Ok(subtitle_cues)
+}
+/// true if the path looks like a subtitle file we can load
+pub fn is_subtitle_file(path: &Path) -> bool {
+    if let Some(extension) = path.extension().and_then(|e| e.to_str()) {
+        let extension = extension.to_lowercase();
+        return extension == "srt"
+            || extension == "vtt"
+            || extension == "ass"
+            || extension == "ssa";
+    }
+    false
+}
+
+/// srt and webvtt share the same block layout:
+/// an optional id line, a `start --> end` line, then the text lines
+/// the blocks are separated by blank lines
+fn parse_srt_or_vtt(content: &str) -> Vec<SubtitleCue> {
+    let mut cues = vec![];
+    let mut block = vec![];
+    // `lines` also takes away the '\r' of the crlf files
+    for line in content.lines().chain(std::iter::once("")) {
+        if line.trim().is_empty() {
+            if let Some(cue) = parse_srt_or_vtt_block(&block) {
+                cues.push(cue);
+            }
+            block.clear();
+        } else {
+            block.push(line);
+        }
+    }
+    cues
+}
+/// header, NOTE, STYLE and REGION blocks have no timing line and give no cue
+fn parse_srt_or_vtt_block(lines: &[&str]) -> Option<SubtitleCue> {
+    let timing_index = lines.iter().position(|line| line.contains("-->"))?;
+    let mut parts = lines[timing_index].split("-->");
+    let start_ms = parts.next().and_then(|s| parse_timestamp_ms(s.trim()))?;
+    // webvtt may put cue settings after the end time
+    let end_ms = parts
+        .next()
+        .and_then(|s| s.split_whitespace().next())
+        .and_then(parse_timestamp_ms)?;
+    let text = strip_html_tags(&lines[timing_index + 1..].join("\n"));
+    if text.is_empty() {
+        return None;
+    }
+    Some(SubtitleCue {
+        start_ms,
+        end_ms,
+        content: SubtitleContent::Text(text),
+    })
+}
+/// read the `Dialogue:` lines of the `[Events]` section,
+/// the field order is given by the `Format:` line
+fn parse_ass(content: &str) -> Vec<SubtitleCue> {
+    let mut cues = vec![];
+    let mut in_events = false;
+    let mut format: Vec<String> = vec![];
+    for line in content.lines() {
+        let line = line.trim();
+        if line.starts_with('[') {
+            in_events = line.eq_ignore_ascii_case("[events]");
+            continue;
+        }
+        if !in_events {
+            continue;
+        }
+        if let Some(format_line) = line.strip_prefix("Format:") {
+            format = format_line
+                .split(',')
+                .map(|f| f.trim().to_lowercase())
+                .collect();
+        } else if let Some(dialogue) = line.strip_prefix("Dialogue:") {
+            if format.is_empty() {
+                continue;
+            }
+            // the text is the last field and may contain commas
+            let fields: Vec<&str> = dialogue.splitn(format.len(), ',').collect();
+            let field = |name: &str| {
+                format
+                    .iter()
+                    .position(|f| f == name)
+                    .and_then(|i| fields.get(i))
+                    .map(|f| f.trim())
+            };
+            let start = field("start").and_then(parse_timestamp_ms);
+            let end = field("end").and_then(parse_timestamp_ms);
+            if let (Some(start_ms), Some(end_ms), Some(text)) = (start, end, field("text")) {
+                let text = strip_ass_tags(text);
+                if !text.is_empty() {
+                    cues.push(SubtitleCue {
+                        start_ms,
+                        end_ms,
+                        content: SubtitleContent::Text(text),
+                    });
+                }
+            }
+        }
+    }
+    cues
+}
+/// parse `hh:mm:ss,mmm` (srt), `mm:ss.mmm` (vtt) or `h:mm:ss.cc` (ass) to milliseconds
+fn parse_timestamp_ms(s: &str) -> Option<i64> {
+    let (clock, fraction) = match s.rfind([',', '.']) {
+        Some(idx) => (&s[..idx], &s[idx + 1..]),
+        None => (s, ""),
+    };
+    let mut ms = 0;
+    for part in clock.split(':') {
+        ms = ms * 60 + part.trim().parse::<i64>().ok()?;
+    }
+    ms *= 1000;
+    if !fraction.is_empty() {
+        if !fraction.chars().all(|c| c.is_ascii_digit()) {
+            return None;
+        }
+        let digits = fraction.len().min(3);
+        let value = fraction[..digits].parse::<i64>().ok()?;
+        ms += value * 10_i64.pow(3 - digits as u32);
+    }
+    Some(ms)
+}
+/// remove the `<i>` `<b>` `<font>` like tags used by srt and webvtt
+fn strip_html_tags(text: &str) -> String {
+    let mut res = String::with_capacity(text.len());
+    let mut in_tag = false;
+    for c in text.chars() {
+        match c {
+            '<' => in_tag = true,
+            '>' if in_tag => in_tag = false,
+            _ if !in_tag => res.push(c),
+            _ => {}
+        }
+    }
+    res.replace("&amp;", "&")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .trim()
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cue_texts(cues: &[SubtitleCue]) -> Vec<(i64, i64, String)> {
+        cues.iter()
+            .map(|cue| {
+                let text = match &cue.content {
+                    SubtitleContent::Text(text) => text.clone(),
+                    SubtitleContent::Bitmap(_) => String::new(),
+                };
+                (cue.start_ms, cue.end_ms, text)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn timestamp_formats() {
+        assert_eq!(parse_timestamp_ms("00:01:02,345"), Some(62_345));
+        assert_eq!(parse_timestamp_ms("01:02.500"), Some(62_500));
+        assert_eq!(parse_timestamp_ms("1:02:03.45"), Some(3_723_450));
+        assert_eq!(parse_timestamp_ms("00:00:07"), Some(7_000));
+        assert_eq!(parse_timestamp_ms("00:00:01,2x"), None);
+        assert_eq!(parse_timestamp_ms("abc"), None);
+    }
+
+    #[test]
+    fn srt_with_lf_and_crlf() {
+        let srt = "1\n00:00:01,000 --> 00:00:02,500\n<i>Hello</i>\nworld\n\n\
+                   2\n00:00:03,000 --> 00:00:04,000\nfish &amp; chips\n";
+        let expected = vec![
+            (1_000, 2_500, "Hello\nworld".to_string()),
+            (3_000, 4_000, "fish & chips".to_string()),
+        ];
+        assert_eq!(cue_texts(&parse_srt_or_vtt(srt)), expected);
+        let crlf = srt.replace('\n', "\r\n");
+        assert_eq!(cue_texts(&parse_srt_or_vtt(&crlf)), expected);
+    }
+
+    #[test]
+    fn vtt_skips_header_and_note_blocks() {
+        let vtt = "WEBVTT\r\n\r\nNOTE a comment\r\n\r\n\
+                   00:01.000 --> 00:02.000 align:start position:10%\r\nfirst\r\n\r\n\
+                   intro\r\n00:03.000 --> 00:04.000\r\nsecond\r\n";
+        assert_eq!(
+            cue_texts(&parse_srt_or_vtt(vtt)),
+            vec![
+                (1_000, 2_000, "first".to_string()),
+                (3_000, 4_000, "second".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn ass_dialogue_fields_follow_the_format_line() {
+        let ass = "[Script Info]\r\nTitle: test\r\n\r\n[Events]\r\n\
+                   Format: Layer, Start, End, Style, Name, MarginL, MarginR, MarginV, Effect, Text\r\n\
+                   Dialogue: 0,0:00:01.00,0:00:02.50,Default,,0,0,0,,{\\b1}Hi, there\\Nnext\r\n\
+                   Comment: 0,0:00:03.00,0:00:04.00,Default,,0,0,0,,ignored\r\n";
+        assert_eq!(
+            cue_texts(&parse_ass(ass)),
+            vec![(1_000, 2_500, "Hi, there\nnext".to_string())]
+        );
+    }
+
+    #[test]
+    fn cues_decoded_again_are_skipped() {
+        let cue = |start_ms, text: &str| SubtitleCue {
+            start_ms,
+            end_ms: start_ms + 1_000,
+            content: SubtitleContent::Text(text.to_string()),
+        };
+        let mut cues = SubtitleCues::default();
+        cues.push(cue(1_000, "a"));
+        cues.push(cue(2_000, "b"));
+        cues.push(cue(1_000, "a"));
+        cues.push(cue(1_000, "c"));
+        assert_eq!(cues.active_cues(1_500).count(), 2);
+        assert!(cues.spans(2_500));
+        assert!(!cues.spans(500));
+        assert!(!cues.spans(3_500));
+    }
+
+    #[test]
+    fn repeats_with_another_end_or_position_are_kept() {
+        let bitmap = |x| {
+            SubtitleContent::Bitmap(SubtitleBitmap {
+                x,
+                y: 0,
+                width: 1,
+                height: 1,
+                canvas: [10, 10],
+                rgba: vec![0; 4],
+            })
+        };
+        let mut cues = SubtitleCues::default();
+        cues.push(SubtitleCue {
+            start_ms: 1_000,
+            end_ms: 2_000,
+            content: SubtitleContent::Text("a".to_string()),
+        });
+        cues.push(SubtitleCue {
+            start_ms: 1_000,
+            end_ms: 3_000,
+            content: SubtitleContent::Text("a".to_string()),
+        });
+        cues.push(SubtitleCue {
+            start_ms: 1_000,
+            end_ms: 2_000,
+            content: bitmap(0),
+        });
+        cues.push(SubtitleCue {
+            start_ms: 1_000,
+            end_ms: 2_000,
+            content: bitmap(5),
+        });
+        assert_eq!(cues.active_cues(1_500).count(), 4);
+        assert_eq!(cues.active_cues(2_500).count(), 1);
+    }
+
+    #[test]
+    fn open_cue_decoded_again_is_skipped() {
+        let open_cue = |start_ms, text: &str| SubtitleCue {
+            start_ms,
+            end_ms: i64::MAX,
+            content: SubtitleContent::Text(text.to_string()),
+        };
+        let mut cues = SubtitleCues::default();
+        cues.push(open_cue(1_000, "a"));
+        cues.push(open_cue(2_000, "b"));
+        cues.push(open_cue(1_000, "a"));
+        assert_eq!(
+            cue_texts(&cues.cues).first(),
+            Some(&(1_000, 2_000, "a".to_string()))
+        );
+        assert_eq!(cues.active_cues(1_500).count(), 1);
+    }
+
+    #[test]
+    fn ended_cues_are_kept_for_a_minute() {
+        let mut cues = SubtitleCues::default();
+        cues.push(SubtitleCue {
+            start_ms: 0,
+            end_ms: 1_000,
+            content: SubtitleContent::Text("a".to_string()),
+        });
+        cues.remove_ended_before(1_000 + KEPT_ENDED_CUE_MS);
+        assert!(cues.spans(500));
+        cues.remove_ended_before(1_001 + KEPT_ENDED_CUE_MS);
+        assert!(!cues.spans(500));
+    }
+}
@@ -0,0 +1,615 @@
+use eframe::{
+    egui_wgpu::{CallbackResources, CallbackTrait, RenderState},
+    wgpu::{
+        BindGroup, BindGroupDescriptor, BindGroupEntry, BindGroupLayout, BindGroupLayoutDescriptor,
+        BindGroupLayoutEntry, BindingResource, BindingType, Buffer, BufferBindingType,
+        BufferDescriptor, BufferUsages, ColorTargetState, ColorWrites, Device, Extent3d,
+        FilterMode, FragmentState, MultisampleState, Origin3d, PipelineLayoutDescriptor,
+        PrimitiveState, PrimitiveTopology, Queue, RenderPass, RenderPipeline,
+        RenderPipelineDescriptor, Sampler, SamplerBindingType, SamplerDescriptor, ShaderStages,
+        TexelCopyBufferLayout, TexelCopyTextureInfo, Texture, TextureAspect, TextureDescriptor,
+        TextureDimension, TextureFormat, TextureSampleType, TextureUsages, TextureViewDescriptor,
+        TextureViewDimension, VertexState, include_wgsl,
+    },
+};
+use egui::PaintCallbackInfo;
+use ffmpeg_the_third::{
+    color::{Range, Space},
+    format::Pixel,
+    frame::Video,
+};
+use tracing::{info, warn};
+
+/// how the planes of a frame are laid out
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum PlaneLayout {
+    /// y, u and v each in their own plane
+    Planar,
+    /// y in the first plane, u and v interleaved in the second one
+    SemiPlanar,
+}
+impl PlaneLayout {
+    /// the texture formats of the planes
+    fn texture_formats(&self) -> &'static [TextureFormat] {
+        match self {
+            PlaneLayout::Planar => &[
+                TextureFormat::R8Unorm,
+                TextureFormat::R8Unorm,
+                TextureFormat::R8Unorm,
+            ],
+            PlaneLayout::SemiPlanar => &[TextureFormat::R8Unorm, TextureFormat::Rg8Unorm],
+        }
+    }
+}
+/// the 8 bit yuv formats the shader reads, the chroma subsampling is handled by the sampler
+fn plane_layout(format: Pixel) -> Option<PlaneLayout> {
+    match format {
+        Pixel::YUV420P
+        | Pixel::YUVJ420P
+        | Pixel::YUV422P
+        | Pixel::YUVJ422P
+        | Pixel::YUV444P
+        | Pixel::YUVJ444P => Some(PlaneLayout::Planar),
+        Pixel::NV12 => Some(PlaneLayout::SemiPlanar),
+        _ => None,
+    }
+}
+/// the other frames are converted to rgba by swscale,
+/// like the bottom-up ones whose planes have a negative stride
+pub fn can_draw(frame: &Video) -> bool {
+    // `Video::stride` gives the line size as usize, a negative one is read from the frame
+    plane_layout(frame.format()).is_some()
+        && (0..frame.planes()).all(|index| unsafe { (*frame.as_ptr()).linesize[index] > 0 })
+}
+
+/// the textures of the frames of one format and size
+struct YuvPlanes {
+    format: Pixel,
+    width: u32,
+    height: u32,
+    textures: Vec<Texture>,
+    bind_group: BindGroup,
+}
+/// uploads the yuv planes of the video frames and draws them with a converting shader
+/// it lives in the callback resources of the egui renderer
+pub struct YuvRenderer {
+    pipeline: RenderPipeline,
+    bind_group_layout: BindGroupLayout,
+    sampler: Sampler,
+    params_buffer: Buffer,
+    /// the target stores linear colors, the shader output is converted to it
+    srgb_target: bool,
+    planes: Option<YuvPlanes>,
+}
+impl YuvRenderer {
+    /// the size of the uniform of the shader, three vec4
+    const PARAMS_SIZE: u64 = 48;
+    /// create the pipeline for the target of the egui renderer and keep it in its resources
+    pub fn install(render_state: &RenderState) {
+        let renderer = Self::new(&render_state.device, render_state.target_format);
+        render_state
+            .renderer
+            .write()
+            .callback_resources
+            .insert(renderer);
+    }
+    fn new(device: &Device, target_format: TextureFormat) -> Self {
+        let shader = device.create_shader_module(include_wgsl!("yuv_render.wgsl"));
+        let plane_entry = |binding| BindGroupLayoutEntry {
+            binding,
+            visibility: ShaderStages::FRAGMENT,
+            ty: BindingType::Texture {
+                sample_type: TextureSampleType::Float { filterable: true },
+                view_dimension: TextureViewDimension::D2,
+                multisampled: false,
+            },
+            count: None,
+        };
+        let bind_group_layout = device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+            label: Some("yuv_bind_group_layout"),
+            entries: &[
+                BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: ShaderStages::FRAGMENT,
+                    ty: BindingType::Buffer {
+                        ty: BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: ShaderStages::FRAGMENT,
+                    ty: BindingType::Sampler(SamplerBindingType::Filtering),
+                    count: None,
+                },
+                plane_entry(2),
+                plane_entry(3),
+                plane_entry(4),
+            ],
+        });
+        let pipeline_layout = device.create_pipeline_layout(&PipelineLayoutDescriptor {
+            label: Some("yuv_pipeline_layout"),
+            bind_group_layouts: &[&bind_group_layout],
+            push_constant_ranges: &[],
+        });
+        let pipeline = device.create_render_pipeline(&RenderPipelineDescriptor {
+            label: Some("yuv_pipeline"),
+            layout: Some(&pipeline_layout),
+            vertex: VertexState {
+                module: &shader,
+                entry_point: Some("vs_main"),
+                compilation_options: Default::default(),
+                buffers: &[],
+            },
+            fragment: Some(FragmentState {
+                module: &shader,
+                entry_point: Some("fs_main"),
+                compilation_options: Default::default(),
+                targets: &[Some(ColorTargetState {
+                    format: target_format,
+                    blend: None,
+                    write_mask: ColorWrites::ALL,
+                })],
+            }),
+            primitive: PrimitiveState {
+                topology: PrimitiveTopology::TriangleStrip,
+                ..Default::default()
+            },
+            depth_stencil: None,
+            multisample: MultisampleState::default(),
+            multiview: None,
+            cache: None,
+        });
+        let sampler = device.create_sampler(&SamplerDescriptor {
+            label: Some("yuv_sampler"),
+            mag_filter: FilterMode::Linear,
+            min_filter: FilterMode::Linear,
+            ..Default::default()
+        });
+        let params_buffer = device.create_buffer(&BufferDescriptor {
+            label: Some("yuv_params"),
+            size: Self::PARAMS_SIZE,
+            usage: BufferUsages::UNIFORM | BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        info!("yuv renderer created for target {:?}", target_format);
+        Self {
+            pipeline,
+            bind_group_layout,
+            sampler,
+            params_buffer,
+            srgb_target: target_format.is_srgb(),
+            planes: None,
+        }
+    }
+    /// write the planes and the color details of the frame for the next paint
+    /// return false if the frame can't be drawn by the shader
+    pub fn upload(&mut self, device: &Device, queue: &Queue, frame: &Video) -> bool {
+        if !can_draw(frame) {
+            warn!("yuv renderer can't draw {:?}", frame.format());
+            return false;
+        }
+        if let Some(layout) = plane_layout(frame.format()) {
+            let texture_formats = layout.texture_formats();
+            if !self.planes.as_ref().is_some_and(|planes| {
+                planes.format == frame.format()
+                    && planes.width == frame.width()
+                    && planes.height == frame.height()
+            }) {
+                self.planes = Some(self.create_planes(device, frame, texture_formats));
+            }
+            if let Some(planes) = &self.planes {
+                for (index, texture) in planes.textures.iter().enumerate() {
+                    let size = texture.size();
+                    queue.write_texture(
+                        TexelCopyTextureInfo {
+                            texture,
+                            mip_level: 0,
+                            origin: Origin3d::ZERO,
+                            aspect: TextureAspect::All,
+                        },
+                        frame.data(index),
+                        TexelCopyBufferLayout {
+                            offset: 0,
+                            // positive, checked by `can_draw`
+                            bytes_per_row: Some(frame.stride(index) as u32),
+                            rows_per_image: None,
+                        },
+                        size,
+                    );
+                }
+                let params = self.params(frame, layout);
+                queue.write_buffer(&self.params_buffer, 0, bytemuck::cast_slice(&params));
+                return true;
+            }
+        }
+        false
+    }
+    fn create_planes(
+        &self,
+        device: &Device,
+        frame: &Video,
+        texture_formats: &[TextureFormat],
+    ) -> YuvPlanes {
+        info!(
+            "create yuv planes {:?} {}x{}",
+            frame.format(),
+            frame.width(),
+            frame.height()
+        );
+        let textures = texture_formats
+            .iter()
+            .enumerate()
+            .map(|(index, format)| {
+                device.create_texture(&TextureDescriptor {
+                    label: Some("yuv_plane"),
+                    size: Extent3d {
+                        width: frame.plane_width(index),
+                        height: frame.plane_height(index),
+                        depth_or_array_layers: 1,
+                    },
+                    mip_level_count: 1,
+                    sample_count: 1,
+                    dimension: TextureDimension::D2,
+                    format: *format,
+                    usage: TextureUsages::TEXTURE_BINDING | TextureUsages::COPY_DST,
+                    view_formats: &[],
+                })
+            })
+            .collect::<Vec<_>>();
+        let views = textures
+            .iter()
+            .map(|texture| texture.create_view(&TextureViewDescriptor::default()))
+            .collect::<Vec<_>>();
+        // the interleaved chroma plane is bound for both u and v
+        let u_view = &views[1];
+        let v_view = views.get(2).unwrap_or(u_view);
+        let bind_group = device.create_bind_group(&BindGroupDescriptor {
+            label: Some("yuv_bind_group"),
+            layout: &self.bind_group_layout,
+            entries: &[
+                BindGroupEntry {
+                    binding: 0,
+                    resource: self.params_buffer.as_entire_binding(),
+                },
+                BindGroupEntry {
+                    binding: 1,
+                    resource: BindingResource::Sampler(&self.sampler),
+                },
+                BindGroupEntry {
+                    binding: 2,
+                    resource: BindingResource::TextureView(&views[0]),
+                },
+                BindGroupEntry {
+                    binding: 3,
+                    resource: BindingResource::TextureView(u_view),
+                },
+                BindGroupEntry {
+                    binding: 4,
+                    resource: BindingResource::TextureView(v_view),
+                },
+            ],
+        });
+        YuvPlanes {
+            format: frame.format(),
+            width: frame.width(),
+            height: frame.height(),
+            textures,
+            bind_group,
+        }
+    }
+    /// draw the uploaded planes over the viewport of the pass
+    fn draw(&self, render_pass: &mut RenderPass<'_>) {
+        if let Some(planes) = &self.planes {
+            render_pass.set_pipeline(&self.pipeline);
+            render_pass.set_bind_group(0, &planes.bind_group, &[]);
+            render_pass.draw(0..4, 0..1);
+        }
+    }
+    /// the uniform of the shader, the decoder has already put the resolved matrix and range
+    /// in the color tags of the frame
+    fn params(&self, frame: &Video, layout: PlaneLayout) -> [f32; 12] {
+        let (kr, kb) = match frame.color_space() {
+            Space::BT709 => (0.2126, 0.0722),
+            Space::BT2020NCL | Space::BT2020CL => (0.2627, 0.0593),
+            _ => (0.299, 0.114),
+        };
+        let kg = 1.0 - kr - kb;
+        let (y_offset, y_scale, c_scale) = if frame.color_range() == Range::JPEG {
+            (0.0, 1.0, 1.0)
+        } else {
+            (16.0 / 255.0, 255.0 / 219.0, 255.0 / 224.0)
+        };
+        let semi_planar = if layout == PlaneLayout::SemiPlanar {
+            1.0
+        } else {
+            0.0
+        };
+        let srgb_target = if self.srgb_target { 1.0 } else { 0.0 };
+        [
+            y_offset,
+            y_scale,
+            c_scale,
+            semi_planar,
+            2.0 * (1.0 - kr),
+            2.0 * kb * (1.0 - kb) / kg,
+            2.0 * kr * (1.0 - kr) / kg,
+            2.0 * (1.0 - kb),
+            srgb_target,
+            0.0,
+            0.0,
+            0.0,
+        ]
+    }
+}
+
+/// draws the uploaded planes into the rect of the paint callback
+pub struct YuvPaintCallback;
+impl CallbackTrait for YuvPaintCallback {
+    fn paint(
+        &self,
+        _info: PaintCallbackInfo,
+        render_pass: &mut RenderPass<'static>,
+        callback_resources: &CallbackResources,
+    ) {
+        if let Some(renderer) = callback_resources.get::<YuvRenderer>() {
+            renderer.draw(render_pass);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::mpsc,
+        time::{Duration, Instant},
+    };
+
+    use eframe::wgpu::{
+        Backends, Color, CommandEncoderDescriptor, DeviceDescriptor, DeviceType, Instance,
+        InstanceDescriptor, LoadOp, MapMode, Operations, PollType, RenderPassColorAttachment,
+        RenderPassDescriptor, RequestAdapterOptions, StoreOp, TexelCopyBufferInfo,
+    };
+    use ffmpeg_the_third::{
+        ffi::{AVColorSpace, SWS_CS_DEFAULT, sws_getCoefficients, sws_setColorspaceDetails},
+        software::scaling,
+    };
+    use tokio::runtime::Runtime;
+
+    use super::*;
+
+    const SIZE: u32 = 64;
+    /// the gpu and swscale round and place the upsampled chroma a bit differently
+    const TOLERANCE: u8 = 8;
+
+    /// the software vulkan adapter like lavapipe, none when the machine has no such driver
+    fn software_device(rt: &Runtime) -> Option<(Device, Queue)> {
+        let instance = Instance::new(&InstanceDescriptor {
+            backends: Backends::VULKAN,
+            ..Default::default()
+        });
+        let adapter = rt
+            .block_on(instance.request_adapter(&RequestAdapterOptions {
+                force_fallback_adapter: true,
+                ..Default::default()
+            }))
+            .ok()?;
+        if adapter.get_info().device_type != DeviceType::Cpu {
+            return None;
+        }
+        rt.block_on(adapter.request_device(&DeviceDescriptor::default()))
+            .ok()
+    }
+
+    /// smooth chroma gradients, the luma changes every pixel
+    fn yuv_frame(format: Pixel, space: Space, range: Range) -> Video {
+        let mut frame = Video::new(format, SIZE, SIZE);
+        frame.set_color_space(space);
+        frame.set_color_range(range);
+        let y_stride = frame.stride(0);
+        for row in 0..SIZE as usize {
+            for col in 0..SIZE as usize {
+                frame.data_mut(0)[row * y_stride + col] = (32 + col * 2 + row) as u8;
+            }
+        }
+        let chroma =
+            |row: usize, col: usize| ((96 + col * 2 + row) as u8, (160 - col - row * 2) as u8);
+        for row in 0..SIZE as usize / 2 {
+            for col in 0..SIZE as usize / 2 {
+                let (u, v) = chroma(row, col);
+                if format == Pixel::NV12 {
+                    let stride = frame.stride(1);
+                    frame.data_mut(1)[row * stride + col * 2] = u;
+                    frame.data_mut(1)[row * stride + col * 2 + 1] = v;
+                } else {
+                    let (u_stride, v_stride) = (frame.stride(1), frame.stride(2));
+                    frame.data_mut(1)[row * u_stride + col] = u;
+                    frame.data_mut(2)[row * v_stride + col] = v;
+                }
+            }
+        }
+        frame
+    }
+
+    /// the frame converted like the decoder does for the swscale renderer
+    fn swscale_rgba(frame: &Video) -> Option<Video> {
+        let mut converter = scaling::Context::get(
+            frame.format(),
+            SIZE,
+            SIZE,
+            Pixel::RGBA,
+            SIZE,
+            SIZE,
+            scaling::Flags::BILINEAR,
+        )
+        .ok()?;
+        unsafe {
+            sws_setColorspaceDetails(
+                converter.as_mut_ptr(),
+                sws_getCoefficients(AVColorSpace::from(frame.color_space()) as i32),
+                (frame.color_range() == Range::JPEG) as i32,
+                sws_getCoefficients(SWS_CS_DEFAULT as i32),
+                1,
+                0,
+                1 << 16,
+                1 << 16,
+            );
+        }
+        let mut rgba = Video::empty();
+        converter.run(frame, &mut rgba).ok()?;
+        Some(rgba)
+    }
+
+    /// the frame drawn by the shader into an offscreen rgba texture, read back row by row
+    fn shader_rgba(device: &Device, queue: &Queue, frame: &Video) -> Option<Vec<u8>> {
+        let mut renderer = YuvRenderer::new(device, TextureFormat::Rgba8Unorm);
+        if !renderer.upload(device, queue, frame) {
+            return None;
+        }
+        let size = Extent3d {
+            width: SIZE,
+            height: SIZE,
+            depth_or_array_layers: 1,
+        };
+        let target = device.create_texture(&TextureDescriptor {
+            label: Some("yuv_test_target"),
+            size,
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: TextureDimension::D2,
+            format: TextureFormat::Rgba8Unorm,
+            usage: TextureUsages::RENDER_ATTACHMENT | TextureUsages::COPY_SRC,
+            view_formats: &[],
+        });
+        let view = target.create_view(&TextureViewDescriptor::default());
+        // 64 rgba pixels are 256 bytes, the row alignment of the copy
+        let readback = device.create_buffer(&BufferDescriptor {
+            label: Some("yuv_test_readback"),
+            size: (SIZE * SIZE * 4) as u64,
+            usage: BufferUsages::MAP_READ | BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        let mut encoder = device.create_command_encoder(&CommandEncoderDescriptor::default());
+        {
+            let mut render_pass = encoder.begin_render_pass(&RenderPassDescriptor {
+                color_attachments: &[Some(RenderPassColorAttachment {
+                    view: &view,
+                    depth_slice: None,
+                    resolve_target: None,
+                    ops: Operations {
+                        load: LoadOp::Clear(Color::BLACK),
+                        store: StoreOp::Store,
+                    },
+                })],
+                ..Default::default()
+            });
+            renderer.draw(&mut render_pass);
+        }
+        encoder.copy_texture_to_buffer(
+            TexelCopyTextureInfo {
+                texture: &target,
+                mip_level: 0,
+                origin: Origin3d::ZERO,
+                aspect: TextureAspect::All,
+            },
+            TexelCopyBufferInfo {
+                buffer: &readback,
+                layout: TexelCopyBufferLayout {
+                    offset: 0,
+                    bytes_per_row: Some(SIZE * 4),
+                    rows_per_image: None,
+                },
+            },
+            size,
+        );
+        queue.submit([encoder.finish()]);
+        let (map_sender, map_receiver) = mpsc::channel();
+        readback.slice(..).map_async(MapMode::Read, move |res| {
+            let _ = map_sender.send(res.is_ok());
+        });
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while Instant::now() < deadline {
+            let _ = device.poll(PollType::Poll);
+            match map_receiver.try_recv() {
+                Ok(true) => return Some(readback.slice(..).get_mapped_range().to_vec()),
+                Ok(false) => return None,
+                Err(_) => std::thread::sleep(Duration::from_millis(1)),
+            }
+        }
+        None
+    }
+
+    /// the largest difference of the r, g and b values
+    fn max_difference(shader: &[u8], reference: &Video) -> u8 {
+        let stride = reference.stride(0);
+        let data = reference.data(0);
+        let mut max_difference = 0;
+        for row in 0..SIZE as usize {
+            for col in 0..SIZE as usize {
+                for channel in 0..3 {
+                    let shader_value = shader[(row * SIZE as usize + col) * 4 + channel];
+                    let reference_value = data[row * stride + col * 4 + channel];
+                    max_difference = max_difference.max(shader_value.abs_diff(reference_value));
+                }
+            }
+        }
+        max_difference
+    }
+
+    #[test]
+    fn negative_stride_is_left_to_swscale() {
+        let mut frame = yuv_frame(Pixel::YUV420P, Space::BT709, Range::MPEG);
+        assert!(can_draw(&frame));
+        // a bottom-up picture starts at its last row
+        unsafe {
+            (*frame.as_mut_ptr()).linesize[0] = -(*frame.as_ptr()).linesize[0];
+        }
+        assert!(!can_draw(&frame));
+        assert!(!can_draw(&Video::new(Pixel::YUV420P10LE, SIZE, SIZE)));
+    }
+
+    #[test]
+    #[ignore = "needs a software vulkan adapter such as lavapipe"]
+    fn shader_matches_swscale() {
+        let device = Runtime::new().ok().and_then(|rt| software_device(&rt));
+        assert!(device.is_some(), "no software vulkan adapter");
+        if let Some((device, queue)) = device {
+            for format in [Pixel::YUV420P, Pixel::NV12] {
+                for space in [Space::BT470BG, Space::BT709] {
+                    for range in [Range::MPEG, Range::JPEG] {
+                        let frame = yuv_frame(format, space, range);
+                        let reference = swscale_rgba(&frame);
+                        let shader = shader_rgba(&device, &queue, &frame);
+                        assert!(
+                            reference.is_some(),
+                            "{:?} {:?} {:?} swscale",
+                            format,
+                            space,
+                            range
+                        );
+                        assert!(
+                            shader.is_some(),
+                            "{:?} {:?} {:?} shader",
+                            format,
+                            space,
+                            range
+                        );
+                        if let (Some(reference), Some(shader)) = (reference, shader) {
+                            let difference = max_difference(&shader, &reference);
+                            assert!(
+                                difference <= TOLERANCE,
+                                "{:?} {:?} {:?} differs from swscale by {}",
+                                format,
+                                space,
+                                range,
+                                difference
+                            );
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,68 @@
+// draw the yuv planes of a video frame as rgb over the whole callback viewport
+
+struct YuvParams {
+    // y offset, y scale, chroma scale, 1 if u and v are interleaved in the second plane
+    range: vec4<f32>,
+    // cr to r, cb to g, cr to g, cb to b
+    coeffs: vec4<f32>,
+    // 1 if the target is srgb and expects linear colors
+    output: vec4<f32>,
+};
+
+@group(0) @binding(0) var<uniform> params: YuvParams;
+@group(0) @binding(1) var plane_sampler: sampler;
+@group(0) @binding(2) var y_plane: texture_2d<f32>;
+@group(0) @binding(3) var u_plane: texture_2d<f32>;
+@group(0) @binding(4) var v_plane: texture_2d<f32>;
+
+// the 8 bit chroma value of no color
+const CHROMA_ZERO: f32 = 128.0 / 255.0;
+
+struct VertexOutput {
+    @builtin(position) position: vec4<f32>,
+    @location(0) uv: vec2<f32>,
+};
+
+// a triangle strip of four vertices covering the viewport
+@vertex
+fn vs_main(@builtin(vertex_index) index: u32) -> VertexOutput {
+    let uv = vec2<f32>(f32(index & 1u), f32(index >> 1u));
+    var out: VertexOutput;
+    out.position = vec4<f32>(uv.x * 2.0 - 1.0, 1.0 - uv.y * 2.0, 0.0, 1.0);
+    out.uv = uv;
+    return out;
+}
+
+fn srgb_to_linear(color: vec3<f32>) -> vec3<f32> {
+    let lower = color / 12.92;
+    let higher = pow((color + 0.055) / 1.055, vec3<f32>(2.4));
+    return select(higher, lower, color <= vec3<f32>(0.04045));
+}
+
+@fragment
+fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
+    let y_value = textureSample(y_plane, plane_sampler, in.uv).r;
+    let uv_value = textureSample(u_plane, plane_sampler, in.uv);
+    let v_value = textureSample(v_plane, plane_sampler, in.uv).r;
+    let u = uv_value.r;
+    var v = v_value;
+    if params.range.w > 0.5 {
+        v = uv_value.g;
+    }
+    let y = (y_value - params.range.x) * params.range.y;
+    let cb = (u - CHROMA_ZERO) * params.range.z;
+    let cr = (v - CHROMA_ZERO) * params.range.z;
+    var rgb = clamp(
+        vec3<f32>(
+            y + params.coeffs.x * cr,
+            y - params.coeffs.y * cb - params.coeffs.z * cr,
+            y + params.coeffs.w * cb,
+        ),
+        vec3<f32>(0.0),
+        vec3<f32>(1.0),
+    );
+    if params.output.x > 0.5 {
+        rgb = srgb_to_linear(rgb);
+    }
+    return vec4<f32>(rgb, 1.0);
+}